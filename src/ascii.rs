@@ -0,0 +1,236 @@
+// Modbus ASCII framing. A frame starts with ':' followed by address,
+// PDU and LRC as hexadecimal characters and ends with CR and a
+// delimiter, LF unless changed using diagnostics.
+
+use crate::line_client::{FrameSource, LineClient};
+use crate::rtu::Frame;
+use async_trait::async_trait;
+use futures::FutureExt;
+use std::collections::VecDeque;
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+use tokio_modbus::client::{Client, Context};
+use tokio_modbus::slave::Slave;
+
+/// Maximum number of characters in a frame, excluding start and end
+const MAX_FRAME_CHARS: usize = 511;
+
+const HEX_DIGITS: &[u8; 16] = b"0123456789ABCDEF";
+
+/// Longitudinal redundancy check
+pub fn lrc(data: &[u8]) -> u8 {
+    data.iter()
+        .fold(0u8, |sum, b| sum.wrapping_add(*b))
+        .wrapping_neg()
+}
+
+fn frame(unit: u8, pdu: &[u8], lrc: u8) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(pdu.len() * 2 + 7);
+    frame.push(b':');
+    for b in [unit].iter().chain(pdu).chain(&[lrc]) {
+        frame.push(HEX_DIGITS[usize::from(b >> 4)]);
+        frame.push(HEX_DIGITS[usize::from(b & 0x0f)]);
+    }
+    frame.extend_from_slice(b"\r\n");
+    frame
+}
+
+/// Build a frame with address and LRC from a PDU
+pub fn encode_frame(unit: u8, pdu: &[u8]) -> Vec<u8> {
+    let mut data = vec![unit];
+    data.extend_from_slice(pdu);
+    frame(unit, pdu, lrc(&data))
+}
+
+/// Build a frame with an invalid LRC
+pub fn encode_frame_bad_lrc(unit: u8, pdu: &[u8]) -> Vec<u8> {
+    let mut data = vec![unit];
+    data.extend_from_slice(pdu);
+    frame(unit, pdu, !lrc(&data))
+}
+
+fn hex_value(c: u8) -> Option<u8> {
+    (c as char).to_digit(16).map(|v| v as u8)
+}
+
+// Decode the characters between start and end of a frame. LRC errors
+// are reported as CRC errors.
+fn decode(chars: &[u8]) -> Frame {
+    if chars.len() < 6 || !chars.len().is_multiple_of(2) {
+        return Frame::CrcError;
+    }
+    let bytes: Option<Vec<u8>> = chars
+        .chunks(2)
+        .map(|pair| Some(hex_value(pair[0])? << 4 | hex_value(pair[1])?))
+        .collect();
+    match bytes {
+        Some(mut adu) if lrc(&adu) == 0 => {
+            adu.pop();
+            Frame::Adu(adu)
+        }
+        _ => Frame::CrcError,
+    }
+}
+
+pub struct FrameReader<R> {
+    reader: R,
+    received: VecDeque<u8>,
+    buf: Vec<u8>,
+    in_frame: bool,
+    after_cr: bool,
+    overrun: bool,
+}
+
+impl<R> FrameReader<R>
+where
+    R: AsyncRead + Unpin,
+{
+    pub fn new(reader: R) -> Self {
+        FrameReader {
+            reader,
+            received: VecDeque::new(),
+            buf: Vec::new(),
+            in_frame: false,
+            after_cr: false,
+            overrun: false,
+        }
+    }
+
+    // Handle one received character. Any character following CR ends
+    // the frame so that a changed delimiter is accepted.
+    fn push(&mut self, c: u8) -> Option<Frame> {
+        if c == b':' {
+            self.buf.clear();
+            self.in_frame = true;
+            self.after_cr = false;
+            self.overrun = false;
+            return None;
+        }
+        if !self.in_frame {
+            return None;
+        }
+        if self.after_cr {
+            self.in_frame = false;
+            let chars = std::mem::take(&mut self.buf);
+            if std::mem::take(&mut self.overrun) {
+                return Some(Frame::Overrun);
+            }
+            return Some(decode(&chars));
+        }
+        if c == b'\r' {
+            self.after_cr = true;
+        } else if self.buf.len() < MAX_FRAME_CHARS {
+            self.buf.push(c);
+        } else {
+            self.overrun = true;
+        }
+        None
+    }
+
+    /// Drop data received so far, including data that can be read
+    /// without waiting
+    pub fn discard_input(&mut self) {
+        let mut chunk = [0u8; 256];
+        while let Some(Ok(n)) = self.reader.read(&mut chunk).now_or_never()
+            && n > 0
+        {}
+        self.received.clear();
+        self.buf.clear();
+        self.in_frame = false;
+        self.after_cr = false;
+        self.overrun = false;
+    }
+
+    /// Wait for the next frame. Returns None at end of stream.
+    pub async fn next(&mut self) -> io::Result<Option<Frame>> {
+        let mut chunk = [0u8; 256];
+        loop {
+            while let Some(c) = self.received.pop_front() {
+                if let Some(frame) = self.push(c) {
+                    return Ok(Some(frame));
+                }
+            }
+            let n = self.reader.read(&mut chunk).await?;
+            if n == 0 {
+                return Ok(None);
+            }
+            self.received.extend(&chunk[..n]);
+        }
+    }
+}
+
+#[async_trait]
+impl<R> FrameSource for FrameReader<R>
+where
+    R: AsyncRead + Unpin + Send,
+{
+    async fn next(&mut self) -> io::Result<Option<Frame>> {
+        FrameReader::next(self).await
+    }
+
+    fn discard_input(&mut self) {
+        FrameReader::discard_input(self)
+    }
+}
+
+/// Create a client context using ASCII framing on a serial line
+pub fn attach_slave<T>(transport: T, slave: Slave) -> Context
+where
+    T: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (reader, writer) = tokio::io::split(transport);
+    let client = LineClient::new(FrameReader::new(reader), writer, encode_frame, slave);
+    Context::from(Box::new(client) as Box<dyn Client>)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    #[test]
+    fn lrc_test() {
+        // Example from the Modbus serial line specification
+        assert_eq!(lrc(&[0x11, 0x03, 0x00, 0x6b, 0x00, 0x03]), 0x7e);
+        assert_eq!(
+            encode_frame(0x11, &[0x03, 0x00, 0x6b, 0x00, 0x03]),
+            b":1103006B00037E\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn frame_reader_test() {
+        let (mut tx, rx) = tokio::io::duplex(1024);
+        let mut frames = FrameReader::new(rx);
+        let mut data = b"noise".to_vec();
+        data.extend(encode_frame(1, &[0x03, 0x00, 0x00, 0x00, 0x02]));
+        // A new start character discards the incomplete frame
+        data.extend(b":0103");
+        data.extend(encode_frame(2, &[0x06, 0x00, 0x01, 0x12, 0x34]));
+        tx.write_all(&data).await.unwrap();
+        assert_eq!(
+            frames.next().await.unwrap(),
+            Some(Frame::Adu(vec![1, 0x03, 0x00, 0x00, 0x00, 0x02]))
+        );
+        assert_eq!(
+            frames.next().await.unwrap(),
+            Some(Frame::Adu(vec![2, 0x06, 0x00, 0x01, 0x12, 0x34]))
+        );
+        // Lower case and a changed delimiter are accepted
+        tx.write_all(b":1103006b00037e\r!").await.unwrap();
+        assert_eq!(
+            frames.next().await.unwrap(),
+            Some(Frame::Adu(vec![0x11, 0x03, 0x00, 0x6b, 0x00, 0x03]))
+        );
+        tx.write_all(&encode_frame_bad_lrc(1, &[0x03, 0x00, 0x00, 0x00, 0x02]))
+            .await
+            .unwrap();
+        assert_eq!(frames.next().await.unwrap(), Some(Frame::CrcError));
+        tx.write_all(b":").await.unwrap();
+        tx.write_all(&[b'0'; MAX_FRAME_CHARS + 1]).await.unwrap();
+        tx.write_all(b"\r\n").await.unwrap();
+        assert_eq!(frames.next().await.unwrap(), Some(Frame::Overrun));
+        drop(tx);
+        assert_eq!(frames.next().await.unwrap(), None);
+    }
+}
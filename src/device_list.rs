@@ -1,11 +1,207 @@
-use crate::tag_list::TagDefList;
+use crate::devices::Table;
+use crate::serial_ports::SerialSettings;
+use crate::tag_list::{Poll, TagDefList};
+use rand::Rng;
 use std::collections::{btree_map, BTreeMap};
+use std::num::ParseIntError;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Objects returned by Read Device Identification (FC 43 / MEI 14)
+#[derive(Clone)]
+pub struct DeviceIdentification {
+    pub objects: BTreeMap<u8, String>, // Object value indexed by object id
+}
+
+impl DeviceIdentification {
+    pub fn object_name(id: u8) -> &'static str {
+        match id {
+            0x00 => "VendorName",
+            0x01 => "ProductCode",
+            0x02 => "MajorMinorRevision",
+            0x03 => "VendorUrl",
+            0x04 => "ProductName",
+            0x05 => "ModelName",
+            0x06 => "UserApplicationName",
+            0x80..=0xff => "Extended",
+            _ => "Reserved",
+        }
+    }
+}
+
+/// What happens to a request selected by a fault
+#[derive(Clone, Debug, PartialEq)]
+pub enum FaultAction {
+    Exception(u8), // Reply with this exception code without executing the request
+    DropResponse,  // Execute the request but don't reply
+    Delay(Duration),
+    CorruptCrc, // Only affects RTU
+}
+
+/// Fault injected by the server
+#[derive(Clone, Debug)]
+pub struct FaultDef {
+    pub label: Option<String>,
+    pub action: FaultAction,
+    pub function: Option<u8>, // Only requests with this function code
+    pub addresses: Option<RangeInclusive<u16>>, // Only requests touching these addresses
+    pub probability: u8,      // Percentage of matching requests affected
+    pub enabled: bool,        // Initial state
+}
+
+/// Time the server waits before replying, picked at random from the
+/// range for each request
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ResponseDelay {
+    pub min: Duration,
+    pub max: Duration,
+}
+
+impl ResponseDelay {
+    pub fn sample(&self) -> Duration {
+        if self.max > self.min {
+            rand::rng().random_range(self.min..=self.max)
+        } else {
+            self.min
+        }
+    }
+}
+
+/// Parses a delay in milliseconds, either fixed ("100") or a range ("50-200")
+impl FromStr for ResponseDelay {
+    type Err = ParseIntError;
+    fn from_str(s: &str) -> Result<Self, ParseIntError> {
+        let (min, max) = match s.split_once('-') {
+            Some((min, max)) => (min.trim().parse()?, max.trim().parse()?),
+            None => {
+                let ms = s.trim().parse()?;
+                (ms, ms)
+            }
+        };
+        Ok(ResponseDelay {
+            min: Duration::from_millis(min),
+            max: Duration::from_millis(max),
+        })
+    }
+}
+
+/// How a client reaches a device. Devices with equal connections share
+/// one connection.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConnectionDef {
+    Tcp {
+        host: String,
+        port: u16,
+    },
+    RtuOverTcp {
+        host: String,
+        port: u16,
+    },
+    Udp {
+        host: String,
+        port: u16,
+    },
+    Rtu {
+        device: String,
+        settings: SerialSettings,
+    },
+    Ascii {
+        device: String,
+        settings: SerialSettings,
+    },
+}
+
+impl std::fmt::Display for ConnectionDef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use ConnectionDef::*;
+        match self {
+            Tcp { host, port } => write!(f, "TCP {host}:{port}"),
+            RtuOverTcp { host, port } => write!(f, "RTU over TCP {host}:{port}"),
+            Udp { host, port } => write!(f, "UDP {host}:{port}"),
+            Rtu { device, .. } => write!(f, "RTU {device}"),
+            Ascii { device, .. } => write!(f, "ASCII {device}"),
+        }
+    }
+}
+
+/// Timing of a client connection. Unset values are taken from the
+/// command line.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ClientTiming {
+    /// How long to wait for a response
+    pub timeout: Option<Duration>,
+    /// How many times a request without a response is repeated
+    pub retries: Option<u32>,
+    /// Delay before reconnecting, doubled after each failed attempt
+    pub reconnect_delay: Option<Duration>,
+    pub max_reconnect_delay: Option<Duration>,
+}
+
+/// Limits on the requests reading a device in client mode
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ReadLimits {
+    /// Unused addresses a read may span to merge nearby ranges. Taken
+    /// from the command line if not set.
+    pub max_gap: Option<u16>,
+    /// Most registers read with one request, if less than the standard
+    /// allows
+    pub max_registers: Option<u16>,
+    /// Most coils or discrete inputs read with one request
+    pub max_bits: Option<u16>,
+}
+
+/// When a client issues a scheduled write
+#[derive(Clone, Debug, PartialEq)]
+pub enum WriteTrigger {
+    Interval(Duration),
+    /// When a value of the same device rises above or falls below a
+    /// threshold
+    Above {
+        table: Table,
+        addr: u16,
+        threshold: u16,
+    },
+    Below {
+        table: Table,
+        addr: u16,
+        threshold: u16,
+    },
+}
+
+/// Value written to a holding register or coil by a client, on a timer
+/// or when another value crosses a threshold
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScheduledWriteDef {
+    pub label: Option<String>,
+    pub table: Table, // Holding registers or coils
+    pub addr: u16,
+    pub value: u16, // Coils are set if not 0
+    pub trigger: WriteTrigger,
+    pub enabled: bool, // Initial state
+}
 
 pub struct DeviceDef {
     pub addr: u8, // Device or unit address
     pub tags: TagDefList,
+    pub identification: Option<DeviceIdentification>,
+    pub faults: Vec<FaultDef>,
+    pub response_delay: ResponseDelay,
+    /// Connection used in client mode instead of the one given on the
+    /// command line
+    pub connection: Option<ConnectionDef>,
+    /// Unit address used on the connection, usually the same as `addr`
+    pub remote_addr: u8,
+    /// Timing of the connection, if given
+    pub client_timing: ClientTiming,
+    /// When tags outside any group with its own setting are read in
+    /// client mode
+    pub poll: Poll,
+    pub read_limits: ReadLimits,
+    pub write_schedule: Vec<ScheduledWriteDef>,
 }
 
+#[derive(Default)]
 pub struct DeviceDefList(BTreeMap<u8, DeviceDef>);
 
 impl DeviceDefList {
@@ -1,10 +1,19 @@
-use crate::device_list::{DeviceDef, DeviceDefList};
-use crate::tag_list_xml::{self, parse_tag_list};
+use crate::device_list::{
+    ClientTiming, ConnectionDef, DeviceDef, DeviceDefList, DeviceIdentification, FaultAction,
+    FaultDef, ReadLimits, ResponseDelay, ScheduledWriteDef, WriteTrigger,
+};
+use crate::devices::Table;
+use crate::modbus_connection::{READ_BITS_MAX_LEN, READ_REGISTERS_MAX_LEN};
+use crate::serial_ports::{self, InvalidValue, SerialSettings};
+use crate::tag_list::TagDefList;
+use crate::tag_list_xml::{self, ParsedU16, parse_tag_list_child};
 use crate::xml_common::ParseErrorKind::UnexpectedElement;
-use crate::xml_common::{self, check_element_ns, required_attribute};
+use crate::xml_common::{self, check_element_ns, optional_attribute, required_attribute};
 use roxmltree::Node;
+use std::collections::BTreeMap;
 use std::num::ParseIntError;
 use std::str::FromStr;
+use std::time::Duration;
 
 pub type ParseError = xml_common::ParseErrorBase<ParseErrorKind>;
 
@@ -30,6 +39,18 @@ impl From<xml_common::ParseError> for ParseError {
 pub enum ParseErrorKind {
     Base(tag_list_xml::ParseErrorKind),
     DuplicateAddr,
+    InvalidObjectId,
+    ObjectTooLong,
+    InvalidProbability,
+    InvalidExceptionCode,
+    InvalidAddressRange,
+    InvalidResponseDelay,
+    InvalidTransport,
+    InvalidClientTiming,
+    InvalidReadLimit,
+    InvalidTable,
+    InvalidWriteTable,
+    InvalidWriteTrigger,
 }
 use ParseErrorKind::*;
 
@@ -41,6 +62,45 @@ impl std::fmt::Display for ParseErrorKind {
                 f,
                 "A device with the same address already configured"
             ),
+            InvalidObjectId => write!(
+                f,
+                "Attribute 'id' must be in the extended range 0x80 to 0xff"
+            ),
+            ObjectTooLong => write!(
+                f,
+                "Identification objects must be at most {MAX_OBJECT_LEN} bytes long"
+            ),
+            InvalidProbability => write!(f, "Attribute 'probability' must be at most 100"),
+            InvalidExceptionCode => write!(f, "Exception code must be in the range 1 to 255"),
+            InvalidAddressRange => write!(
+                f,
+                "Either use attribute 'addr' or both of 'addr-low' and 'addr-high'"
+            ),
+            InvalidResponseDelay => write!(
+                f,
+                "The lower limit of 'response-delay' must not be greater than the upper limit"
+            ),
+            InvalidTransport => write!(
+                f,
+                "Attribute 'transport' must be one of tcp, rtu-over-tcp, udp, rtu or ascii"
+            ),
+            InvalidClientTiming => write!(
+                f,
+                "Timeouts and delays must be greater than 0 and 'max-reconnect-delay' not less than 'reconnect-delay'"
+            ),
+            InvalidReadLimit => write!(
+                f,
+                "Attribute 'max-read-registers' must be in the range 1 to {READ_REGISTERS_MAX_LEN} and 'max-read-bits' in the range 1 to {READ_BITS_MAX_LEN}"
+            ),
+            InvalidTable => write!(
+                f,
+                "Table must be one of holding-registers, input-registers, coils or discrete-inputs"
+            ),
+            InvalidWriteTable => write!(f, "Only holding registers and coils can be written"),
+            InvalidWriteTrigger => write!(
+                f,
+                "Either use attribute 'interval' or 'when-addr' together with one of 'above' and 'below'"
+            ),
         }
     }
 }
@@ -64,11 +124,357 @@ impl From<ParsedU8> for u8 {
     }
 }
 
+// Largest object that fits in a response PDU together with the response header
+const MAX_OBJECT_LEN: usize = 244;
+
+// Attributes for basic (0x00-0x02) and regular (0x03-0x06) objects
+const IDENTIFICATION_ATTRIBUTES: [(&str, u8); 4] = [
+    ("vendor-url", 0x03),
+    ("product-name", 0x04),
+    ("model-name", 0x05),
+    ("user-application-name", 0x06),
+];
+
+fn parse_identification(node: &Node) -> Result<DeviceIdentification, ParseError> {
+    let mut objects = BTreeMap::new();
+    objects.insert(0x00, required_attribute::<String>(node, "vendor-name")?);
+    objects.insert(0x01, required_attribute::<String>(node, "product-code")?);
+    objects.insert(0x02, required_attribute::<String>(node, "revision")?);
+    for (name, id) in IDENTIFICATION_ATTRIBUTES {
+        if let Some(value) = optional_attribute::<String>(node, name)? {
+            objects.insert(id, value);
+        }
+    }
+    for child in node.children() {
+        if check_element_ns(&child)? {
+            match child.tag_name().name() {
+                "object" => {
+                    let id: u8 = required_attribute::<ParsedU8>(&child, "id")?.into();
+                    if id < 0x80 {
+                        return Err(ParseError::new(&child, InvalidObjectId));
+                    }
+                    let value: String = required_attribute(&child, "value")?;
+                    objects.insert(id, value);
+                }
+                _ => {
+                    return Err(ParseError::new(
+                        &child,
+                        Base(tag_list_xml::ParseErrorKind::Base(UnexpectedElement)),
+                    ))
+                }
+            }
+        }
+    }
+    if objects.values().any(|v| v.len() > MAX_OBJECT_LEN) {
+        return Err(ParseError::new(node, ObjectTooLong));
+    }
+    Ok(DeviceIdentification { objects })
+}
+
+fn parse_fault(node: &Node) -> Result<FaultDef, ParseError> {
+    let action = match node.tag_name().name() {
+        "exception" => {
+            let code: u8 = required_attribute::<ParsedU8>(node, "code")?.into();
+            if code == 0 {
+                return Err(ParseError::new(node, InvalidExceptionCode));
+            }
+            FaultAction::Exception(code)
+        }
+        "drop-response" => FaultAction::DropResponse,
+        "delay" => FaultAction::Delay(Duration::from_millis(required_attribute(node, "ms")?)),
+        "corrupt-crc" => FaultAction::CorruptCrc,
+        _ => {
+            return Err(ParseError::new(
+                node,
+                Base(tag_list_xml::ParseErrorKind::Base(UnexpectedElement)),
+            ));
+        }
+    };
+    let label = optional_attribute(node, "label")?;
+    let function = optional_attribute::<ParsedU8>(node, "function")?.map(u8::from);
+    let addr = optional_attribute::<ParsedU16>(node, "addr")?.map(u16::from);
+    let addr_low = optional_attribute::<ParsedU16>(node, "addr-low")?.map(u16::from);
+    let addr_high = optional_attribute::<ParsedU16>(node, "addr-high")?.map(u16::from);
+    let addresses = match (addr, addr_low, addr_high) {
+        (None, None, None) => None,
+        (Some(addr), None, None) => Some(addr..=addr),
+        (None, Some(low), Some(high)) if low <= high => Some(low..=high),
+        _ => return Err(ParseError::new(node, InvalidAddressRange)),
+    };
+    let probability = optional_attribute::<u8>(node, "probability")?.unwrap_or(100);
+    if probability > 100 {
+        return Err(ParseError::new(node, InvalidProbability));
+    }
+    let enabled = optional_attribute(node, "enabled")?.unwrap_or(true);
+    Ok(FaultDef {
+        label,
+        action,
+        function,
+        addresses,
+        probability,
+        enabled,
+    })
+}
+
+fn parse_faults(node: &Node) -> Result<Vec<FaultDef>, ParseError> {
+    let mut faults = Vec::new();
+    for child in node.children() {
+        if check_element_ns(&child)? {
+            faults.push(parse_fault(&child)?);
+        }
+    }
+    Ok(faults)
+}
+
+// Optional attribute parsed with one of the serial setting parsers
+fn serial_attribute<T>(
+    node: &Node,
+    name: &str,
+    parse: fn(&str) -> Result<T, InvalidValue>,
+    default: T,
+) -> Result<T, ParseError> {
+    match node.attribute(name) {
+        Some(value) => parse(value).map_err(|e| {
+            xml_common::ParseError::new(
+                node,
+                xml_common::ParseErrorKind::ParseAttribute(name.to_string(), e.into()),
+            )
+            .into()
+        }),
+        None => Ok(default),
+    }
+}
+
+fn parse_serial_settings(node: &Node) -> Result<SerialSettings, ParseError> {
+    let default = SerialSettings::default();
+    Ok(SerialSettings {
+        baud_rate: serial_attribute(
+            node,
+            "baud-rate",
+            serial_ports::parse_baud_rate,
+            default.baud_rate,
+        )?,
+        parity: serial_attribute(node, "parity", serial_ports::parse_parity, default.parity)?,
+        data_bits: serial_attribute(
+            node,
+            "data-bits",
+            serial_ports::parse_data_bits,
+            default.data_bits,
+        )?,
+        stop_bits: serial_attribute(
+            node,
+            "stop-bits",
+            serial_ports::parse_stop_bits,
+            default.stop_bits,
+        )?,
+        flow_control: serial_attribute(
+            node,
+            "flow-control",
+            serial_ports::parse_flow_control,
+            default.flow_control,
+        )?,
+    })
+}
+
+// Returns the connection and the unit address used on it, if given
+fn parse_connection(node: &Node) -> Result<(ConnectionDef, Option<u8>), ParseError> {
+    let transport: String = required_attribute(node, "transport")?;
+    let network = || -> Result<(String, u16), ParseError> {
+        let host = required_attribute(node, "host")?;
+        let port = optional_attribute(node, "port")?.unwrap_or(502);
+        Ok((host, port))
+    };
+    let connection = match transport.as_str() {
+        "tcp" => {
+            let (host, port) = network()?;
+            ConnectionDef::Tcp { host, port }
+        }
+        "rtu-over-tcp" => {
+            let (host, port) = network()?;
+            ConnectionDef::RtuOverTcp { host, port }
+        }
+        "udp" => {
+            let (host, port) = network()?;
+            ConnectionDef::Udp { host, port }
+        }
+        "rtu" => ConnectionDef::Rtu {
+            device: required_attribute(node, "device")?,
+            settings: parse_serial_settings(node)?,
+        },
+        "ascii" => ConnectionDef::Ascii {
+            device: required_attribute(node, "device")?,
+            settings: parse_serial_settings(node)?,
+        },
+        _ => return Err(ParseError::new(node, InvalidTransport)),
+    };
+    let unit = optional_attribute::<ParsedU8>(node, "unit")?.map(u8::from);
+    Ok((connection, unit))
+}
+
+fn parse_client_timing(node: &Node) -> Result<ClientTiming, ParseError> {
+    let millis = |name| -> Result<Option<Duration>, ParseError> {
+        match optional_attribute::<u64>(node, name)? {
+            Some(0) => Err(ParseError::new(node, InvalidClientTiming)),
+            ms => Ok(ms.map(Duration::from_millis)),
+        }
+    };
+    let timing = ClientTiming {
+        timeout: millis("timeout")?,
+        retries: optional_attribute(node, "retries")?,
+        reconnect_delay: millis("reconnect-delay")?,
+        max_reconnect_delay: millis("max-reconnect-delay")?,
+    };
+    if let (Some(delay), Some(max)) = (timing.reconnect_delay, timing.max_reconnect_delay)
+        && delay > max
+    {
+        return Err(ParseError::new(node, InvalidClientTiming));
+    }
+    Ok(timing)
+}
+
+fn parse_read_limits(node: &Node) -> Result<ReadLimits, ParseError> {
+    let limit = |name, max| -> Result<Option<u16>, ParseError> {
+        match optional_attribute::<u16>(node, name)? {
+            Some(n) if n == 0 || n > max => Err(ParseError::new(node, InvalidReadLimit)),
+            n => Ok(n),
+        }
+    };
+    Ok(ReadLimits {
+        max_gap: optional_attribute(node, "max-read-gap")?,
+        max_registers: limit("max-read-registers", READ_REGISTERS_MAX_LEN)?,
+        max_bits: limit("max-read-bits", READ_BITS_MAX_LEN)?,
+    })
+}
+
+// Table named by an attribute, using the element names of the tag list
+fn table_attribute(node: &Node, name: &str) -> Result<Option<Table>, ParseError> {
+    let Some(value) = node.attribute(name) else {
+        return Ok(None);
+    };
+    let table = match value {
+        "holding-registers" => Table::HoldingRegisters,
+        "input-registers" => Table::InputRegisters,
+        "coils" => Table::Coils,
+        "discrete-inputs" => Table::DiscreteInputs,
+        _ => return Err(ParseError::new(node, InvalidTable)),
+    };
+    Ok(Some(table))
+}
+
+fn parse_scheduled_write(node: &Node) -> Result<ScheduledWriteDef, ParseError> {
+    if node.tag_name().name() != "write" {
+        return Err(ParseError::new(
+            node,
+            Base(tag_list_xml::ParseErrorKind::Base(UnexpectedElement)),
+        ));
+    }
+    let label = optional_attribute(node, "label")?;
+    let table = table_attribute(node, "table")?.unwrap_or(Table::HoldingRegisters);
+    if !matches!(table, Table::HoldingRegisters | Table::Coils) {
+        return Err(ParseError::new(node, InvalidWriteTable));
+    }
+    let addr = required_attribute::<ParsedU16>(node, "addr")?.into();
+    let value = required_attribute::<ParsedU16>(node, "value")?.into();
+    let interval: Option<u64> = optional_attribute(node, "interval")?;
+    let when_addr = optional_attribute::<ParsedU16>(node, "when-addr")?.map(u16::from);
+    // Watch the table written to unless told otherwise
+    let when_table = table_attribute(node, "when-table")?.unwrap_or(table);
+    let above = optional_attribute::<ParsedU16>(node, "above")?.map(u16::from);
+    let below = optional_attribute::<ParsedU16>(node, "below")?.map(u16::from);
+    let trigger = match (interval, when_addr, above, below) {
+        (Some(ms), None, None, None) if ms > 0 => WriteTrigger::Interval(Duration::from_millis(ms)),
+        (None, Some(addr), Some(threshold), None) => WriteTrigger::Above {
+            table: when_table,
+            addr,
+            threshold,
+        },
+        (None, Some(addr), None, Some(threshold)) => WriteTrigger::Below {
+            table: when_table,
+            addr,
+            threshold,
+        },
+        _ => return Err(ParseError::new(node, InvalidWriteTrigger)),
+    };
+    let enabled = optional_attribute(node, "enabled")?.unwrap_or(true);
+    Ok(ScheduledWriteDef {
+        label,
+        table,
+        addr,
+        value,
+        trigger,
+        enabled,
+    })
+}
+
+fn parse_write_schedule(node: &Node) -> Result<Vec<ScheduledWriteDef>, ParseError> {
+    let mut writes = Vec::new();
+    for child in node.children() {
+        if check_element_ns(&child)? {
+            writes.push(parse_scheduled_write(&child)?);
+        }
+    }
+    Ok(writes)
+}
+
 fn parse_device(node: &Node) -> Result<DeviceDef, ParseError> {
     let addr = required_attribute::<ParsedU8>(node, "addr")?.into();
-    let tags = parse_tag_list(node)?;
+    let response_delay: ResponseDelay =
+        optional_attribute(node, "response-delay")?.unwrap_or_default();
+    if response_delay.min > response_delay.max {
+        return Err(ParseError::new(node, InvalidResponseDelay));
+    }
+    let poll = tag_list_xml::parse_poll(node)?;
+    let read_limits = parse_read_limits(node)?;
+    let mut tags = TagDefList::default();
+    let mut identification = None;
+    let mut faults = Vec::new();
+    let mut connection = None;
+    let mut remote_addr = None;
+    let mut client_timing = ClientTiming::default();
+    let mut write_schedule = Vec::new();
+    for child in node.children() {
+        if check_element_ns(&child)? {
+            match child.tag_name().name() {
+                "connection" => {
+                    let (conn, unit) = parse_connection(&child)?;
+                    connection = Some(conn);
+                    remote_addr = unit;
+                    client_timing = parse_client_timing(&child)?;
+                }
+                "identification" => {
+                    identification = Some(parse_identification(&child)?);
+                }
+                "faults" => {
+                    faults = parse_faults(&child)?;
+                }
+                "write-schedule" => {
+                    write_schedule = parse_write_schedule(&child)?;
+                }
+                _ => {
+                    if !parse_tag_list_child(&mut tags, &child)? {
+                        return Err(ParseError::new(
+                            &child,
+                            Base(tag_list_xml::ParseErrorKind::Base(UnexpectedElement)),
+                        ));
+                    }
+                }
+            }
+        }
+    }
 
-    Ok(DeviceDef { addr, tags })
+    Ok(DeviceDef {
+        addr,
+        tags,
+        identification,
+        faults,
+        response_delay,
+        connection,
+        remote_addr: remote_addr.unwrap_or(addr),
+        client_timing,
+        poll,
+        read_limits,
+        write_schedule,
+    })
 }
 
 pub fn parse_device_list(node: &Node) -> Result<DeviceDefList, ParseError> {
@@ -92,3 +498,169 @@ pub fn parse_device_list(node: &Node) -> Result<DeviceDefList, ParseError> {
 
     Ok(devices)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use roxmltree::Document;
+    use tokio_serial::Parity;
+
+    #[test]
+    fn parse_connection_test() {
+        let doc = Document::parse(
+            r#"
+<tag-list xmlns="http://www.elektro-kapsel.se/xml/modbus_config/v2">
+  <device addr="1">
+    <connection transport="tcp" host="10.0.0.2" unit="255" timeout="2000" retries="2"
+                reconnect-delay="500"/>
+  </device>
+  <device addr="2">
+    <connection transport="rtu" device="/dev/ttyUSB0" baud-rate="19200" parity="none"/>
+  </device>
+  <device addr="3"/>
+</tag-list>
+"#,
+        )
+        .unwrap();
+        let devices = parse_device_list(&doc.root_element()).unwrap();
+        let dev = devices.get(1).unwrap();
+        assert_eq!(
+            dev.connection,
+            Some(ConnectionDef::Tcp {
+                host: "10.0.0.2".to_string(),
+                port: 502
+            })
+        );
+        assert_eq!(dev.remote_addr, 255);
+        assert_eq!(
+            dev.client_timing,
+            ClientTiming {
+                timeout: Some(Duration::from_millis(2000)),
+                retries: Some(2),
+                reconnect_delay: Some(Duration::from_millis(500)),
+                max_reconnect_delay: None,
+            }
+        );
+        let dev = devices.get(2).unwrap();
+        assert_eq!(
+            dev.connection,
+            Some(ConnectionDef::Rtu {
+                device: "/dev/ttyUSB0".to_string(),
+                settings: SerialSettings {
+                    baud_rate: 19200,
+                    parity: Parity::None,
+                    ..SerialSettings::default()
+                }
+            })
+        );
+        assert_eq!(dev.remote_addr, 2);
+        assert_eq!(dev.client_timing, ClientTiming::default());
+        assert_eq!(devices.get(3).unwrap().connection, None);
+
+        for connection in [
+            r#"<connection transport="serial" device="/dev/ttyS0"/>"#,
+            r#"<connection transport="udp"/>"#,
+            r#"<connection transport="ascii" device="/dev/ttyS0" stop-bits="3"/>"#,
+            r#"<connection transport="tcp" host="10.0.0.2" timeout="0"/>"#,
+            r#"<connection transport="tcp" host="10.0.0.2" reconnect-delay="5000"
+                max-reconnect-delay="1000"/>"#,
+        ] {
+            let xml = format!(
+                r#"<tag-list xmlns="http://www.elektro-kapsel.se/xml/modbus_config/v2">
+  <device addr="1">{connection}</device></tag-list>"#
+            );
+            let doc = Document::parse(&xml).unwrap();
+            assert!(parse_device_list(&doc.root_element()).is_err());
+        }
+    }
+
+    #[test]
+    fn parse_faults_test() {
+        let doc = Document::parse(
+            r#"
+<tag-list xmlns="http://www.elektro-kapsel.se/xml/modbus_config/v2">
+  <device addr="1">
+    <faults>
+      <exception function="0x03" addr="0x1000" code="2"/>
+      <drop-response addr-low="0x10" addr-high="31" probability="50"/>
+    </faults>
+  </device>
+</tag-list>
+"#,
+        )
+        .unwrap();
+        let devices = parse_device_list(&doc.root_element()).unwrap();
+        let faults = &devices.get(1).unwrap().faults;
+        assert_eq!(faults[0].function, Some(3));
+        assert_eq!(faults[0].addresses, Some(0x1000..=0x1000));
+        assert_eq!(faults[1].addresses, Some(16..=31));
+        assert_eq!(faults[1].probability, 50);
+    }
+
+    #[test]
+    fn parse_write_schedule_test() {
+        let doc = Document::parse(
+            r#"
+<tag-list xmlns="http://www.elektro-kapsel.se/xml/modbus_config/v2">
+  <device addr="1">
+    <write-schedule>
+      <write label="Start" addr="10" value="0x2a" interval="5000"/>
+      <write table="coils" addr="3" value="1" when-table="input-registers" when-addr="0"
+             above="100" enabled="false"/>
+      <write addr="0x0b" value="0" when-addr="0x0c" below="5"/>
+    </write-schedule>
+  </device>
+</tag-list>
+"#,
+        )
+        .unwrap();
+        let devices = parse_device_list(&doc.root_element()).unwrap();
+        let writes = &devices.get(1).unwrap().write_schedule;
+        assert_eq!(
+            writes[0],
+            ScheduledWriteDef {
+                label: Some("Start".to_string()),
+                table: Table::HoldingRegisters,
+                addr: 10,
+                value: 42,
+                trigger: WriteTrigger::Interval(Duration::from_secs(5)),
+                enabled: true,
+            }
+        );
+        assert_eq!(writes[1].table, Table::Coils);
+        assert_eq!(
+            writes[1].trigger,
+            WriteTrigger::Above {
+                table: Table::InputRegisters,
+                addr: 0,
+                threshold: 100
+            }
+        );
+        assert!(!writes[1].enabled);
+        assert_eq!(writes[2].addr, 11);
+        assert_eq!(
+            writes[2].trigger,
+            WriteTrigger::Below {
+                table: Table::HoldingRegisters,
+                addr: 12,
+                threshold: 5
+            }
+        );
+
+        for write in [
+            r#"<write table="input-registers" addr="0" value="1" interval="100"/>"#,
+            r#"<write addr="0" value="1"/>"#,
+            r#"<write addr="0" value="1" interval="0"/>"#,
+            r#"<write addr="0" value="1" interval="100" when-addr="1" above="2"/>"#,
+            r#"<write addr="0" value="1" when-addr="1" above="2" below="1"/>"#,
+            r#"<write addr="0" value="1" when-table="registers" when-addr="1" above="2"/>"#,
+        ] {
+            let xml = format!(
+                r#"<tag-list xmlns="http://www.elektro-kapsel.se/xml/modbus_config/v2">
+  <device addr="1"><write-schedule>{write}</write-schedule></device></tag-list>"#
+            );
+            let doc = Document::parse(&xml).unwrap();
+            assert!(parse_device_list(&doc.root_element()).is_err());
+        }
+    }
+}
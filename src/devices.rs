@@ -1,23 +1,171 @@
-use crate::device_list::{DeviceDef, DeviceDefList};
+use crate::device_list::{
+    DeviceDef, DeviceDefList, DeviceIdentification, ReadLimits, ResponseDelay,
+};
+use crate::faults::Faults;
+use crate::observable_array::ObservableArray;
+use crate::records::{Records, Updated as UpdatedRecords};
 use crate::tag_ranges::TagRanges;
 use crate::tags::{Tags, Updated as UpdatedTags};
+use crate::write_schedule::WriteSchedule;
 use futures::future;
-use std::sync::Arc;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::ops::Range;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::SystemTime;
+use tokio::sync::{Mutex as AsyncMutex, broadcast, mpsc};
+use tokio_modbus::ExceptionCode;
 
 #[derive(Clone)]
 pub struct Device {
     unit: u8,
+    // Unit address used when polling the device in client mode
+    remote_unit: u8,
+    read_limits: ReadLimits,
     tags: Tags,
+    records: Records,
     ranges: Arc<TagRanges>,
+    identification: Option<Arc<DeviceIdentification>>,
+    faults: Arc<Faults>,
+    write_schedule: Arc<WriteSchedule>,
+    response_delay: Arc<RwLock<ResponseDelay>>,
+    // Identification read from the remote device in client mode
+    remote_identification: Arc<RwLock<Option<DeviceIdentification>>>,
+    status: Arc<RwLock<UnitStatus>>,
+    // Ranges read in client mode, indexed by table and start address
+    health: Arc<RwLock<BTreeMap<(Table, u16), RangeHealth>>>,
+    // Queue of the client serving the device, if any
+    writes: Arc<Mutex<Option<mpsc::UnboundedSender<WriteRequest>>>>,
+}
+
+/// Communication with the remote device in client mode
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct UnitStatus {
+    /// Whether the last request was answered. None before the first
+    /// request.
+    pub responding: Option<bool>,
+    pub requests: u64,
+    pub failures: u64,
+    pub last_error: Option<String>,
+    /// Requests without a response since the last one that was answered
+    pub consecutive_failures: u32,
+    /// Too many consecutive requests without a response
+    pub offline: bool,
+}
+
+/// Register or bit table of a device
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Table {
+    HoldingRegisters,
+    InputRegisters,
+    Coils,
+    DiscreteInputs,
+}
+
+/// How far values read in client mode can be trusted
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum Quality {
+    /// Not read yet
+    #[default]
+    Unknown,
+    /// The last read succeeded
+    Good,
+    /// The last read failed, the values are from an earlier read
+    Stale,
+    /// Never read successfully
+    Bad,
+}
+
+/// Why reading a range failed
+#[derive(Clone, Debug, PartialEq)]
+pub enum RangeError {
+    Exception(ExceptionCode),
+    Timeout,
+    Io(String),
+}
+
+impl std::fmt::Display for RangeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RangeError::Exception(code) => write!(f, "{code} (0x{:02x})", u8::from(*code)),
+            RangeError::Timeout => write!(f, "No response"),
+            RangeError::Io(e) => write!(f, "I/O error: {e}"),
+        }
+    }
+}
+
+/// Outcome of reading a range of addresses in client mode
+#[derive(Clone, Debug, PartialEq)]
+pub struct RangeHealth {
+    pub table: Table,
+    pub start: u16,
+    pub length: u16,
+    pub quality: Quality,
+    pub last_success: Option<SystemTime>,
+    pub last_error: Option<RangeError>,
+}
+
+/// Values changed from the web interface that a client should write to
+/// the remote unit
+#[derive(Clone, Debug, PartialEq)]
+pub struct WriteRequest {
+    /// WebSocket connection to report the result to, if any
+    pub origin: Option<u64>,
+    pub unit: u8,
+    pub table: Table,
+    pub start: u16,
+    /// Values to write, bits as 0 or 1
+    pub values: Vec<u16>,
+    /// Values in the tags before the change, as last read from the
+    /// remote unit
+    pub previous: Vec<u16>,
+}
+
+/// Outcome of a write requested with `Devices::write_values`
+#[derive(Clone, Debug, PartialEq)]
+pub struct WriteResult {
+    pub request: WriteRequest,
+    pub error: Option<String>,
+}
+
+/// A request forwarded in gateway or tap mode and its result
+#[derive(Clone, Debug)]
+pub struct Traffic {
+    pub unit: u8,
+    pub request: String,
+    pub response: String,
+}
+
+/// Changes to a device, other than tag values
+#[derive(Clone, Debug)]
+pub enum Event {
+    RemoteIdentification(u8),
+    FaultsChanged(u8),
+    WriteScheduleChanged(u8),
+    ResponseDelayChanged(u8),
+    UnitStatusChanged(u8),
+    RangeHealthChanged(u8),
+    // Values wanted by the web interface, read tags polled on demand
+    ReadRequested(u8),
+    WriteDone(Arc<WriteResult>),
 }
 
 #[derive(Clone)]
-pub struct Devices(Vec<Device>);
+pub struct Devices {
+    devices: Vec<Device>,
+    events: broadcast::Sender<Event>,
+    traffic: broadcast::Sender<Arc<Traffic>>,
+    // Writes requested for the selected units
+    write_queue: Option<Arc<AsyncMutex<mpsc::UnboundedReceiver<WriteRequest>>>>,
+}
 
 #[derive(Debug)]
 pub enum Error {
     UnitNotAvailabe,
     LockFailed,
+    OutOfRange,
+    /// The table can't be written by a client
+    ReadOnly(Table),
 }
 
 impl std::error::Error for Error {}
@@ -28,6 +176,14 @@ impl std::fmt::Display for Error {
         match self {
             UnitNotAvailabe => write!(f, "Unit not available"),
             LockFailed => write!(f, "Unit not available"),
+            OutOfRange => write!(f, "Address out of range"),
+            ReadOnly(Table::InputRegisters) => {
+                write!(f, "Input registers are read only in client mode")
+            }
+            ReadOnly(Table::DiscreteInputs) => {
+                write!(f, "Discrete inputs are read only in client mode")
+            }
+            ReadOnly(_) => write!(f, "Table is read only in client mode"),
         }
     }
 }
@@ -35,30 +191,71 @@ fn get_unit(dev: &Device) -> u8 {
     dev.unit
 }
 
+// Values in a range of a table, bits as 0 or 1
+fn read_values<T, F>(array: &ObservableArray<T>, range: Range<usize>, f: F) -> Option<Vec<u16>>
+where
+    T: Default + Clone + Send + Sync + 'static,
+    F: Fn(&T) -> u16,
+{
+    if range.end > array.len() {
+        return None;
+    }
+    Some(array.get_array(|a| a[range].iter().map(f).collect()))
+}
+
 impl Devices {
     pub fn new(init: &DeviceDefList) -> Devices {
         let mut devs: Vec<Device> = Vec::new();
         for DeviceDef {
             tags: tag_list,
             addr,
+            identification,
+            faults,
+            response_delay,
+            remote_addr,
+            poll,
+            read_limits,
+            write_schedule,
+            ..
         } in init
         {
             let tags = Tags::new(&tag_list);
-            let ranges = Arc::new(TagRanges::from(&*tag_list));
+            let records = Records::new(tag_list);
+            let ranges = Arc::new(TagRanges::with_poll(tag_list, *poll));
             let dev = Device {
                 unit: *addr,
+                remote_unit: *remote_addr,
+                read_limits: *read_limits,
                 tags,
+                records,
                 ranges,
+                identification: identification.clone().map(Arc::new),
+                faults: Arc::new(Faults::new(faults)),
+                write_schedule: Arc::new(WriteSchedule::new(write_schedule)),
+                response_delay: Arc::new(RwLock::new(*response_delay)),
+                remote_identification: Arc::new(RwLock::new(None)),
+                status: Arc::new(RwLock::new(UnitStatus::default())),
+                health: Arc::new(RwLock::new(BTreeMap::new())),
+                writes: Arc::new(Mutex::new(None)),
             };
             devs.push(dev);
         }
         devs.sort_by_key(get_unit);
-        Devices(devs)
+        let (events, _) = broadcast::channel(64);
+        // Forwarded traffic comes in bursts. Listeners falling behind only
+        // miss traffic, not other events.
+        let (traffic, _) = broadcast::channel(256);
+        Devices {
+            devices: devs,
+            events,
+            traffic,
+            write_queue: None,
+        }
     }
 
     fn find_unit(&self, unit: u8) -> Option<&Device> {
-        match self.0.binary_search_by_key(&unit, get_unit) {
-            Ok(index) => Some(&self.0[index]),
+        match self.devices.binary_search_by_key(&unit, get_unit) {
+            Ok(index) => Some(&self.devices[index]),
             Err(_) => None,
         }
     }
@@ -84,6 +281,16 @@ impl Devices {
         let tags = &dev.tags;
         Ok(f(tags))
     }
+    pub fn records_read<F, R>(&self, unit: u8, f: F) -> Result<R, Error>
+    where
+        F: FnOnce(&Records) -> R,
+    {
+        let Some(dev) = self.find_unit(unit) else {
+            return Err(Error::UnitNotAvailabe);
+        };
+        Ok(f(&dev.records))
+    }
+
     pub fn ranges(&self, unit: u8) -> Result<&TagRanges, Error> {
         let Some(dev) = self.find_unit(unit) else {
             return Err(Error::UnitNotAvailabe);
@@ -91,14 +298,335 @@ impl Devices {
         Ok(&dev.ranges)
     }
 
+    pub fn identification(&self, unit: u8) -> Result<Option<&DeviceIdentification>, Error> {
+        let Some(dev) = self.find_unit(unit) else {
+            return Err(Error::UnitNotAvailabe);
+        };
+        Ok(dev.identification.as_deref())
+    }
+
+    pub fn remote_identification(&self, unit: u8) -> Result<Option<DeviceIdentification>, Error> {
+        let Some(dev) = self.find_unit(unit) else {
+            return Err(Error::UnitNotAvailabe);
+        };
+        let ident = dev
+            .remote_identification
+            .read()
+            .map_err(|_| Error::LockFailed)?;
+        Ok(ident.clone())
+    }
+
+    pub fn set_remote_identification(
+        &self,
+        unit: u8,
+        ident: DeviceIdentification,
+    ) -> Result<(), Error> {
+        let Some(dev) = self.find_unit(unit) else {
+            return Err(Error::UnitNotAvailabe);
+        };
+        *dev.remote_identification
+            .write()
+            .map_err(|_| Error::LockFailed)? = Some(ident);
+        let _ = self.events.send(Event::RemoteIdentification(unit));
+        Ok(())
+    }
+
+    pub fn faults(&self, unit: u8) -> Result<&Faults, Error> {
+        let Some(dev) = self.find_unit(unit) else {
+            return Err(Error::UnitNotAvailabe);
+        };
+        Ok(&dev.faults)
+    }
+
+    /// Returns false if the device has no fault with this index
+    pub fn set_fault_enabled(&self, unit: u8, index: usize, enabled: bool) -> Result<bool, Error> {
+        let found = self.faults(unit)?.set_enabled(index, enabled);
+        if found {
+            let _ = self.events.send(Event::FaultsChanged(unit));
+        }
+        Ok(found)
+    }
+
+    pub fn write_schedule(&self, unit: u8) -> Result<&WriteSchedule, Error> {
+        let Some(dev) = self.find_unit(unit) else {
+            return Err(Error::UnitNotAvailabe);
+        };
+        Ok(&dev.write_schedule)
+    }
+
+    /// Returns false if the device has no scheduled write with this index
+    pub fn set_scheduled_write_enabled(
+        &self,
+        unit: u8,
+        index: usize,
+        enabled: bool,
+    ) -> Result<bool, Error> {
+        let found = self.write_schedule(unit)?.set_enabled(index, enabled);
+        if found {
+            let _ = self.events.send(Event::WriteScheduleChanged(unit));
+        }
+        Ok(found)
+    }
+
+    pub fn response_delay(&self, unit: u8) -> Result<ResponseDelay, Error> {
+        let Some(dev) = self.find_unit(unit) else {
+            return Err(Error::UnitNotAvailabe);
+        };
+        let delay = dev.response_delay.read().map_err(|_| Error::LockFailed)?;
+        Ok(*delay)
+    }
+
+    pub fn set_response_delay(&self, unit: u8, delay: ResponseDelay) -> Result<(), Error> {
+        let Some(dev) = self.find_unit(unit) else {
+            return Err(Error::UnitNotAvailabe);
+        };
+        *dev.response_delay.write().map_err(|_| Error::LockFailed)? = delay;
+        let _ = self.events.send(Event::ResponseDelayChanged(unit));
+        Ok(())
+    }
+
+    pub fn unit_status(&self, unit: u8) -> Result<UnitStatus, Error> {
+        let Some(dev) = self.find_unit(unit) else {
+            return Err(Error::UnitNotAvailabe);
+        };
+        let status = dev.status.read().map_err(|_| Error::LockFailed)?;
+        Ok(status.clone())
+    }
+
+    /// Record the outcome of a request to a remote unit. A unit
+    /// answering with an exception is responding but the request failed.
+    /// The unit is offline after `offline_after` consecutive requests
+    /// without a response, never if it's 0.
+    /// Listeners are only notified when the unit starts or stops
+    /// responding, goes offline or fails in a new way.
+    pub fn record_request(
+        &self,
+        unit: u8,
+        responding: bool,
+        error: Option<String>,
+        offline_after: u32,
+    ) -> Result<(), Error> {
+        let Some(dev) = self.find_unit(unit) else {
+            return Err(Error::UnitNotAvailabe);
+        };
+        let mut status = dev.status.write().map_err(|_| Error::LockFailed)?;
+        let before = (status.responding, status.last_error.clone(), status.offline);
+        status.requests += 1;
+        status.responding = Some(responding);
+        if error.is_some() {
+            status.failures += 1;
+            status.last_error = error;
+        }
+        if responding {
+            status.consecutive_failures = 0;
+        } else {
+            status.consecutive_failures = status.consecutive_failures.saturating_add(1);
+        }
+        status.offline = offline_after > 0 && status.consecutive_failures >= offline_after;
+        if before != (status.responding, status.last_error.clone(), status.offline) {
+            let _ = self.events.send(Event::UnitStatusChanged(unit));
+        }
+        Ok(())
+    }
+
+    /// Record the outcome of reading a range from a remote unit.
+    /// Listeners are notified when the quality changes or the read fails
+    /// in a new way.
+    pub fn record_range(
+        &self,
+        unit: u8,
+        table: Table,
+        range: Range<u16>,
+        result: Result<(), RangeError>,
+    ) -> Result<(), Error> {
+        let Some(dev) = self.find_unit(unit) else {
+            return Err(Error::UnitNotAvailabe);
+        };
+        let mut health = dev.health.write().map_err(|_| Error::LockFailed)?;
+        let range_health = health
+            .entry((table, range.start))
+            .or_insert_with(|| RangeHealth {
+                table,
+                start: range.start,
+                length: range.end - range.start,
+                quality: Quality::Unknown,
+                last_success: None,
+                last_error: None,
+            });
+        let before = (range_health.quality, range_health.last_error.clone());
+        match result {
+            Ok(()) => {
+                range_health.quality = Quality::Good;
+                range_health.last_success = Some(SystemTime::now());
+            }
+            Err(e) => {
+                range_health.quality = match range_health.last_success {
+                    Some(_) => Quality::Stale,
+                    None => Quality::Bad,
+                };
+                range_health.last_error = Some(e);
+            }
+        }
+        if before != (range_health.quality, range_health.last_error.clone()) {
+            let _ = self.events.send(Event::RangeHealthChanged(unit));
+        }
+        Ok(())
+    }
+
+    /// Ranges read from a remote unit so far, in address order per table
+    pub fn range_health(&self, unit: u8) -> Result<Vec<RangeHealth>, Error> {
+        let Some(dev) = self.find_unit(unit) else {
+            return Err(Error::UnitNotAvailabe);
+        };
+        let health = dev.health.read().map_err(|_| Error::LockFailed)?;
+        Ok(health.values().cloned().collect())
+    }
+
+    /// Ask the client to read the tags of a unit that are polled on
+    /// demand
+    pub fn request_read(&self, unit: u8) {
+        let _ = self.events.send(Event::ReadRequested(unit));
+    }
+
+    /// Values in a table of a unit, bits as 0 or 1
+    pub fn values(&self, unit: u8, table: Table, range: Range<usize>) -> Result<Vec<u16>, Error> {
+        self.tags_read(unit, |tags| match table {
+            Table::HoldingRegisters => read_values(&tags.holding_registers, range, |&v| v),
+            Table::InputRegisters => read_values(&tags.input_registers, range, |&v| v),
+            Table::Coils => read_values(&tags.coils, range, |&b| b as u16),
+            Table::DiscreteInputs => read_values(&tags.discrete_inputs, range, |&b| b as u16),
+        })?
+        .ok_or(Error::OutOfRange)
+    }
+
+    /// Change values in the tags of a unit and ask the client serving the
+    /// unit to write them. Nothing is written if there's no client. A
+    /// client can't write input registers or discrete inputs, so those
+    /// are left unchanged when there is one.
+    pub fn write_values(
+        &self,
+        origin: Option<u64>,
+        unit: u8,
+        table: Table,
+        start: u16,
+        values: &[u16],
+    ) -> Result<(), Error> {
+        let Some(dev) = self.find_unit(unit) else {
+            return Err(Error::UnitNotAvailabe);
+        };
+        let writes = dev.writes.lock().map_err(|_| Error::LockFailed)?;
+        if writes.is_some() && matches!(table, Table::InputRegisters | Table::DiscreteInputs) {
+            return Err(Error::ReadOnly(table));
+        }
+        let range = usize::from(start)..usize::from(start) + values.len();
+        let previous = self.values(unit, table, range)?;
+        let bits: Vec<_> = values.iter().map(|&v| v != 0).collect();
+        let start_index = usize::from(start);
+        match table {
+            Table::HoldingRegisters => dev.tags.holding_registers.update(start_index, values),
+            Table::InputRegisters => dev.tags.input_registers.update(start_index, values),
+            Table::Coils => dev.tags.coils.update(start_index, &bits),
+            Table::DiscreteInputs => dev.tags.discrete_inputs.update(start_index, &bits),
+        }
+        if let Some(writes) = writes.as_ref() {
+            let _ = writes.send(WriteRequest {
+                origin,
+                unit,
+                table,
+                start,
+                values: values.to_vec(),
+                previous,
+            });
+        }
+        Ok(())
+    }
+
+    /// Wait for the next write requested for these devices. Writes are
+    /// queued until taken, but only for devices returned by `select`.
+    pub async fn next_write(&self) -> WriteRequest {
+        if let Some(queue) = &self.write_queue
+            && let Some(request) = queue.lock().await.recv().await
+        {
+            return request;
+        }
+        future::pending().await
+    }
+
+    pub fn report_write(&self, result: WriteResult) {
+        let _ = self.events.send(Event::WriteDone(Arc::new(result)));
+    }
+
+    /// Report forwarded traffic. The unit doesn't have to be configured.
+    pub fn record_traffic(&self, traffic: Traffic) {
+        let _ = self.traffic.send(Arc::new(traffic));
+    }
+
     pub async fn updated(&self) -> (u8, UpdatedTags) {
-        let notify = future::select_all(self.0.iter().map(|dev| Box::pin(dev.tags.updated())));
+        let notify =
+            future::select_all(self.devices.iter().map(|dev| Box::pin(dev.tags.updated())));
         let (updated, index, _) = notify.await;
-        let unit = self.0[index].unit;
+        let unit = self.devices[index].unit;
         (unit, updated)
     }
+
+    pub async fn records_updated(&self) -> (u8, UpdatedRecords) {
+        let notify = future::select_all(self.devices.iter().map(|dev| dev.records.updated()));
+        let (updated, index, _) = notify.await;
+        let unit = self.devices[index].unit;
+        (unit, updated)
+    }
+
+    /// Receive events for all devices
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.events.subscribe()
+    }
+
+    pub fn subscribe_traffic(&self) -> broadcast::Receiver<Arc<Traffic>> {
+        self.traffic.subscribe()
+    }
+
+    /// Unit address to use when polling a device in client mode
+    pub fn remote_unit(&self, unit: u8) -> Result<u8, Error> {
+        let Some(dev) = self.find_unit(unit) else {
+            return Err(Error::UnitNotAvailabe);
+        };
+        Ok(dev.remote_unit)
+    }
+
+    /// Limits on the requests reading the unit in client mode
+    pub fn read_limits(&self, unit: u8) -> Result<ReadLimits, Error> {
+        let Some(dev) = self.find_unit(unit) else {
+            return Err(Error::UnitNotAvailabe);
+        };
+        Ok(dev.read_limits)
+    }
+
+    /// The given units, sharing state and events with these devices.
+    /// Writes requested for the units from now on go to the returned
+    /// devices.
+    pub fn select(&self, units: &[u8]) -> Devices {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let devices: Vec<_> = self
+            .devices
+            .iter()
+            .filter(|d| units.contains(&d.unit))
+            .cloned()
+            .collect();
+        for dev in &devices {
+            if let Ok(mut writes) = dev.writes.lock() {
+                *writes = Some(sender.clone());
+            }
+        }
+        Devices {
+            devices,
+            events: self.events.clone(),
+            traffic: self.traffic.clone(),
+            write_queue: Some(Arc::new(AsyncMutex::new(receiver))),
+        }
+    }
+
     /// Iterate over unit numbers
     pub fn units(&self) -> impl Iterator<Item = u8> {
-        self.0.iter().map(|d| d.unit)
+        self.devices.iter().map(|d| d.unit)
     }
 }
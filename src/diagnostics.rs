@@ -0,0 +1,324 @@
+// Serial line diagnostics as described in the Modbus application
+// protocol specification, function codes 8, 11 and 12.
+
+use crate::devices::Error;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Mutex, MutexGuard};
+use tokio_modbus::bytes::Bytes;
+use tokio_modbus::{ExceptionCode, Response};
+
+pub const FC_DIAGNOSTICS: u8 = 0x08;
+pub const FC_GET_COMM_EVENT_COUNTER: u8 = 0x0b;
+pub const FC_GET_COMM_EVENT_LOG: u8 = 0x0c;
+
+const EVENT_LOG_LEN: usize = 64;
+
+// Event log entries
+const EVENT_RECEIVE: u8 = 0x80;
+const EVENT_RECEIVE_COMM_ERROR: u8 = 0x02;
+const EVENT_RECEIVE_OVERRUN: u8 = 0x10;
+const EVENT_RECEIVE_LISTEN_ONLY: u8 = 0x20;
+const EVENT_RECEIVE_BROADCAST: u8 = 0x40;
+const EVENT_SEND: u8 = 0x40;
+const EVENT_SEND_READ_EXCEPTION: u8 = 0x01;
+const EVENT_SEND_ABORT_EXCEPTION: u8 = 0x02;
+const EVENT_SEND_BUSY_EXCEPTION: u8 = 0x04;
+const EVENT_SEND_NAK_EXCEPTION: u8 = 0x08;
+const EVENT_SEND_LISTEN_ONLY: u8 = 0x20;
+const EVENT_LISTEN_ONLY: u8 = 0x04;
+const EVENT_RESTART: u8 = 0x00;
+
+// Diagnostics sub-functions
+const RETURN_QUERY_DATA: u16 = 0x00;
+const RESTART_COMMUNICATIONS: u16 = 0x01;
+const RETURN_DIAGNOSTIC_REGISTER: u16 = 0x02;
+const CHANGE_ASCII_DELIMITER: u16 = 0x03;
+const FORCE_LISTEN_ONLY: u16 = 0x04;
+const CLEAR_COUNTERS: u16 = 0x0a;
+const BUS_MESSAGE_COUNT: u16 = 0x0b;
+const BUS_COMM_ERROR_COUNT: u16 = 0x0c;
+const BUS_EXCEPTION_COUNT: u16 = 0x0d;
+const SERVER_MESSAGE_COUNT: u16 = 0x0e;
+const SERVER_NO_RESPONSE_COUNT: u16 = 0x0f;
+const SERVER_NAK_COUNT: u16 = 0x10;
+const SERVER_BUSY_COUNT: u16 = 0x11;
+const BUS_CHAR_OVERRUN_COUNT: u16 = 0x12;
+const CLEAR_OVERRUN: u16 = 0x14;
+
+#[derive(Default, Clone, Debug)]
+pub struct Counters {
+    pub bus_message: u16,
+    pub bus_comm_error: u16,
+    pub bus_exception: u16,
+    pub server_message: u16,
+    pub server_no_response: u16,
+    pub server_nak: u16,
+    pub server_busy: u16,
+    pub bus_char_overrun: u16,
+}
+
+#[derive(Default)]
+struct UnitDiagnostics {
+    counters: Counters,
+    event_counter: u16,
+    diagnostic_register: u16,
+    listen_only: bool,
+    // Most recent event first
+    events: VecDeque<u8>,
+}
+
+impl UnitDiagnostics {
+    fn event(&mut self, event: u8) {
+        self.events.push_front(event);
+        self.events.truncate(EVENT_LOG_LEN);
+    }
+
+    fn clear_counters(&mut self) {
+        self.counters = Counters::default();
+        self.diagnostic_register = 0;
+    }
+}
+
+/// Communication counters and event logs for every unit served on a
+/// serial line. All units see the same bus.
+pub struct Diagnostics {
+    units: Mutex<BTreeMap<u8, UnitDiagnostics>>,
+}
+
+fn inc(counter: &mut u16) {
+    *counter = counter.wrapping_add(1);
+}
+
+fn send_event(exception: Option<ExceptionCode>) -> u8 {
+    use ExceptionCode::*;
+    EVENT_SEND
+        | match exception {
+            None => 0,
+            Some(IllegalFunction | IllegalDataAddress | IllegalDataValue) => {
+                EVENT_SEND_READ_EXCEPTION
+            }
+            Some(ServerDeviceFailure) => EVENT_SEND_ABORT_EXCEPTION,
+            Some(Acknowledge | ServerDeviceBusy) => EVENT_SEND_BUSY_EXCEPTION,
+            Some(e) if u8::from(e) == 0x07 => EVENT_SEND_NAK_EXCEPTION,
+            Some(_) => 0,
+        }
+}
+
+impl Diagnostics {
+    pub fn new(units: impl Iterator<Item = u8>) -> Diagnostics {
+        Diagnostics {
+            units: Mutex::new(units.map(|u| (u, UnitDiagnostics::default())).collect()),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, BTreeMap<u8, UnitDiagnostics>> {
+        self.units.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn unit<F, R>(&self, unit: u8, f: F) -> Result<R, Error>
+    where
+        F: FnOnce(&mut UnitDiagnostics) -> R,
+    {
+        let mut units = self.lock();
+        let diag = units.get_mut(&unit).ok_or(Error::UnitNotAvailabe)?;
+        Ok(f(diag))
+    }
+
+    /// A frame with a valid CRC was seen on the bus
+    pub fn bus_message(&self) {
+        for diag in self.lock().values_mut() {
+            inc(&mut diag.counters.bus_message);
+        }
+    }
+
+    /// A frame with an invalid CRC was seen on the bus
+    pub fn bus_comm_error(&self) {
+        for diag in self.lock().values_mut() {
+            inc(&mut diag.counters.bus_comm_error);
+            diag.event(EVENT_RECEIVE | EVENT_RECEIVE_COMM_ERROR);
+        }
+    }
+
+    /// A frame was longer than the maximum ADU length
+    pub fn bus_char_overrun(&self) {
+        for diag in self.lock().values_mut() {
+            inc(&mut diag.counters.bus_char_overrun);
+            diag.event(EVENT_RECEIVE | EVENT_RECEIVE_OVERRUN);
+        }
+    }
+
+    /// A request addressed to the unit, or broadcast, was received
+    pub fn received(&self, unit: u8, broadcast: bool) {
+        let _ = self.unit(unit, |diag| {
+            inc(&mut diag.counters.server_message);
+            let mut event = EVENT_RECEIVE;
+            if diag.listen_only {
+                event |= EVENT_RECEIVE_LISTEN_ONLY;
+            }
+            if broadcast {
+                event |= EVENT_RECEIVE_BROADCAST;
+            }
+            diag.event(event);
+        });
+    }
+
+    pub fn listen_only(&self, unit: u8) -> bool {
+        self.unit(unit, |diag| diag.listen_only).unwrap_or(false)
+    }
+
+    /// A request was ignored in listen only mode
+    pub fn ignored(&self, unit: u8) {
+        let _ = self.unit(unit, |diag| inc(&mut diag.counters.server_no_response));
+    }
+
+    /// Processing of a request is finished. `responded` is false if
+    /// no response was sent.
+    pub fn completed(
+        &self,
+        unit: u8,
+        function: u8,
+        result: &Result<Response, ExceptionCode>,
+        responded: bool,
+    ) {
+        let _ = self.unit(unit, |diag| {
+            let exception = result.as_ref().err().copied();
+            match exception {
+                _ if !responded => inc(&mut diag.counters.server_no_response),
+                Some(ExceptionCode::ServerDeviceBusy) => {
+                    inc(&mut diag.counters.bus_exception);
+                    inc(&mut diag.counters.server_busy);
+                }
+                Some(e) if u8::from(e) == 0x07 => {
+                    inc(&mut diag.counters.bus_exception);
+                    inc(&mut diag.counters.server_nak);
+                }
+                Some(_) => inc(&mut diag.counters.bus_exception),
+                None => {}
+            }
+            if exception.is_none()
+                && function != FC_GET_COMM_EVENT_COUNTER
+                && function != FC_GET_COMM_EVENT_LOG
+            {
+                inc(&mut diag.event_counter);
+            }
+            let mut event = send_event(exception);
+            if diag.listen_only {
+                event |= EVENT_SEND_LISTEN_ONLY;
+            }
+            diag.event(event);
+        });
+    }
+
+    pub fn counters(&self, unit: u8) -> Result<Counters, Error> {
+        self.unit(unit, |diag| diag.counters.clone())
+    }
+
+    /// Handle function codes 8, 11 and 12
+    pub fn request(
+        &self,
+        unit: u8,
+        function: u8,
+        data: &[u8],
+    ) -> Result<Result<Response, ExceptionCode>, Error> {
+        self.unit(unit, |diag| match function {
+            FC_DIAGNOSTICS => diagnostics(diag, data),
+            FC_GET_COMM_EVENT_COUNTER => {
+                let mut reply = Vec::with_capacity(4);
+                reply.extend_from_slice(&0u16.to_be_bytes());
+                reply.extend_from_slice(&diag.event_counter.to_be_bytes());
+                Ok(Response::Custom(function, Bytes::from(reply)))
+            }
+            FC_GET_COMM_EVENT_LOG => {
+                let mut reply = Vec::with_capacity(7 + diag.events.len());
+                reply.push((6 + diag.events.len()) as u8);
+                reply.extend_from_slice(&0u16.to_be_bytes());
+                reply.extend_from_slice(&diag.event_counter.to_be_bytes());
+                reply.extend_from_slice(&diag.counters.bus_message.to_be_bytes());
+                reply.extend(diag.events.iter());
+                Ok(Response::Custom(function, Bytes::from(reply)))
+            }
+            _ => Err(ExceptionCode::IllegalFunction),
+        })
+    }
+}
+
+fn diagnostics(diag: &mut UnitDiagnostics, data: &[u8]) -> Result<Response, ExceptionCode> {
+    let reply = |sub: u16, value: u16| {
+        let mut reply = Vec::with_capacity(4);
+        reply.extend_from_slice(&sub.to_be_bytes());
+        reply.extend_from_slice(&value.to_be_bytes());
+        Ok(Response::Custom(FC_DIAGNOSTICS, Bytes::from(reply)))
+    };
+    if data.len() < 2 {
+        return Err(ExceptionCode::IllegalDataValue);
+    }
+    let sub = u16::from_be_bytes([data[0], data[1]]);
+    if sub == RETURN_QUERY_DATA {
+        return Ok(Response::Custom(
+            FC_DIAGNOSTICS,
+            Bytes::copy_from_slice(data),
+        ));
+    }
+    if data.len() != 4 {
+        return Err(ExceptionCode::IllegalDataValue);
+    }
+    let value = u16::from_be_bytes([data[2], data[3]]);
+    let c = &diag.counters;
+    let count = match sub {
+        BUS_MESSAGE_COUNT => Some(c.bus_message),
+        BUS_COMM_ERROR_COUNT => Some(c.bus_comm_error),
+        BUS_EXCEPTION_COUNT => Some(c.bus_exception),
+        SERVER_MESSAGE_COUNT => Some(c.server_message),
+        SERVER_NO_RESPONSE_COUNT => Some(c.server_no_response),
+        SERVER_NAK_COUNT => Some(c.server_nak),
+        SERVER_BUSY_COUNT => Some(c.server_busy),
+        BUS_CHAR_OVERRUN_COUNT => Some(c.bus_char_overrun),
+        _ => None,
+    };
+    if let Some(count) = count {
+        if value != 0 {
+            return Err(ExceptionCode::IllegalDataValue);
+        }
+        return reply(sub, count);
+    }
+    match sub {
+        RESTART_COMMUNICATIONS => {
+            match value {
+                0x0000 => {}
+                0xff00 => diag.events.clear(),
+                _ => return Err(ExceptionCode::IllegalDataValue),
+            }
+            diag.clear_counters();
+            diag.event_counter = 0;
+            diag.listen_only = false;
+            diag.event(EVENT_RESTART);
+            reply(sub, value)
+        }
+        RETURN_DIAGNOSTIC_REGISTER => reply(sub, diag.diagnostic_register),
+        // The ASCII frame reader ends a frame at any character following
+        // CR, so the new delimiter needs no state
+        CHANGE_ASCII_DELIMITER => reply(sub, value),
+        FORCE_LISTEN_ONLY => {
+            diag.listen_only = true;
+            diag.event(EVENT_LISTEN_ONLY);
+            reply(sub, value)
+        }
+        CLEAR_COUNTERS => {
+            diag.clear_counters();
+            reply(sub, value)
+        }
+        CLEAR_OVERRUN => {
+            diag.counters.bus_char_overrun = 0;
+            reply(sub, value)
+        }
+        _ => Err(ExceptionCode::IllegalFunction),
+    }
+}
+
+/// True if the request restarts communication, the only request
+/// processed in listen only mode.
+pub fn is_restart(function: u8, data: &[u8]) -> bool {
+    function == FC_DIAGNOSTICS
+        && data.len() >= 2
+        && u16::from_be_bytes([data[0], data[1]]) == RESTART_COMMUNICATIONS
+}
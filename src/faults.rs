@@ -0,0 +1,180 @@
+// Faults injected by the simulated server. Faults are defined per
+// device in the configuration and can be enabled or disabled while
+// running.
+
+use crate::device_list::{FaultAction, FaultDef};
+use rand::Rng;
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio_modbus::ExceptionCode;
+
+/// Combined effect of all faults selected for a request
+#[derive(Default, Debug, PartialEq)]
+pub struct Injection {
+    pub exception: Option<ExceptionCode>,
+    pub drop_response: bool,
+    pub delay: Duration,
+    pub corrupt_crc: bool,
+}
+
+pub struct Faults {
+    defs: Vec<FaultDef>,
+    enabled: Vec<AtomicBool>,
+}
+
+fn matches(def: &FaultDef, function: u8, addresses: &[Range<u32>]) -> bool {
+    if def.function.is_some_and(|f| f != function) {
+        return false;
+    }
+    match &def.addresses {
+        Some(fault_addrs) => addresses.iter().any(|r| {
+            r.start <= u32::from(*fault_addrs.end()) && u32::from(*fault_addrs.start()) < r.end
+        }),
+        None => true,
+    }
+}
+
+/// Short text describing when and how a fault affects requests
+pub fn description(def: &FaultDef) -> String {
+    let mut desc = match def.action {
+        FaultAction::Exception(code) => format!("Exception {code}"),
+        FaultAction::DropResponse => "Drop response".to_string(),
+        FaultAction::Delay(delay) => format!("Delay {} ms", delay.as_millis()),
+        FaultAction::CorruptCrc => "Corrupt CRC".to_string(),
+    };
+    if let Some(function) = def.function {
+        desc += &format!(", function {function}");
+    }
+    if let Some(addrs) = &def.addresses {
+        desc += &format!(", addresses {}-{}", addrs.start(), addrs.end());
+    }
+    if def.probability < 100 {
+        desc += &format!(", {}% of requests", def.probability);
+    }
+    desc
+}
+
+impl Faults {
+    pub fn new(defs: &[FaultDef]) -> Faults {
+        Faults {
+            defs: defs.to_vec(),
+            enabled: defs.iter().map(|d| AtomicBool::new(d.enabled)).collect(),
+        }
+    }
+
+    /// All faults and whether they are currently enabled
+    pub fn list(&self) -> impl Iterator<Item = (&FaultDef, bool)> {
+        self.defs
+            .iter()
+            .zip(&self.enabled)
+            .map(|(def, enabled)| (def, enabled.load(Ordering::Relaxed)))
+    }
+
+    /// Returns false if there's no fault with this index
+    pub fn set_enabled(&self, index: usize, enabled: bool) -> bool {
+        match self.enabled.get(index) {
+            Some(e) => {
+                e.store(enabled, Ordering::Relaxed);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Select the faults to inject for a request with the given
+    /// function code, touching `addresses`
+    pub fn select(&self, function: u8, addresses: &[Range<u32>]) -> Injection {
+        let mut injection = Injection::default();
+        let mut rng = rand::rng();
+        for (def, enabled) in self.list() {
+            if !enabled
+                || !matches(def, function, addresses)
+                || rng.random_range(0..100) >= def.probability
+            {
+                continue;
+            }
+            match def.action {
+                FaultAction::Exception(code) => {
+                    injection.exception.get_or_insert(ExceptionCode::new(code));
+                }
+                FaultAction::DropResponse => injection.drop_response = true,
+                FaultAction::Delay(delay) => injection.delay += delay,
+                FaultAction::CorruptCrc => injection.corrupt_crc = true,
+            }
+        }
+        injection
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn fault(action: FaultAction) -> FaultDef {
+        FaultDef {
+            label: None,
+            action,
+            function: None,
+            addresses: None,
+            probability: 100,
+            enabled: true,
+        }
+    }
+
+    #[test]
+    fn select_test() {
+        let faults = Faults::new(&[
+            FaultDef {
+                function: Some(3),
+                addresses: Some(10..=19),
+                ..fault(FaultAction::Exception(4))
+            },
+            FaultDef {
+                function: Some(6),
+                ..fault(FaultAction::DropResponse)
+            },
+            fault(FaultAction::Delay(Duration::from_millis(50))),
+            FaultDef {
+                enabled: false,
+                ..fault(FaultAction::CorruptCrc)
+            },
+            FaultDef {
+                probability: 0,
+                ..fault(FaultAction::CorruptCrc)
+            },
+        ]);
+        let delay = Duration::from_millis(50);
+        // Read and write ranges of Read/Write Multiple Registers
+        assert_eq!(
+            faults.select(3, &[30..31, 5..11]),
+            Injection {
+                exception: Some(ExceptionCode::ServerDeviceFailure),
+                delay,
+                ..Injection::default()
+            }
+        );
+        assert_eq!(
+            faults.select(3, &[0..10, 30..31]),
+            Injection {
+                delay,
+                ..Injection::default()
+            }
+        );
+        assert_eq!(
+            faults.select(6, &[0..1, 30..31]),
+            Injection {
+                drop_response: true,
+                delay,
+                ..Injection::default()
+            }
+        );
+        assert!(faults.set_enabled(3, true));
+        assert!(!faults.set_enabled(5, true));
+        assert!(faults.select(1, &[]).corrupt_crc);
+        assert_eq!(
+            description(&faults.defs[0]),
+            "Exception 4, function 3, addresses 10-19"
+        );
+    }
+}
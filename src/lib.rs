@@ -1,3 +1,4 @@
+pub mod ascii;
 pub mod build_main_page;
 pub mod encoding;
 pub mod error;
@@ -6,6 +7,7 @@ pub mod modbus_connection;
 pub mod observable_array;
 pub mod presentation;
 pub mod range_array;
+pub mod records;
 pub mod register_value;
 pub mod xml_common;
 pub mod tag_list;
@@ -17,5 +19,14 @@ pub mod device_list_xml;
 pub mod tag_ranges;
 pub mod tags;
 pub mod devices;
+pub mod diagnostics;
+pub mod faults;
+pub mod pdu;
+pub mod rtu;
+pub mod line_client;
+pub mod serial_ports;
 pub mod template;
+pub mod tls;
+pub mod udp;
 pub mod web_server;
+pub mod write_schedule;
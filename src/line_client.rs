@@ -0,0 +1,83 @@
+// Client side of the serial line framings, RTU and ASCII. One request
+// at a time is sent and answered by the unit it was sent to.
+
+use crate::pdu;
+use crate::rtu::Frame;
+use async_trait::async_trait;
+use std::io;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio_modbus::client::Client;
+use tokio_modbus::slave::{Slave, SlaveContext};
+use tokio_modbus::{Request, Response};
+
+/// Frames received on a line
+#[async_trait]
+pub trait FrameSource {
+    /// Wait for the next frame. Returns None at end of stream.
+    async fn next(&mut self) -> io::Result<Option<Frame>>;
+
+    /// Drop data received so far, including data that can be read
+    /// without waiting
+    fn discard_input(&mut self);
+}
+
+/// Client sending requests to one unit at a time
+pub struct LineClient<F, W> {
+    frames: F,
+    writer: W,
+    // Builds a frame with address and checksum from a PDU
+    encode: fn(u8, &[u8]) -> Vec<u8>,
+    slave: Slave,
+}
+
+impl<F, W> LineClient<F, W> {
+    pub fn new(frames: F, writer: W, encode: fn(u8, &[u8]) -> Vec<u8>, slave: Slave) -> Self {
+        LineClient {
+            frames,
+            writer,
+            encode,
+            slave,
+        }
+    }
+}
+
+impl<F, W> SlaveContext for LineClient<F, W> {
+    fn set_slave(&mut self, slave: Slave) {
+        self.slave = slave;
+    }
+}
+
+#[async_trait]
+impl<F, W> Client for LineClient<F, W>
+where
+    F: FrameSource + Send,
+    W: AsyncWrite + Unpin + Send,
+{
+    async fn call(&mut self, request: Request<'_>) -> tokio_modbus::Result<Response> {
+        let unit = self.slave.into();
+        // A late response to an earlier request would be taken as the
+        // answer to this one
+        self.frames.discard_input();
+        self.writer
+            .write_all(&(self.encode)(unit, &pdu::encode_request(&request)))
+            .await?;
+        loop {
+            let adu = match self.frames.next().await? {
+                Some(Frame::Adu(adu)) => adu,
+                Some(_) => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid frame").into());
+                }
+                None => return Err(io::Error::from(io::ErrorKind::BrokenPipe).into()),
+            };
+            // Ignore frames from other units on the line
+            if adu[0] != unit {
+                continue;
+            }
+            return pdu::decode_response(&request, &adu[1..]);
+        }
+    }
+
+    async fn disconnect(&mut self) -> io::Result<()> {
+        self.writer.shutdown().await
+    }
+}
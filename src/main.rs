@@ -1,30 +1,70 @@
 use bytes::Bytes;
-use clap::{CommandFactory, FromArgMatches, Parser};
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand};
+use futures::future;
 use log::{debug, error, info};
+use mb_tool::device_list::{ClientTiming, ConnectionDef, DeviceDefList, ResponseDelay};
 use mb_tool::device_list_xml;
-use mb_tool::devices::Devices;
+use mb_tool::devices::{Devices, Event, Quality, Table};
 use mb_tool::error::DynResult;
-use mb_tool::modbus_connection::{self, ModbusOptions};
+use mb_tool::faults;
+use mb_tool::modbus_connection::{self, Gateway, ModbusOptions};
 use mb_tool::observable_array::ObservableArray;
+use mb_tool::records::{self, FILE_RECORDS, Records, Updated as UpdatedRecords};
+use mb_tool::rtu;
+use mb_tool::serial_ports::{
+    self, SerialSettings, parse_baud_rate, parse_data_bits, parse_flow_control, parse_parity,
+    parse_stop_bits,
+};
 use mb_tool::tags::{Tags, Updated};
 use mb_tool::template;
+use mb_tool::tls::{self, TlsConfig};
 use mb_tool::web_server;
 use mb_tool::web_server::{WebsocketConnect, WebsocketReceive, WsSender};
+use mb_tool::write_schedule;
 use roxmltree::Document;
 use rust_embed::RustEmbed;
 use serde_derive::{Deserialize, Serialize};
 use std::fs::File;
+use std::future::Future;
 use std::io::Read;
-use std::net::IpAddr;
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::net::{Ipv4Addr, SocketAddr};
 use std::path::PathBuf;
+use std::pin::Pin;
 use std::process::ExitCode;
 use std::sync::Arc;
-use tokio::sync::mpsc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::UNIX_EPOCH;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc::error::TrySendError;
 use tokio::task::JoinHandle;
 use tokio::time::Duration;
 use tokio_modbus::Slave;
-use tokio_serial::{Parity, SerialStream};
+use tokio_serial::{DataBits, FlowControl, Parity, SerialStream, StopBits};
+
+#[derive(Serialize, Deserialize)]
+struct FaultState {
+    label: Option<String>,
+    description: String,
+    enabled: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ScheduledWriteState {
+    label: Option<String>,
+    description: String,
+    enabled: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RangeHealthState {
+    table: Table,
+    start: u16,
+    length: u16,
+    quality: Quality,
+    // Milliseconds since the Unix epoch
+    last_success: Option<u64>,
+    last_error: Option<String>,
+}
 
 #[derive(Serialize, Deserialize)]
 enum MbCommands {
@@ -68,23 +108,131 @@ enum MbCommands {
         start: u16,
         length: u16,
     },
+    UpdateFifo {
+        unit_addr: u8,
+        addr: u16,
+        values: Vec<u16>,
+    },
+    RequestFifo {
+        unit_addr: u8,
+        addr: u16,
+    },
+    UpdateFileRecords {
+        unit_addr: u8,
+        file: u16,
+        start: u16,
+        regs: Vec<u16>,
+    },
+    RequestFileRecords {
+        unit_addr: u8,
+        file: u16,
+        start: u16,
+        length: u16,
+    },
+    RequestDeviceIdentification {
+        unit_addr: u8,
+    },
+    DeviceIdentification {
+        unit_addr: u8,
+        objects: Vec<(u8, String)>,
+    },
+    RequestFaults {
+        unit_addr: u8,
+    },
+    Faults {
+        unit_addr: u8,
+        faults: Vec<FaultState>,
+    },
+    SetFault {
+        unit_addr: u8,
+        index: usize,
+        enabled: bool,
+    },
+    RequestWriteSchedule {
+        unit_addr: u8,
+    },
+    WriteSchedule {
+        unit_addr: u8,
+        writes: Vec<ScheduledWriteState>,
+    },
+    SetScheduledWrite {
+        unit_addr: u8,
+        index: usize,
+        enabled: bool,
+    },
+    // Issue a scheduled write now, whether enabled or not
+    RunScheduledWrite {
+        unit_addr: u8,
+        index: usize,
+    },
+    RequestResponseDelay {
+        unit_addr: u8,
+    },
+    // Delay range in milliseconds
+    ResponseDelay {
+        unit_addr: u8,
+        min: u64,
+        max: u64,
+    },
+    RequestUnitStatus {
+        unit_addr: u8,
+    },
+    // Communication with a remote unit in client mode
+    UnitStatus {
+        unit_addr: u8,
+        responding: Option<bool>,
+        offline: bool,
+        requests: u64,
+        failures: u64,
+        consecutive_failures: u32,
+        last_error: Option<String>,
+    },
+    RequestRangeHealth {
+        unit_addr: u8,
+    },
+    // Ranges read from a remote unit in client mode
+    RangeHealth {
+        unit_addr: u8,
+        ranges: Vec<RangeHealthState>,
+    },
+    // Outcome of writing values changed by this connection in client mode
+    WriteResult {
+        unit_addr: u8,
+        table: Table,
+        start: u16,
+        length: u16,
+        error: Option<String>,
+    },
+    // Forwarded in gateway or tap mode
+    Traffic {
+        unit_addr: u8,
+        request: String,
+        response: String,
+    },
     ListUnitAddresses(Vec<u8>),
     Echo(i64),
 }
 
 struct WsHandler {
     devices: Devices,
+    // Identifies connections, so write results go to the one that made
+    // the change
+    next_id: AtomicU64,
 }
 
 impl WsHandler {
     fn new(devices: Devices) -> WsHandler {
-        WsHandler { devices }
+        WsHandler {
+            devices,
+            next_id: AtomicU64::new(0),
+        }
     }
 }
 
 impl WebsocketConnect for WsHandler {
     fn connected(&self, send: WsSender) -> Box<dyn WebsocketReceive + Send + Sync> {
-        debug!("Socket connected");
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        debug!("Socket {id} connected");
         let devices = self.devices.clone();
         let update_send = send.clone();
         tokio::spawn(async move {
@@ -99,9 +247,67 @@ impl WebsocketConnect for WsHandler {
             }
         });
 
+        let devices = self.devices.clone();
+        let update_send = send.clone();
+        tokio::spawn(async move {
+            loop {
+                let (unit_addr, updated) = devices.records_updated().await;
+                if update_send.is_closed() {
+                    break;
+                }
+                devices
+                    .records_read(unit_addr, |records| {
+                        handle_record_updates(unit_addr, records, &updated, &update_send)
+                    })
+                    .unwrap();
+            }
+        });
+
+        let devices = self.devices.clone();
+        let mut events = self.devices.subscribe();
+        let event_send = send.clone();
+        tokio::spawn(async move {
+            loop {
+                let event = match events.recv().await {
+                    Ok(event) => event,
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => break,
+                };
+                if event_send.is_closed() {
+                    break;
+                }
+                handle_event(&devices, &event, id, &event_send);
+            }
+        });
+
+        let mut traffic = self.devices.subscribe_traffic();
+        let traffic_send = send.clone();
+        tokio::spawn(async move {
+            loop {
+                let traffic = match traffic.recv().await {
+                    Ok(traffic) => traffic,
+                    Err(RecvError::Lagged(n)) => {
+                        debug!("Missed {n} forwarded requests in the traffic log");
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+                if traffic_send.is_closed() {
+                    break;
+                }
+                let cmd = MbCommands::Traffic {
+                    unit_addr: traffic.unit,
+                    request: traffic.request.clone(),
+                    response: traffic.response.clone(),
+                };
+                send_command(&traffic_send, &cmd);
+            }
+        });
+
         Box::new(WsReceive {
             devices: self.devices.clone(),
             send,
+            id,
         })
     }
 }
@@ -109,11 +315,12 @@ impl WebsocketConnect for WsHandler {
 struct WsReceive {
     devices: Devices,
     send: WsSender,
+    id: u64,
 }
 impl WebsocketReceive for WsReceive {
     fn message(&mut self, msg: &str) -> Option<String> {
         debug!("Received from WS: {msg}");
-        handle_receive(&self.devices, &self.send, msg);
+        handle_receive(&self.devices, &self.send, self.id, msg);
         None
     }
     fn disconnected(&mut self) {
@@ -121,9 +328,17 @@ impl WebsocketReceive for WsReceive {
     }
 }
 
+// Queue a message for the WebSocket client. Dropped if the client doesn't
+// keep up.
+fn send_command(mb_send: &WsSender, cmd: &MbCommands) {
+    if let Err(TrySendError::Full(_)) = mb_send.try_send(serde_json::to_string(cmd).unwrap()) {
+        error!("WebSocket client not keeping up, message dropped");
+    }
+}
+
 fn ws_request<T, F>(
     array: &ObservableArray<T>,
-    mb_send: &mpsc::Sender<String>,
+    mb_send: &WsSender,
     start: u16,
     length: u16,
     f: F,
@@ -138,10 +353,28 @@ fn ws_request<T, F>(
         )
     });
 
-    let _ = mb_send.send(serde_json::to_string(&reply).unwrap());
+    send_command(mb_send, &reply);
+}
+
+// Change values from a connection and have a client write them
+fn write_values(
+    devices: &Devices,
+    origin: u64,
+    unit: u8,
+    table: Table,
+    start: u16,
+    values: &[u16],
+) {
+    if let Err(e) = devices.write_values(Some(origin), unit, table, start, values) {
+        error!("Failed to change values of unit {unit}: {e}");
+    }
+}
+
+fn bits_to_values(bits: &[bool]) -> Vec<u16> {
+    bits.iter().map(|&b| u16::from(b)).collect()
 }
 
-fn handle_receive(devices: &Devices, mb_send: &mpsc::Sender<String>, json: &str) {
+fn handle_receive(devices: &Devices, mb_send: &WsSender, origin: u64, json: &str) {
     debug!("JSON: {}", json);
     match serde_json::from_str::<MbCommands>(json) {
         Ok(cmd) => {
@@ -153,6 +386,7 @@ fn handle_receive(devices: &Devices, mb_send: &mpsc::Sender<String>, json: &str)
                     length,
                 } => {
                     debug!("RequestHoldingRegs");
+                    devices.request_read(unit_addr);
                     devices
                         .tags_read(unit_addr, |tags| {
                             ws_request(
@@ -175,11 +409,14 @@ fn handle_receive(devices: &Devices, mb_send: &mpsc::Sender<String>, json: &str)
                     regs: reg_data,
                 } => {
                     debug!("UpdateHoldingRegs");
-                    devices
-                        .tags_write(unit_addr, |tags| {
-                            tags.holding_registers.update(start as usize, &reg_data);
-                        })
-                        .unwrap();
+                    write_values(
+                        devices,
+                        origin,
+                        unit_addr,
+                        Table::HoldingRegisters,
+                        start,
+                        &reg_data,
+                    );
                 }
 
                 // Input registers
@@ -189,6 +426,7 @@ fn handle_receive(devices: &Devices, mb_send: &mpsc::Sender<String>, json: &str)
                     length,
                 } => {
                     debug!("RequestInputRegs");
+                    devices.request_read(unit_addr);
                     devices
                         .tags_read(unit_addr, |tags| {
                             ws_request(
@@ -210,11 +448,14 @@ fn handle_receive(devices: &Devices, mb_send: &mpsc::Sender<String>, json: &str)
                     start,
                     regs: reg_data,
                 } => {
-                    devices
-                        .tags_write(unit_addr, |tags| {
-                            tags.input_registers.update(start as usize, &reg_data);
-                        })
-                        .unwrap();
+                    write_values(
+                        devices,
+                        origin,
+                        unit_addr,
+                        Table::InputRegisters,
+                        start,
+                        &reg_data,
+                    );
                 }
 
                 // Coils
@@ -224,6 +465,7 @@ fn handle_receive(devices: &Devices, mb_send: &mpsc::Sender<String>, json: &str)
                     length,
                 } => {
                     debug!("RequestCoils");
+                    devices.request_read(unit_addr);
                     devices
                         .tags_read(unit_addr, |tags| {
                             ws_request(&tags.coils, &mb_send, start, length, |start, regs| {
@@ -241,11 +483,8 @@ fn handle_receive(devices: &Devices, mb_send: &mpsc::Sender<String>, json: &str)
                     start,
                     regs: reg_data,
                 } => {
-                    devices
-                        .tags_write(unit_addr, |tags| {
-                            tags.coils.update(start as usize, &reg_data);
-                        })
-                        .unwrap();
+                    let values = bits_to_values(&reg_data);
+                    write_values(devices, origin, unit_addr, Table::Coils, start, &values);
                 }
 
                 // Discrete inputs
@@ -255,6 +494,7 @@ fn handle_receive(devices: &Devices, mb_send: &mpsc::Sender<String>, json: &str)
                     length,
                 } => {
                     debug!("RequestDiscreteInputs");
+                    devices.request_read(unit_addr);
                     devices
                         .tags_read(unit_addr, |tags| {
                             ws_request(
@@ -276,21 +516,133 @@ fn handle_receive(devices: &Devices, mb_send: &mpsc::Sender<String>, json: &str)
                     start,
                     regs: reg_data,
                 } => {
-                    devices
-                        .tags_write(unit_addr, |tags| {
-                            tags.discrete_inputs.update(start as usize, &reg_data);
-                        })
-                        .unwrap();
+                    let values = bits_to_values(&reg_data);
+                    write_values(
+                        devices,
+                        origin,
+                        unit_addr,
+                        Table::DiscreteInputs,
+                        start,
+                        &values,
+                    );
+                }
+
+                // FIFO queues
+                MbCommands::RequestFifo { unit_addr, addr } => {
+                    let res = devices.records_read(unit_addr, |records| {
+                        send_fifo(unit_addr, addr, records, mb_send)
+                    });
+                    if let Err(e) = res {
+                        error!("Failed to read FIFO queue: {e}");
+                    }
+                }
+                MbCommands::UpdateFifo {
+                    unit_addr,
+                    addr,
+                    values,
+                } => {
+                    let res = devices
+                        .records_read(unit_addr, |records| update_fifo(records, addr, &values));
+                    if let Err(e) = res {
+                        error!("Failed to update FIFO queue: {e}");
+                    }
+                }
+
+                // File records
+                MbCommands::RequestFileRecords {
+                    unit_addr,
+                    file,
+                    start,
+                    length,
+                } => {
+                    let res = devices.records_read(unit_addr, |records| {
+                        send_file_records(unit_addr, file, start, length, records, mb_send)
+                    });
+                    if let Err(e) = res {
+                        error!("Failed to read file records: {e}");
+                    }
+                }
+                MbCommands::UpdateFileRecords {
+                    unit_addr,
+                    file,
+                    start,
+                    regs,
+                } => {
+                    let res = devices.records_read(unit_addr, |records| {
+                        update_file_records(records, file, start, &regs)
+                    });
+                    if let Err(e) = res {
+                        error!("Failed to update file records: {e}");
+                    }
                 }
 
                 MbCommands::Echo(count) => {
                     let reply = MbCommands::Echo(count);
-                    let _ = mb_send.send(serde_json::to_string(&reply).unwrap());
+                    send_command(mb_send, &reply);
+                }
+                MbCommands::RequestDeviceIdentification { unit_addr } => {
+                    send_remote_identification(devices, unit_addr, mb_send);
+                }
+                MbCommands::DeviceIdentification { .. } => {}
+                MbCommands::RequestFaults { unit_addr } => {
+                    send_faults(devices, unit_addr, mb_send);
                 }
+                MbCommands::Faults { .. } => {}
+                MbCommands::SetFault {
+                    unit_addr,
+                    index,
+                    enabled,
+                } => match devices.set_fault_enabled(unit_addr, index, enabled) {
+                    Ok(true) => {}
+                    Ok(false) => error!("No fault {index} for unit {unit_addr}"),
+                    Err(e) => error!("Failed to set fault: {e}"),
+                },
+                MbCommands::RequestWriteSchedule { unit_addr } => {
+                    send_write_schedule(devices, unit_addr, mb_send);
+                }
+                MbCommands::WriteSchedule { .. } => {}
+                MbCommands::SetScheduledWrite {
+                    unit_addr,
+                    index,
+                    enabled,
+                } => match devices.set_scheduled_write_enabled(unit_addr, index, enabled) {
+                    Ok(true) => {}
+                    Ok(false) => error!("No scheduled write {index} for unit {unit_addr}"),
+                    Err(e) => error!("Failed to set scheduled write: {e}"),
+                },
+                MbCommands::RunScheduledWrite { unit_addr, index } => {
+                    run_scheduled_write(devices, unit_addr, index);
+                }
+                MbCommands::RequestResponseDelay { unit_addr } => {
+                    send_response_delay(devices, unit_addr, mb_send);
+                }
+                MbCommands::ResponseDelay {
+                    unit_addr,
+                    min,
+                    max,
+                } => {
+                    let delay = ResponseDelay {
+                        min: Duration::from_millis(min.min(max)),
+                        max: Duration::from_millis(max.max(min)),
+                    };
+                    if let Err(e) = devices.set_response_delay(unit_addr, delay) {
+                        error!("Failed to set response delay: {e}");
+                    }
+                }
+                MbCommands::RequestUnitStatus { unit_addr } => {
+                    send_unit_status(devices, unit_addr, mb_send);
+                }
+                MbCommands::UnitStatus { .. } => {}
+                MbCommands::RequestRangeHealth { unit_addr } => {
+                    send_range_health(devices, unit_addr, mb_send);
+                }
+                MbCommands::RangeHealth { .. } => {}
+                MbCommands::WriteResult { .. } => {}
+                MbCommands::Traffic { .. } => {}
                 MbCommands::ListUnitAddresses(_) => {
                     let units = devices.units().collect();
                     let reply = MbCommands::ListUnitAddresses(units);
-                    let _ = mb_send.send(serde_json::to_string(&reply).unwrap());
+                    send_command(mb_send, &reply);
                 }
             }
         }
@@ -300,7 +652,254 @@ fn handle_receive(devices: &Devices, mb_send: &mpsc::Sender<String>, json: &str)
     }
 }
 
-fn handle_updates(unit_addr: u8, tags: &Tags, updated: &Updated, mb_send: &mpsc::Sender<String>) {
+fn send_remote_identification(devices: &Devices, unit_addr: u8, mb_send: &WsSender) {
+    match devices.remote_identification(unit_addr) {
+        Ok(Some(ident)) => {
+            let reply = MbCommands::DeviceIdentification {
+                unit_addr,
+                objects: ident.objects.into_iter().collect(),
+            };
+            send_command(mb_send, &reply);
+        }
+        Ok(None) => {}
+        Err(e) => error!("Failed to get device identification: {e}"),
+    }
+}
+
+fn send_faults(devices: &Devices, unit_addr: u8, mb_send: &WsSender) {
+    let faults = match devices.faults(unit_addr) {
+        Ok(faults) => faults,
+        Err(e) => {
+            error!("Failed to get faults: {e}");
+            return;
+        }
+    };
+    let reply = MbCommands::Faults {
+        unit_addr,
+        faults: faults
+            .list()
+            .map(|(def, enabled)| FaultState {
+                label: def.label.clone(),
+                description: faults::description(def),
+                enabled,
+            })
+            .collect(),
+    };
+    send_command(mb_send, &reply);
+}
+
+fn send_write_schedule(devices: &Devices, unit_addr: u8, mb_send: &WsSender) {
+    let schedule = match devices.write_schedule(unit_addr) {
+        Ok(schedule) => schedule,
+        Err(e) => {
+            error!("Failed to get write schedule: {e}");
+            return;
+        }
+    };
+    let reply = MbCommands::WriteSchedule {
+        unit_addr,
+        writes: schedule
+            .list()
+            .map(|(def, enabled)| ScheduledWriteState {
+                label: def.label.clone(),
+                description: write_schedule::description(def),
+                enabled,
+            })
+            .collect(),
+    };
+    send_command(mb_send, &reply);
+}
+
+fn run_scheduled_write(devices: &Devices, unit_addr: u8, index: usize) {
+    let schedule = match devices.write_schedule(unit_addr) {
+        Ok(schedule) => schedule,
+        Err(e) => {
+            error!("Failed to get write schedule: {e}");
+            return;
+        }
+    };
+    let Some((def, _)) = schedule.get(index) else {
+        error!("No scheduled write {index} for unit {unit_addr}");
+        return;
+    };
+    if let Err(e) = write_schedule::execute(devices, unit_addr, def) {
+        error!("Unit {unit_addr}: Scheduled write failed: {e}");
+    }
+}
+
+fn send_response_delay(devices: &Devices, unit_addr: u8, mb_send: &WsSender) {
+    match devices.response_delay(unit_addr) {
+        Ok(delay) => {
+            let reply = MbCommands::ResponseDelay {
+                unit_addr,
+                min: delay.min.as_millis() as u64,
+                max: delay.max.as_millis() as u64,
+            };
+            send_command(mb_send, &reply);
+        }
+        Err(e) => error!("Failed to get response delay: {e}"),
+    }
+}
+
+fn send_unit_status(devices: &Devices, unit_addr: u8, mb_send: &WsSender) {
+    match devices.unit_status(unit_addr) {
+        Ok(status) => {
+            let reply = MbCommands::UnitStatus {
+                unit_addr,
+                responding: status.responding,
+                offline: status.offline,
+                requests: status.requests,
+                failures: status.failures,
+                consecutive_failures: status.consecutive_failures,
+                last_error: status.last_error,
+            };
+            send_command(mb_send, &reply);
+        }
+        Err(e) => error!("Failed to get unit status: {e}"),
+    }
+}
+
+fn send_range_health(devices: &Devices, unit_addr: u8, mb_send: &WsSender) {
+    match devices.range_health(unit_addr) {
+        Ok(health) => {
+            let ranges = health
+                .into_iter()
+                .map(|range| RangeHealthState {
+                    table: range.table,
+                    start: range.start,
+                    length: range.length,
+                    quality: range.quality,
+                    last_success: range.last_success.and_then(|t| {
+                        t.duration_since(UNIX_EPOCH)
+                            .ok()
+                            .map(|d| d.as_millis() as u64)
+                    }),
+                    last_error: range.last_error.map(|e| e.to_string()),
+                })
+                .collect();
+            let reply = MbCommands::RangeHealth { unit_addr, ranges };
+            send_command(mb_send, &reply);
+        }
+        Err(e) => error!("Failed to get range health: {e}"),
+    }
+}
+
+fn handle_event(devices: &Devices, event: &Event, origin: u64, mb_send: &WsSender) {
+    match event {
+        Event::RemoteIdentification(unit_addr) => {
+            send_remote_identification(devices, *unit_addr, mb_send);
+        }
+        Event::FaultsChanged(unit_addr) => {
+            send_faults(devices, *unit_addr, mb_send);
+        }
+        Event::WriteScheduleChanged(unit_addr) => {
+            send_write_schedule(devices, *unit_addr, mb_send);
+        }
+        Event::ResponseDelayChanged(unit_addr) => {
+            send_response_delay(devices, *unit_addr, mb_send);
+        }
+        Event::UnitStatusChanged(unit_addr) => {
+            send_unit_status(devices, *unit_addr, mb_send);
+        }
+        Event::RangeHealthChanged(unit_addr) => {
+            send_range_health(devices, *unit_addr, mb_send);
+        }
+        Event::ReadRequested(_) => {}
+        Event::WriteDone(result) => {
+            let request = &result.request;
+            if request.origin == Some(origin) {
+                let cmd = MbCommands::WriteResult {
+                    unit_addr: request.unit,
+                    table: request.table,
+                    start: request.start,
+                    length: request.values.len() as u16,
+                    error: result.error.clone(),
+                };
+                send_command(mb_send, &cmd);
+            }
+        }
+    }
+}
+
+fn send_fifo(unit_addr: u8, addr: u16, records: &Records, mb_send: &WsSender) {
+    let Some(fifo) = records.fifos.get(&addr) else {
+        error!("No FIFO queue at address {addr}");
+        return;
+    };
+    let cmd = MbCommands::UpdateFifo {
+        unit_addr,
+        addr,
+        values: records::fifo_values(fifo),
+    };
+    send_command(mb_send, &cmd);
+}
+
+fn update_fifo(records: &Records, addr: u16, values: &[u16]) {
+    match records.fifos.get(&addr) {
+        Some(fifo) => records::set_fifo_values(fifo, values),
+        None => error!("No FIFO queue at address {addr}"),
+    }
+}
+
+fn send_file_records(
+    unit_addr: u8,
+    file: u16,
+    start: u16,
+    length: u16,
+    records: &Records,
+    mb_send: &WsSender,
+) {
+    let Some(array) = records.files.get(&file) else {
+        error!("No file number {file}");
+        return;
+    };
+    let length = length.min(FILE_RECORDS.saturating_sub(start as usize) as u16);
+    ws_request(array, mb_send, start, length, |start, regs| {
+        MbCommands::UpdateFileRecords {
+            unit_addr,
+            file,
+            start,
+            regs,
+        }
+    });
+}
+
+fn update_file_records(records: &Records, file: u16, start: u16, regs: &[u16]) {
+    match records.files.get(&file) {
+        Some(array) if start as usize + regs.len() <= FILE_RECORDS => {
+            array.update(start as usize, regs)
+        }
+        _ => error!("Invalid update of records {start} in file {file}"),
+    }
+}
+
+fn handle_record_updates(
+    unit_addr: u8,
+    records: &Records,
+    updated: &UpdatedRecords,
+    mb_send: &WsSender,
+) {
+    match updated {
+        UpdatedRecords::Fifo(addr) => send_fifo(unit_addr, *addr, records, mb_send),
+        UpdatedRecords::FileRecords(file, ranges) => {
+            let Some(array) = records.files.get(file) else {
+                return;
+            };
+            for range in ranges {
+                let cmd = array.get_array(|r| MbCommands::UpdateFileRecords {
+                    unit_addr,
+                    file: *file,
+                    start: range.start as u16,
+                    regs: Vec::from(&r[range.start..range.end]),
+                });
+
+                send_command(mb_send, &cmd);
+            }
+        }
+    }
+}
+
+fn handle_updates(unit_addr: u8, tags: &Tags, updated: &Updated, mb_send: &WsSender) {
     use Updated::*;
     match updated {
         HoldingRegisters(ranges) => {
@@ -313,7 +912,7 @@ fn handle_updates(unit_addr: u8, tags: &Tags, updated: &Updated, mb_send: &mpsc:
                         regs: Vec::from(&r[range.start..range.end]),
                     });
 
-                let _ = mb_send.send(serde_json::to_string(&cmd).unwrap());
+                send_command(mb_send, &cmd);
             }
         }
         InputRegisters(ranges) => {
@@ -326,7 +925,7 @@ fn handle_updates(unit_addr: u8, tags: &Tags, updated: &Updated, mb_send: &mpsc:
                         regs: Vec::from(&r[range.start..range.end]),
                     });
 
-                let _ = mb_send.send(serde_json::to_string(&cmd).unwrap());
+                send_command(mb_send, &cmd);
             }
         }
         Coils(ranges) => {
@@ -337,7 +936,7 @@ fn handle_updates(unit_addr: u8, tags: &Tags, updated: &Updated, mb_send: &mpsc:
                     regs: Vec::from(&r[range.start..range.end]),
                 });
 
-                let _ = mb_send.send(serde_json::to_string(&cmd).unwrap());
+                send_command(mb_send, &cmd);
             }
         }
         DiscreteInputs(ranges) => {
@@ -350,46 +949,293 @@ fn handle_updates(unit_addr: u8, tags: &Tags, updated: &Updated, mb_send: &mpsc:
                         regs: Vec::from(&r[range.start..range.end]),
                     });
 
-                let _ = mb_send.send(serde_json::to_string(&cmd).unwrap());
+                send_command(mb_send, &cmd);
             }
         }
     }
 }
 
+fn list_ports(json: bool) -> ExitCode {
+    let ports = match serial_ports::available() {
+        Ok(ports) => ports,
+        Err(e) => {
+            error!("Failed to list serial ports: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    if json {
+        println!("{}", serde_json::to_string_pretty(&ports).unwrap());
+    } else {
+        print!("{}", serial_ports::to_text(&ports));
+    }
+    ExitCode::SUCCESS
+}
+
+fn serial_settings(args: &CmdArgs) -> SerialSettings {
+    SerialSettings {
+        baud_rate: args.baud_rate,
+        parity: args.parity,
+        data_bits: args.data_bits,
+        stop_bits: args.stop_bits,
+        flow_control: args.flow_control,
+    }
+}
+
+/// Resolve host names and IP-addresses. IPv6 addresses may be enclosed
+/// in brackets. Returns 127.0.0.1 if no host is given.
+async fn resolve(hosts: &[String], port: u16) -> std::io::Result<Vec<SocketAddr>> {
+    if hosts.is_empty() {
+        return Ok(vec![SocketAddr::from((Ipv4Addr::new(127, 0, 0, 1), port))]);
+    }
+    let mut addrs = Vec::new();
+    for host in hosts {
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        for addr in tokio::net::lookup_host((host, port)).await? {
+            if !addrs.contains(&addr) {
+                addrs.push(addr);
+            }
+        }
+    }
+    Ok(addrs)
+}
+
+fn addr_list(addrs: &[SocketAddr]) -> String {
+    let addrs: Vec<String> = addrs.iter().map(|a| a.to_string()).collect();
+    addrs.join(", ")
+}
+
+/// Run a server on every address. Fails when any of them fails.
+fn serve_all<F, Fut>(addrs: &[SocketAddr], server: F) -> JoinHandle<DynResult<()>>
+where
+    F: Fn(SocketAddr) -> Fut,
+    Fut: Future<Output = DynResult<()>> + Send + 'static,
+{
+    let servers: Vec<Fut> = addrs.iter().map(|addr| server(*addr)).collect();
+    tokio::spawn(async move { future::try_join_all(servers).await.map(|_| ()) })
+}
+
+type ClientFuture = Pin<Box<dyn Future<Output = DynResult<()>> + Send>>;
+
+/// Units grouped by the connection used to poll them. Units without a
+/// connection of their own use the one given on the command line.
+type ConnectionGroup = (Option<ConnectionDef>, ClientTiming, Vec<u8>);
+
+// The timing of a shared connection is taken from the first device
+fn connection_groups(device_list: &DeviceDefList) -> Vec<ConnectionGroup> {
+    let mut groups: Vec<ConnectionGroup> = Vec::new();
+    for dev in device_list {
+        match groups.iter_mut().find(|(conn, ..)| *conn == dev.connection) {
+            Some((.., units)) => units.push(dev.addr),
+            None => groups.push((dev.connection.clone(), dev.client_timing, vec![dev.addr])),
+        }
+    }
+    groups
+}
+
+async fn resolve_first(host: &str, port: u16) -> DynResult<SocketAddr> {
+    match resolve(&[host.to_string()], port).await?.first() {
+        Some(addr) => Ok(*addr),
+        None => Err(format!("No address found for {host}").into()),
+    }
+}
+
+/// Poll devices using a connection from the configuration. The unit is
+/// set for every request. The connection is set up again if it fails.
+async fn connection_client(
+    conn: ConnectionDef,
+    devices: Devices,
+    options: ModbusOptions,
+) -> DynResult<()> {
+    let name = conn.to_string();
+    modbus_connection::client_restarting(&name, devices, options, |devices, options| {
+        connect_client(conn.clone(), devices, options)
+    })
+    .await
+}
+
+async fn connect_client(
+    conn: ConnectionDef,
+    devices: Devices,
+    options: ModbusOptions,
+) -> DynResult<()> {
+    use ConnectionDef::*;
+    match conn {
+        Tcp { host, port } => {
+            let socket = resolve_first(&host, port).await?;
+            modbus_connection::client_tcp(socket, devices, options).await
+        }
+        RtuOverTcp { host, port } => {
+            let socket = resolve_first(&host, port).await?;
+            modbus_connection::client_rtu_over_tcp(socket, Slave(0), devices, options).await
+        }
+        Udp { host, port } => {
+            let socket = resolve_first(&host, port).await?;
+            modbus_connection::client_udp(socket, Slave(0), devices, options).await
+        }
+        Rtu { device, settings } => {
+            let ser = SerialStream::open(&settings.builder(&device))
+                .map_err(|e| format!("Failed to open serial port {device}: {e}"))?;
+            modbus_connection::client_rtu(ser, Slave(0), devices, options).await
+        }
+        Ascii { device, settings } => {
+            let ser = SerialStream::open(&settings.builder(&device))
+                .map_err(|e| format!("Failed to open serial port {device}: {e}"))?;
+            modbus_connection::client_ascii(ser, Slave(0), devices, options).await
+        }
+    }
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// List serial ports and exit
+    ListPorts {
+        /// Print as JSON
+        #[arg(long, default_value_t = false)]
+        json: bool,
+    },
+}
+
 #[derive(Parser, Debug)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct CmdArgs {
+    #[command(subcommand)]
+    command: Option<Command>,
     /// Tag list configuration
-    tag_list_conf: PathBuf,
+    #[arg(required = true)]
+    tag_list_conf: Option<PathBuf>,
     /// Run as server
     #[arg(long, default_value_t = false)]
     server: bool,
-    /// IP-address of server
+    /// Run as gateway forwarding Modbus TCP requests to the units on the
+    /// serial device
+    #[arg(
+        long,
+        default_value_t = false,
+        requires = "serial_device",
+        conflicts_with_all = ["server", "rtu_over_tcp", "udp", "tls"]
+    )]
+    gateway: bool,
+    /// Run as tap between Modbus TCP clients and the server at this
+    /// IP-address or host name
+    #[arg(
+        long,
+        conflicts_with_all = ["server", "gateway", "serial_device", "rtu_over_tcp", "udp", "tls"]
+    )]
+    tap: Option<String>,
+    /// Port of the server when running as tap
+    #[arg(long, default_value_t = 502, requires = "tap")]
+    tap_port: u16,
+    /// IP-address or host name of server. May be repeated to make a
+    /// server listen on several addresses.
     #[arg(long)]
-    ip_address: Option<Ipv4Addr>,
+    ip_address: Vec<String>,
     /// Modbus address of server
     #[arg(long, default_value_t = 1)]
     mb_address: u8,
-    /// Modbus TCP port
-    #[arg(long, default_value_t = 502)]
-    ip_port: u16,
+    /// Modbus TCP port [default: 502, 802 with TLS]
+    #[arg(long)]
+    ip_port: Option<u16>,
     /// Serial device
     #[arg(long)]
     serial_device: Option<String>,
     /// Baud rate of serial port
+    #[arg(long, default_value = "9600", value_parser = parse_baud_rate)]
+    baud_rate: u32,
+    /// Parity of serial port: none, even or odd
+    #[arg(long, default_value = "even", value_parser = parse_parity)]
+    parity: Parity,
+    /// Data bits of serial port, 5 to 8
+    #[arg(long, default_value = "8", value_parser = parse_data_bits)]
+    data_bits: DataBits,
+    /// Stop bits of serial port, 1 or 2
+    #[arg(long, default_value = "1", value_parser = parse_stop_bits)]
+    stop_bits: StopBits,
+    /// Flow control of serial port: none, software or hardware
+    #[arg(long, default_value = "none", value_parser = parse_flow_control)]
+    flow_control: FlowControl,
+    /// Silent interval in microseconds ending an RTU frame [default: 3.5
+    /// characters]
+    #[arg(long, requires = "serial_device", conflicts_with = "ascii")]
+    frame_gap: Option<u64>,
+    /// Longest silent interval in microseconds between characters of an
+    /// RTU frame (1.5 characters in the standard). Frames with longer
+    /// intervals are discarded. Not checked if not given.
+    #[arg(long, requires = "serial_device", conflicts_with = "ascii")]
+    char_timeout: Option<u64>,
+    /// Use Modbus ASCII instead of RTU on the serial port
+    #[arg(long, default_value_t = false)]
+    ascii: bool,
+    /// Use RTU framing over TCP instead of Modbus TCP
+    #[arg(long, default_value_t = false)]
+    rtu_over_tcp: bool,
+    /// Use Modbus/UDP instead of Modbus TCP
+    #[arg(long, default_value_t = false, conflicts_with = "rtu_over_tcp")]
+    udp: bool,
+    /// Use Modbus/TCP Security (TLS with client certificates)
+    #[arg(
+        long,
+        default_value_t = false,
+        requires_all = ["tls_cert", "tls_key", "tls_ca"],
+        conflicts_with_all = ["rtu_over_tcp", "udp"]
+    )]
+    tls: bool,
+    /// Certificate chain in PEM format
+    #[arg(long)]
+    tls_cert: Option<PathBuf>,
+    /// Private key in PEM format
+    #[arg(long)]
+    tls_key: Option<PathBuf>,
+    /// CA certificates used to verify the peer
+    #[arg(long)]
+    tls_ca: Option<PathBuf>,
+    /// Name expected in the server certificate instead of the IP-address
+    #[arg(long)]
+    tls_server_name: Option<String>,
+    /// Role a client certificate must have to write. May be repeated.
+    /// Everyone may write if not given.
     #[arg(long)]
-    baud_rate: Option<u32>,
-    // Parity of serial port
-    #[arg(long, default_value = "Even")]
-    parity: String,
-    /// Bind HTTP-server to this address
+    tls_write_role: Vec<String>,
+    /// Bind HTTP-server to this address. May be repeated.
     #[arg(long)]
-    http_address: Option<Ipv4Addr>,
+    http_address: Vec<String>,
     /// HTTP port
     #[arg(long, default_value_t = 0)]
     http_port: u16,
-    /// Time in milliseconds between client polls
+    /// Time in milliseconds between client reads of the same tags, unless
+    /// set in the configuration
     #[arg(long, default_value_t = 100)]
     poll_interval: u64,
+    /// Answer requests for addresses without a tag with an exception
+    #[arg(long, default_value_t = false)]
+    strict: bool,
+    /// Time in milliseconds to wait for a response in client, gateway and
+    /// tap mode, unless set in the configuration
+    #[arg(long, default_value_t = 500, value_parser = clap::value_parser!(u64).range(1..))]
+    timeout: u64,
+    /// Number of times a client repeats a request without a response,
+    /// unless set in the configuration
+    #[arg(long, default_value_t = 0)]
+    retries: u32,
+    /// Time in milliseconds before a client reconnects, doubled after each
+    /// failed attempt, unless set in the configuration
+    #[arg(long, default_value_t = 2000, value_parser = clap::value_parser!(u64).range(1..))]
+    reconnect_delay: u64,
+    /// Longest time in milliseconds between attempts to reconnect, unless
+    /// set in the configuration
+    #[arg(long, default_value_t = 60000, value_parser = clap::value_parser!(u64).range(1..))]
+    max_reconnect_delay: u64,
+    /// Number of consecutive requests without a response before a unit is
+    /// shown as offline, 0 to never
+    #[arg(long, default_value_t = 3)]
+    offline_after: u32,
+    /// Number of unused addresses a client read may span to read nearby
+    /// tags with one request, unless set in the configuration
+    #[arg(long, default_value_t = 0)]
+    max_read_gap: u16,
+    /// Read back values written by a client to check that the device
+    /// accepted them
+    #[arg(long, default_value_t = false)]
+    verify_writes: bool,
 }
 
 #[cfg(feature = "webbrowser")]
@@ -482,10 +1328,18 @@ pub async fn main() -> ExitCode {
         }
     };
 
-    let mut f = match File::open(&args.tag_list_conf) {
+    if let Some(Command::ListPorts { json }) = args.command {
+        return list_ports(json);
+    }
+    // Required unless there's a subcommand
+    let Some(tag_list_conf) = &args.tag_list_conf else {
+        return ExitCode::FAILURE;
+    };
+
+    let mut f = match File::open(tag_list_conf) {
         Ok(f) => f,
         Err(e) => {
-            error!("Failed to open '{}': {}", args.tag_list_conf.display(), e);
+            error!("Failed to open '{}': {}", tag_list_conf.display(), e);
             return ExitCode::FAILURE;
         }
     };
@@ -496,7 +1350,7 @@ pub async fn main() -> ExitCode {
     let device_list = match device_list_xml::parse_device_list(&top) {
         Ok(l) => l,
         Err(e) => {
-            error!("Failed to parse '{}': {}", args.tag_list_conf.display(), e);
+            error!("Failed to parse '{}': {}", tag_list_conf.display(), e);
             return ExitCode::FAILURE;
         }
     };
@@ -509,18 +1363,107 @@ pub async fn main() -> ExitCode {
     //tokio::spawn(mb_task(devices.clone(), mb_send.clone(), mb_receive));
     let mb_options = ModbusOptions {
         poll_interval: Duration::from_millis(args.poll_interval),
+        strict: args.strict,
+        frame_gap: args.frame_gap.map(Duration::from_micros),
+        char_timeout: args.char_timeout.map(Duration::from_micros),
+        timeout: Duration::from_millis(args.timeout),
+        retries: args.retries,
+        reconnect_delay: Duration::from_millis(args.reconnect_delay),
+        max_reconnect_delay: Duration::from_millis(args.max_reconnect_delay),
+        offline_after: args.offline_after,
+        max_read_gap: args.max_read_gap,
+        verify_writes: args.verify_writes,
+    };
+    if mb_options.reconnect_delay > mb_options.max_reconnect_delay {
+        error!("Reconnect delay must not be longer than the maximum reconnect delay");
+        return ExitCode::FAILURE;
+    }
+    let frame_gap = mb_options
+        .frame_gap
+        .unwrap_or(rtu::frame_gap(args.baud_rate));
+    if mb_options.char_timeout.is_some_and(|t| t >= frame_gap) {
+        error!(
+            "Character timeout must be shorter than the frame gap ({} us)",
+            frame_gap.as_micros()
+        );
+        return ExitCode::FAILURE;
+    }
+    let mut serial_port = args
+        .serial_device
+        .as_ref()
+        .map(|path| (path.clone(), serial_settings(&args).builder(path)));
+    let ip_port = args
+        .ip_port
+        .unwrap_or(if args.tls { tls::PORT } else { 502 });
+    let tls_conf = match (args.tls_cert, args.tls_key, args.tls_ca) {
+        (Some(cert), Some(key), Some(ca)) if args.tls => Some(TlsConfig {
+            cert,
+            key,
+            ca,
+            server_name: args.tls_server_name,
+            write_roles: args.tls_write_role,
+        }),
+        _ => None,
+    };
+    let ip_addrs = match resolve(&args.ip_address, ip_port).await {
+        Ok(addrs) if !addrs.is_empty() => addrs,
+        Ok(_) => {
+            error!("No address found for Modbus connection");
+            return ExitCode::FAILURE;
+        }
+        Err(e) => {
+            error!("Failed to resolve Modbus address: {e}");
+            return ExitCode::FAILURE;
+        }
     };
     let join: JoinHandle<DynResult<()>>;
-    if args.server {
-        if let Some(path) = args.serial_device {
-            let parity = match args.parity.get(..1) {
-                Some("e") | Some("E") => Parity::Even,
-                Some("o") | Some("O") => Parity::Odd,
-                Some("n") | Some("N") => Parity::None,
-                Some(_) | None => Parity::Even,
-            };
-            let builder = tokio_serial::new(&path, args.baud_rate.unwrap_or(9600)).parity(parity);
+    if let Some(host) = &args.tap {
+        let upstream = match resolve_first(host, args.tap_port).await {
+            Ok(upstream) => upstream,
+            Err(e) => {
+                error!("Failed to resolve address of tapped server: {e}");
+                return ExitCode::FAILURE;
+            }
+        };
+        let gateway = Gateway::tcp(upstream, devices.clone(), &mb_options);
+        join = serve_all(&ip_addrs, |socket| {
+            modbus_connection::gateway_tcp(socket, gateway.clone())
+        });
+
+        info!("Running as tap at {} to {}", addr_list(&ip_addrs), upstream);
+    } else if let Some((path, builder)) = serial_port.as_ref().filter(|_| args.gateway) {
+        let gateway = match SerialStream::open(builder) {
+            Ok(ser) if args.ascii => Gateway::ascii(ser, devices.clone(), &mb_options),
+            Ok(ser) => match Gateway::rtu(ser, devices.clone(), &mb_options) {
+                Ok(gateway) => gateway,
+                Err(e) => {
+                    error!("Failed to set up serial port: {}", e);
+                    return ExitCode::FAILURE;
+                }
+            },
+            Err(e) => {
+                error!("Failed to open serial port: {}", e);
+                info!("Use the list-ports command to show available ports");
+                return ExitCode::FAILURE;
+            }
+        };
+        join = serve_all(&ip_addrs, |socket| {
+            modbus_connection::gateway_tcp(socket, gateway.clone())
+        });
+
+        info!("Running as gateway at {} to {}", addr_list(&ip_addrs), path);
+    } else if args.server {
+        if let Some((path, builder)) = serial_port {
             match SerialStream::open(&builder) {
+                Ok(ser) if args.ascii => {
+                    join = tokio::spawn(modbus_connection::server_ascii(
+                        ser,
+                        devices.clone(),
+                        mb_options,
+                    ));
+
+                    info!("Running as ASCII server on {}", path);
+                }
                 Ok(ser) => {
                     join = tokio::spawn(modbus_connection::server_rtu(
                         ser,
@@ -532,71 +1475,166 @@ pub async fn main() -> ExitCode {
                 }
                 Err(e) => {
                     error!("Failed to open serial port: {}", e);
-                    for p in tokio_serial::available_ports().unwrap() {
-                        info!("Available device {}: {:?}", p.port_name, p.port_type);
-                    }
+                    info!("Use the list-ports command to show available ports");
                     return ExitCode::FAILURE;
                 }
             }
         } else {
-            let addr = args
-                .ip_address
-                .unwrap_or_else(|| Ipv4Addr::new(127, 0, 0, 1));
-            let port = args.ip_port;
+            let addr_list = addr_list(&ip_addrs);
+            if args.rtu_over_tcp {
+                join = serve_all(&ip_addrs, |socket| {
+                    modbus_connection::server_rtu_over_tcp(
+                        socket,
+                        devices.clone(),
+                        mb_options.clone(),
+                    )
+                });
 
-            join = tokio::spawn(modbus_connection::server_tcp(
-                SocketAddr::V4(SocketAddrV4::new(addr, port)),
-                devices.clone(),
-                mb_options,
-            ));
+                info!("Running as RTU over TCP server at {addr_list}");
+            } else if args.udp {
+                join = serve_all(&ip_addrs, |socket| {
+                    modbus_connection::server_udp(socket, devices.clone(), mb_options.clone())
+                });
+
+                info!("Running as UDP server at {addr_list}");
+            } else if let Some(tls_conf) = tls_conf {
+                join = serve_all(&ip_addrs, |socket| {
+                    modbus_connection::server_tls(
+                        socket,
+                        devices.clone(),
+                        mb_options.clone(),
+                        tls_conf.clone(),
+                    )
+                });
+
+                info!("Running as TLS server at {addr_list}");
+            } else {
+                join = serve_all(&ip_addrs, |socket| {
+                    modbus_connection::server_tcp(socket, devices.clone(), mb_options.clone())
+                });
 
-            info!("Running as TCP server at {}:{}", addr, port);
+                info!("Running as TCP server at {addr_list}");
+            }
         }
     } else {
-        if let Some(path) = args.serial_device {
-            let parity = match args.parity.get(..1) {
-                Some("e") | Some("E") => Parity::Even,
-                Some("o") | Some("O") => Parity::Odd,
-                Some("n") | Some("N") => Parity::None,
-                Some(_) | None => Parity::Even,
-            };
-            let builder = tokio_serial::new(&path, args.baud_rate.unwrap_or(9600)).parity(parity);
-            match SerialStream::open(&builder) {
-                Ok(ser) => {
-                    join = tokio::spawn(modbus_connection::client_rtu(
-                        ser,
-                        Slave(args.mb_address),
-                        devices.clone(),
-                        mb_options,
-                    ));
-                    info!("Running as RTU client on {}", path);
-                }
-                Err(e) => {
-                    error!("Failed to open serial port: {}", e);
-                    for p in tokio_serial::available_ports().unwrap() {
-                        info!("Available device {}: {:?}", p.port_name, p.port_type);
+        let mut clients: Vec<ClientFuture> = Vec::new();
+        for (conn, timing, units) in connection_groups(&device_list) {
+            let devices = devices.select(&units);
+            if let Some(conn) = conn {
+                info!("Running as client for units {units:?} using {conn}");
+                clients.push(Box::pin(connection_client(
+                    conn,
+                    devices,
+                    mb_options.with_timing(&timing),
+                )));
+                continue;
+            }
+            if let Some((path, builder)) = serial_port.take() {
+                match SerialStream::open(&builder) {
+                    Ok(ser) if args.ascii => {
+                        clients.push(Box::pin(modbus_connection::client_ascii(
+                            ser,
+                            Slave(args.mb_address),
+                            devices,
+                            mb_options.clone(),
+                        )));
+                        info!("Running as ASCII client on {}", path);
+                    }
+                    Ok(ser) => {
+                        clients.push(Box::pin(modbus_connection::client_rtu(
+                            ser,
+                            Slave(args.mb_address),
+                            devices,
+                            mb_options.clone(),
+                        )));
+                        info!("Running as RTU client on {}", path);
+                    }
+                    Err(e) => {
+                        error!("Failed to open serial port: {}", e);
+                        info!("Use the list-ports command to show available ports");
+                        return ExitCode::FAILURE;
                     }
-                    return ExitCode::FAILURE;
+                }
+            } else {
+                // Connect to the first address a host name resolves to
+                let socket = ip_addrs[0];
+                let options = mb_options.clone();
+                let slave = Slave(args.mb_address);
+                if args.rtu_over_tcp {
+                    let name = format!("RTU over TCP {socket}");
+                    clients.push(Box::pin(async move {
+                        modbus_connection::client_restarting(&name, devices, options, |d, o| {
+                            modbus_connection::client_rtu_over_tcp(socket, slave, d, o)
+                        })
+                        .await
+                    }));
+                    info!("Running as RTU over TCP client connected to {socket}");
+                } else if args.udp {
+                    clients.push(Box::pin(modbus_connection::client_udp(
+                        socket, slave, devices, options,
+                    )));
+                    info!("Running as UDP client sending to {socket}");
+                } else if let Some(tls_conf) = tls_conf.clone() {
+                    // Certificate errors aren't fixed by connecting again
+                    let connector = match tls::connector(&tls_conf) {
+                        Ok(connector) => connector,
+                        Err(e) => {
+                            error!("Failed to set up TLS: {e}");
+                            return ExitCode::FAILURE;
+                        }
+                    };
+                    let name = format!("TLS {socket}");
+                    clients.push(Box::pin(async move {
+                        modbus_connection::client_restarting(&name, devices, options, |d, o| {
+                            let server_name = tls_conf.server_name.clone();
+                            modbus_connection::client_tls(
+                                socket,
+                                d,
+                                o,
+                                connector.clone(),
+                                server_name,
+                            )
+                        })
+                        .await
+                    }));
+                    info!("Running as TLS client connected to {socket}");
+                } else {
+                    let name = format!("TCP {socket}");
+                    clients.push(Box::pin(async move {
+                        modbus_connection::client_restarting(&name, devices, options, |d, o| {
+                            modbus_connection::client_tcp(socket, d, o)
+                        })
+                        .await
+                    }));
+                    info!("Running as TCP client connected to {socket}");
                 }
             }
-        } else {
-            let addr = args
-                .ip_address
-                .unwrap_or_else(|| Ipv4Addr::new(127, 0, 0, 1));
-            let port = args.ip_port;
-            join = tokio::spawn(modbus_connection::client_tcp(
-                SocketAddr::V4(SocketAddrV4::new(addr, port)),
-                devices.clone(),
-                mb_options,
-            ));
-            info!("Running as TCP client connected to {}:{}", addr, port);
         }
+        // The schedule is dropped when the clients finish
+        let schedule = write_schedule::run(devices.clone());
+        join = tokio::spawn(async move {
+            tokio::select! {
+                res = future::try_join_all(clients) => res.map(|_| ()),
+                res = async {
+                    schedule.await;
+                    future::pending().await
+                } => res,
+            }
+        });
     }
 
     let mut conf = web_server::ServerConfig::new(Box::new(WsHandler::new(devices.clone())));
 
-    if let Some(bind) = args.http_address {
-        conf = conf.bind_addr(IpAddr::V4(bind));
+    match resolve(&args.http_address, 0).await {
+        Ok(addrs) => {
+            for addr in addrs {
+                conf = conf.bind_addr(addr.ip());
+            }
+        }
+        Err(e) => {
+            error!("Failed to resolve HTTP address: {e}");
+            return ExitCode::FAILURE;
+        }
     }
     conf = conf.port(args.http_port);
     let conf = conf.build_page(match template::build_page::<WebTemplates>(device_list) {
@@ -627,14 +1665,28 @@ pub async fn main() -> ExitCode {
         }
     }));
 
-    let (server, bound_ip, bound_port) = match web_server::setup_server(conf) {
+    let conf = conf.api(Box::new(|path| match path {
+        "ports" => {
+            let ports = serial_ports::available()?;
+            Ok(Some((
+                "application/json",
+                Bytes::from(serde_json::to_string(&ports)?),
+            )))
+        }
+        _ => Ok(None),
+    }));
+
+    let (server, bound_addrs) = match web_server::setup_server(conf) {
         Ok(c) => c,
         Err(e) => {
             error!("Failed to set up web server: {e}");
             return ExitCode::FAILURE;
         }
     };
-    let url = format!("http://{}:{}", bound_ip, bound_port);
+    if bound_addrs.len() > 1 {
+        info!("Web server listening on {}", addr_list(&bound_addrs));
+    }
+    let url = format!("http://{}", bound_addrs[0]);
     let browser_start = browser::start(&matches, &url);
 
     tokio::select! {
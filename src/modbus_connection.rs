@@ -1,34 +1,85 @@
-use crate::devices::Devices;
+use crate::ascii;
+use crate::device_list::{ClientTiming, DeviceIdentification, ReadLimits};
+use crate::devices::{self, Devices, Event, RangeError, Table, Traffic, WriteRequest, WriteResult};
+use crate::diagnostics::{self, Diagnostics};
 use crate::error::DynResult;
+use crate::faults::Injection;
 use crate::observable_array::ObservableArray;
-use crate::tags::Updated;
+use crate::pdu;
+use crate::range_array::RangeArray;
+use crate::records::{self, FILE_RECORDS, Records};
+use crate::rtu::{self as rtu_frame, Frame, FrameReader, FrameTiming};
+use crate::tag_list::Poll;
+use crate::tag_ranges::{AccessRanges, PollRanges, TagRanges};
+use crate::tls::{self, TlsConfig};
+use crate::udp;
 #[allow(unused_imports)]
-use log::{debug, error};
-use std::fmt::Debug;
+use log::{debug, error, info};
+use openssl::ssl::SslConnector;
+use std::collections::BTreeMap;
 use std::future::{self, Future};
 use std::net::SocketAddr;
 use std::ops::Range;
 use std::pin::Pin;
-use tokio::io::{AsyncRead, AsyncWrite};
-use tokio::net::TcpListener;
-use tokio::time::{self, Duration};
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::Mutex;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::{self, Duration, Instant};
 use tokio_modbus::ExceptionCode;
+use tokio_modbus::bytes::Bytes;
 use tokio_modbus::client::Reader;
-use tokio_modbus::client::{Context, rtu, tcp};
+use tokio_modbus::client::{Client, Context, tcp};
 use tokio_modbus::prelude::SlaveContext;
 use tokio_modbus::prelude::Writer;
-use tokio_modbus::server::rtu::Server as RtuServer;
+use tokio_modbus::prelude::{
+    ConformityLevel, DeviceIdObject, ReadCode, ReadDeviceIdentificationResponse,
+};
+use tokio_modbus::server::Service;
 use tokio_modbus::server::tcp::Server as TcpServer;
 use tokio_modbus::slave::Slave;
-use tokio_serial::SerialStream;
+use tokio_modbus::{Request, Response, SlaveRequest};
+use tokio_serial::{SerialPort, SerialStream};
 
+#[derive(Clone)]
 struct ModbusService {
     devices: Devices,
+    // Only available on serial lines
+    diagnostics: Option<Arc<Diagnostics>>,
+    // Reject accesses to addresses without a tag
+    strict: bool,
+    // Reject all writes
+    read_only: bool,
 }
 
 impl ModbusService {
     pub fn new(devices: Devices) -> Self {
-        ModbusService { devices }
+        ModbusService {
+            devices,
+            diagnostics: None,
+            strict: false,
+            read_only: false,
+        }
+    }
+
+    pub fn with_diagnostics(devices: Devices, diagnostics: Arc<Diagnostics>) -> Self {
+        ModbusService {
+            devices,
+            diagnostics: Some(diagnostics),
+            strict: false,
+            read_only: false,
+        }
+    }
+
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
     }
 }
 
@@ -44,8 +95,9 @@ where
     T: Default + Clone + Send + Sync + 'static,
 {
     array.get_array(|r| {
-        if start as usize + count as usize <= array.len() {
-            let reg_slice = &r[(start as usize)..(start + count) as usize];
+        let range = start as usize..start as usize + count as usize;
+        if range.end <= array.len() {
+            let reg_slice = &r[range];
             Ok(f(reg_slice.to_vec()))
         } else {
             Err(ExceptionCode::IllegalDataAddress)
@@ -64,7 +116,7 @@ where
     F: FnOnce(u16, &[T]) -> tokio_modbus::prelude::Response,
     T: Default + Clone + Send + Sync + 'static,
 {
-    if (start as usize) < array.len() {
+    if start as usize + data.len() <= array.len() {
         array.update(start as usize, data);
         Ok(f(start, data))
     } else {
@@ -72,6 +124,348 @@ where
     }
 }
 
+fn server_mask_write(
+    array: &ObservableArray<u16>,
+    addr: u16,
+    and_mask: u16,
+    or_mask: u16,
+    _req: &tokio_modbus::prelude::Request,
+) -> Result<tokio_modbus::prelude::Response, ExceptionCode> {
+    let addr = addr as usize;
+    if addr < array.len() {
+        array.modify(addr..addr + 1, |r| {
+            r[addr] = (r[addr] & and_mask) | (or_mask & !and_mask);
+        });
+        Ok(tokio_modbus::prelude::Response::MaskWriteRegister(
+            addr as u16,
+            and_mask,
+            or_mask,
+        ))
+    } else {
+        Err(ExceptionCode::IllegalDataAddress)
+    }
+}
+
+/// The write is done before the read and both are done while holding
+/// the lock, so the read will return the written values if the ranges overlap.
+fn server_read_write(
+    array: &ObservableArray<u16>,
+    read_start: u16,
+    read_count: u16,
+    write_start: u16,
+    data: &[u16],
+    _req: &tokio_modbus::prelude::Request,
+) -> Result<tokio_modbus::prelude::Response, ExceptionCode> {
+    let read = (read_start as usize)..(read_start as usize + read_count as usize);
+    let write = (write_start as usize)..(write_start as usize + data.len());
+    if read.end <= array.len() && write.end <= array.len() {
+        let reply = array.modify(write.clone(), |r| {
+            r[write].clone_from_slice(data);
+            r[read].to_vec()
+        });
+        Ok(tokio_modbus::prelude::Response::ReadWriteMultipleRegisters(reply))
+    } else {
+        Err(ExceptionCode::IllegalDataAddress)
+    }
+}
+
+const MAX_PDU_LEN: usize = 253;
+// Function code, MEI type, read code, conformity level, more follows,
+// next object id and number of objects
+const DEVICE_ID_HEADER_LEN: usize = 7;
+
+/// Objects that don't fit in the response are left for a following
+/// request, starting at `next_object_id`.
+fn server_read_device_id(
+    ident: &DeviceIdentification,
+    read_code: ReadCode,
+    object_id: u8,
+) -> Result<tokio_modbus::prelude::Response, ExceptionCode> {
+    let objects = &ident.objects;
+    let conformity_level = if objects.keys().any(|id| *id >= 0x80) {
+        ConformityLevel::ExtendedIdentification
+    } else if objects.keys().any(|id| *id >= 0x03) {
+        ConformityLevel::RegularIdentification
+    } else {
+        ConformityLevel::BasicIdentification
+    };
+    let ids: Vec<u8> = match read_code {
+        ReadCode::Specific => {
+            if !objects.contains_key(&object_id) {
+                return Err(ExceptionCode::IllegalDataAddress);
+            }
+            vec![object_id]
+        }
+        ReadCode::Basic | ReadCode::Regular | ReadCode::Extended => {
+            let last = match read_code {
+                ReadCode::Basic => 0x02,
+                ReadCode::Regular => 0x06,
+                _ => 0xff,
+            };
+            // Restart from the beginning if the object doesn't exist
+            let first = if object_id <= last && objects.contains_key(&object_id) {
+                object_id
+            } else {
+                0x00
+            };
+            objects.range(first..=last).map(|(id, _)| *id).collect()
+        }
+    };
+    let mut device_id_objects = Vec::new();
+    let mut more_follows = false;
+    let mut next_object_id = 0;
+    let mut len = DEVICE_ID_HEADER_LEN;
+    for id in ids {
+        let value = &objects[&id];
+        len += 2 + value.len();
+        if len > MAX_PDU_LEN {
+            more_follows = true;
+            next_object_id = id;
+            break;
+        }
+        device_id_objects.push(DeviceIdObject {
+            id,
+            value: Bytes::copy_from_slice(value.as_bytes()),
+        });
+    }
+    Ok(tokio_modbus::prelude::Response::ReadDeviceIdentification(
+        ReadDeviceIdentificationResponse {
+            read_code,
+            conformity_level,
+            more_follows,
+            next_object_id,
+            device_id_objects,
+        },
+    ))
+}
+
+const FC_READ_FILE_RECORD: u8 = 0x14;
+const FC_WRITE_FILE_RECORD: u8 = 0x15;
+const FC_READ_FIFO_QUEUE: u8 = 0x18;
+
+// Reference type, file number, record number and record length
+const FILE_SUB_REQUEST_LEN: usize = 7;
+const FILE_REFERENCE_TYPE: u8 = 6;
+const MAX_RECORD_NUMBER: u16 = 0x270f;
+
+fn server_read_fifo(
+    records: &Records,
+    data: &[u8],
+) -> Result<tokio_modbus::Response, ExceptionCode> {
+    let &[addr_high, addr_low] = data else {
+        return Err(ExceptionCode::IllegalDataValue);
+    };
+    let addr = u16::from_be_bytes([addr_high, addr_low]);
+    let Some(fifo) = records.fifos.get(&addr) else {
+        return Err(ExceptionCode::IllegalDataAddress);
+    };
+    let values = records::fifo_values(fifo);
+    let mut reply = Vec::with_capacity(4 + values.len() * 2);
+    reply.extend_from_slice(&((values.len() * 2 + 2) as u16).to_be_bytes());
+    reply.extend_from_slice(&(values.len() as u16).to_be_bytes());
+    for v in values {
+        reply.extend_from_slice(&v.to_be_bytes());
+    }
+    Ok(tokio_modbus::Response::Custom(
+        FC_READ_FIFO_QUEUE,
+        Bytes::from(reply),
+    ))
+}
+
+// Find the file and record range addressed by a sub-request
+fn file_records<'a>(
+    records: &'a Records,
+    sub: &[u8],
+) -> Result<(&'a ObservableArray<u16>, Range<usize>), ExceptionCode> {
+    let file = u16::from_be_bytes([sub[1], sub[2]]);
+    let record = u16::from_be_bytes([sub[3], sub[4]]);
+    let length = u16::from_be_bytes([sub[5], sub[6]]);
+    let end = usize::from(record) + usize::from(length);
+    if sub[0] != FILE_REFERENCE_TYPE || record > MAX_RECORD_NUMBER || end > FILE_RECORDS {
+        return Err(ExceptionCode::IllegalDataAddress);
+    }
+    let Some(array) = records.files.get(&file) else {
+        return Err(ExceptionCode::IllegalDataAddress);
+    };
+    Ok((array, usize::from(record)..end))
+}
+
+fn server_read_file(
+    records: &Records,
+    data: &[u8],
+) -> Result<tokio_modbus::Response, ExceptionCode> {
+    let Some((&byte_count, subs)) = data.split_first() else {
+        return Err(ExceptionCode::IllegalDataValue);
+    };
+    if !(0x07..=0xf5).contains(&byte_count)
+        || subs.len() != usize::from(byte_count)
+        || subs.len() % FILE_SUB_REQUEST_LEN != 0
+    {
+        return Err(ExceptionCode::IllegalDataValue);
+    }
+    // Response data length is filled in last
+    let mut reply = vec![0u8];
+    for sub in subs.chunks(FILE_SUB_REQUEST_LEN) {
+        let (array, range) = file_records(records, sub)?;
+        // Function code, file response length and reference type
+        if 1 + reply.len() + 2 + range.len() * 2 > MAX_PDU_LEN {
+            return Err(ExceptionCode::IllegalDataValue);
+        }
+        reply.push((1 + range.len() * 2) as u8);
+        reply.push(FILE_REFERENCE_TYPE);
+        array.get_array(|a| {
+            for v in &a[range] {
+                reply.extend_from_slice(&v.to_be_bytes());
+            }
+        });
+    }
+    reply[0] = (reply.len() - 1) as u8;
+    Ok(tokio_modbus::Response::Custom(
+        FC_READ_FILE_RECORD,
+        Bytes::from(reply),
+    ))
+}
+
+fn server_write_file(
+    records: &Records,
+    data: &[u8],
+) -> Result<tokio_modbus::Response, ExceptionCode> {
+    let Some((&byte_count, mut subs)) = data.split_first() else {
+        return Err(ExceptionCode::IllegalDataValue);
+    };
+    if !(0x09..=0xfb).contains(&byte_count) || subs.len() != usize::from(byte_count) {
+        return Err(ExceptionCode::IllegalDataValue);
+    }
+    // Check all sub-requests before writing anything
+    let mut writes = Vec::new();
+    while !subs.is_empty() {
+        if subs.len() < FILE_SUB_REQUEST_LEN {
+            return Err(ExceptionCode::IllegalDataValue);
+        }
+        let (array, range) = file_records(records, subs)?;
+        let (values, rest) = subs[FILE_SUB_REQUEST_LEN..]
+            .split_at_checked(range.len() * 2)
+            .ok_or(ExceptionCode::IllegalDataValue)?;
+        let values: Vec<u16> = values
+            .chunks(2)
+            .map(|v| u16::from_be_bytes([v[0], v[1]]))
+            .collect();
+        writes.push((array, range.start, values));
+        subs = rest;
+    }
+    for (array, start, values) in writes {
+        array.update(start, &values);
+    }
+    Ok(tokio_modbus::Response::Custom(
+        FC_WRITE_FILE_RECORD,
+        Bytes::copy_from_slice(data),
+    ))
+}
+
+// Addresses touched by a request for `count` items starting at `start`.
+// The end may be past the last address.
+fn request_range(start: u16, count: usize) -> Range<u32> {
+    let count = u32::try_from(count).unwrap_or(u32::MAX);
+    u32::from(start)..u32::from(start).saturating_add(count)
+}
+
+enum AccessKind {
+    Read,
+    Write,
+}
+
+// Tables and addresses accessed by a request
+fn request_accesses(req: &Request) -> Vec<(Table, AccessKind, Range<u32>)> {
+    use AccessKind::*;
+    use Table::*;
+    use tokio_modbus::Request::*;
+    match req {
+        ReadHoldingRegisters(start, count) => vec![(
+            HoldingRegisters,
+            Read,
+            request_range(*start, usize::from(*count)),
+        )],
+        WriteSingleRegister(addr, _) | MaskWriteRegister(addr, _, _) => {
+            vec![(HoldingRegisters, Write, request_range(*addr, 1))]
+        }
+        WriteMultipleRegisters(addr, values) => {
+            vec![(HoldingRegisters, Write, request_range(*addr, values.len()))]
+        }
+        ReadWriteMultipleRegisters(read_start, read_count, write_start, values) => vec![
+            (
+                HoldingRegisters,
+                Read,
+                request_range(*read_start, usize::from(*read_count)),
+            ),
+            (
+                HoldingRegisters,
+                Write,
+                request_range(*write_start, values.len()),
+            ),
+        ],
+        ReadInputRegisters(start, count) => vec![(
+            InputRegisters,
+            Read,
+            request_range(*start, usize::from(*count)),
+        )],
+        ReadCoils(start, count) => {
+            vec![(Coils, Read, request_range(*start, usize::from(*count)))]
+        }
+        WriteSingleCoil(addr, _) => vec![(Coils, Write, request_range(*addr, 1))],
+        WriteMultipleCoils(addr, values) => {
+            vec![(Coils, Write, request_range(*addr, values.len()))]
+        }
+        ReadDiscreteInputs(start, count) => vec![(
+            DiscreteInputs,
+            Read,
+            request_range(*start, usize::from(*count)),
+        )],
+        _ => Vec::new(),
+    }
+}
+
+// Defined tags and access restrictions for a table
+fn table_ranges(ranges: &TagRanges, table: Table) -> (&RangeArray<u16>, &AccessRanges) {
+    match table {
+        Table::HoldingRegisters => (&ranges.holding_registers, &ranges.holding_register_access),
+        Table::InputRegisters => (&ranges.input_registers, &ranges.input_register_access),
+        Table::Coils => (&ranges.coils, &ranges.coil_access),
+        Table::DiscreteInputs => (&ranges.discrete_inputs, &ranges.discrete_input_access),
+    }
+}
+
+fn is_write(req: &Request) -> bool {
+    matches!(req, Request::Custom(FC_WRITE_FILE_RECORD, _))
+        || request_accesses(req)
+            .iter()
+            .any(|(_, kind, _)| matches!(kind, AccessKind::Write))
+}
+
+/// Reject reads from write-only tags and writes to read-only tags.
+/// In strict mode every address accessed must also belong to a tag.
+fn check_access(ranges: &TagRanges, strict: bool, req: &Request) -> Result<(), ExceptionCode> {
+    for (table, kind, range) in request_accesses(req) {
+        let (defined, access) = table_ranges(ranges, table);
+        let denied = match kind {
+            AccessKind::Read => &access.write_only,
+            AccessKind::Write => &access.read_only,
+        };
+        let last = u32::from(u16::MAX);
+        if range.end > last + 1 {
+            return Err(ExceptionCode::IllegalDataAddress);
+        }
+        // Tag ranges can't include the last address, so a request
+        // reaching it is never fully covered by tags
+        let checked = range.start as u16..range.end.min(last) as u16;
+        if denied.overlaps(&checked)
+            || (strict && (range.end > last || !defined.contains(&checked)))
+        {
+            return Err(ExceptionCode::IllegalDataAddress);
+        }
+    }
+    Ok(())
+}
+
 impl tokio_modbus::server::Service for ModbusService {
     type Request = tokio_modbus::SlaveRequest<'static>;
     type Response = tokio_modbus::Response;
@@ -85,6 +479,31 @@ impl tokio_modbus::server::Service for ModbusService {
             slave: unit,
             request: req,
         } = sreq;
+        let delay = self
+            .devices
+            .response_delay(unit)
+            .map(|d| d.sample())
+            .unwrap_or_default();
+        let delayed = |resp| -> Self::Future {
+            if delay.is_zero() {
+                Box::pin(future::ready(resp))
+            } else {
+                Box::pin(async move {
+                    time::sleep(delay).await;
+                    resp
+                })
+            }
+        };
+        // Unauthorized requests are answered with Illegal Function as
+        // required by Modbus/TCP Security
+        if self.read_only && is_write(&req) {
+            return delayed(Err(ExceptionCode::IllegalFunction));
+        }
+        if let Ok(ranges) = self.devices.ranges(unit)
+            && let Err(e) = check_access(ranges, self.strict, &req)
+        {
+            return delayed(Err(e));
+        }
         let resp = match req {
             ReadHoldingRegisters(start, count) => self.devices.tags_read(unit, |tags| {
                 server_read(
@@ -113,6 +532,21 @@ impl tokio_modbus::server::Service for ModbusService {
                     &req,
                 )
             }),
+            MaskWriteRegister(addr, and_mask, or_mask) => self.devices.tags_read(unit, |tags| {
+                server_mask_write(&tags.holding_registers, addr, and_mask, or_mask, &req)
+            }),
+            ReadWriteMultipleRegisters(read_start, read_count, write_start, ref value) => {
+                self.devices.tags_read(unit, |tags| {
+                    server_read_write(
+                        &tags.holding_registers,
+                        read_start,
+                        read_count,
+                        write_start,
+                        value,
+                        &req,
+                    )
+                })
+            }
             ReadInputRegisters(start, count) => self.devices.tags_read(unit, |tags| {
                 server_read(
                     &tags.input_registers,
@@ -159,30 +593,187 @@ impl tokio_modbus::server::Service for ModbusService {
                     &req,
                 )
             }),
+            ReadDeviceIdentification(read_code, object_id) => {
+                self.devices.identification(unit).map(|ident| match ident {
+                    Some(ident) => server_read_device_id(ident, read_code, object_id),
+                    None => Err(ExceptionCode::IllegalFunction),
+                })
+            }
+            Custom(FC_READ_FIFO_QUEUE, ref data) => self
+                .devices
+                .records_read(unit, |records| server_read_fifo(records, data)),
+            Custom(FC_READ_FILE_RECORD, ref data) => self
+                .devices
+                .records_read(unit, |records| server_read_file(records, data)),
+            Custom(FC_WRITE_FILE_RECORD, ref data) => self
+                .devices
+                .records_read(unit, |records| server_write_file(records, data)),
+            Custom(
+                function @ (diagnostics::FC_DIAGNOSTICS
+                | diagnostics::FC_GET_COMM_EVENT_COUNTER
+                | diagnostics::FC_GET_COMM_EVENT_LOG),
+                ref data,
+            ) => match &self.diagnostics {
+                Some(diag) => diag.request(unit, function, data),
+                None => Ok(Err(ExceptionCode::IllegalFunction)),
+            },
             _ => Ok(Err(ExceptionCode::IllegalFunction)),
         };
         let resp = match resp {
             Ok(r) => r,
             Err(_) => Err(ExceptionCode::ServerDeviceFailure),
         };
-        Box::pin(future::ready(resp))
+        delayed(resp)
+    }
+}
+
+/// Outcome of a request after injecting faults
+struct Injected {
+    result: Result<tokio_modbus::Response, ExceptionCode>,
+    drop_response: bool,
+    corrupt_crc: bool,
+}
+
+/// Applies the faults configured for each device to requests handled
+/// by the wrapped service
+#[derive(Clone)]
+struct FaultInjector {
+    service: ModbusService,
+}
+
+impl FaultInjector {
+    pub fn new(service: ModbusService) -> Self {
+        FaultInjector { service }
+    }
+
+    async fn inject(&self, sreq: SlaveRequest<'static>) -> Injected {
+        let function = sreq.request.function_code().value();
+        let addresses: Vec<Range<u32>> = request_accesses(&sreq.request)
+            .into_iter()
+            .map(|(_, _, range)| range)
+            .collect();
+        let injection = match self.service.devices.faults(sreq.slave) {
+            Ok(faults) => faults.select(function, &addresses),
+            Err(_) => Injection::default(),
+        };
+        if !injection.delay.is_zero() {
+            time::sleep(injection.delay).await;
+        }
+        let result = match injection.exception {
+            Some(exception) => Err(exception),
+            None => self.service.call(sreq).await,
+        };
+        Injected {
+            result,
+            drop_response: injection.drop_response,
+            corrupt_crc: injection.corrupt_crc,
+        }
+    }
+
+    // Decode and handle a request PDU received by a transport not
+    // implemented by tokio-modbus
+    async fn inject_pdu(&self, unit: u8, pdu: &[u8]) -> Injected {
+        match Request::try_from(Bytes::copy_from_slice(pdu)) {
+            Ok(request) => {
+                self.inject(SlaveRequest {
+                    slave: unit,
+                    request,
+                })
+                .await
+            }
+            Err(e) => {
+                debug!("Failed to decode request: {}", e);
+                Injected {
+                    result: Err(ExceptionCode::IllegalDataValue),
+                    drop_response: false,
+                    corrupt_crc: false,
+                }
+            }
+        }
+    }
+}
+
+impl tokio_modbus::server::Service for FaultInjector {
+    type Request = SlaveRequest<'static>;
+    type Response = Option<tokio_modbus::Response>;
+    type Exception = ExceptionCode;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Exception>> + Send>>;
+
+    fn call(&self, sreq: Self::Request) -> Self::Future {
+        let injector = self.clone();
+        Box::pin(async move {
+            let injected = injector.inject(sreq).await;
+            if injected.drop_response {
+                Ok(None)
+            } else {
+                injected.result.map(Some)
+            }
+        })
     }
 }
 
 #[derive(Clone)]
 pub struct ModbusOptions {
     pub poll_interval: Duration,
+    /// Only allow access to addresses defined in the tag list
+    pub strict: bool,
+    /// Silent interval ending an RTU frame on a serial line. Derived
+    /// from the baud rate if not set.
+    pub frame_gap: Option<Duration>,
+    /// Longest silent interval within an RTU frame on a serial line
+    pub char_timeout: Option<Duration>,
+    /// How long a client or gateway waits for a response
+    pub timeout: Duration,
+    /// How many times a client repeats a request without a response
+    pub retries: u32,
+    /// Delay before a client reconnects, doubled after each failed
+    /// attempt up to `max_reconnect_delay`
+    pub reconnect_delay: Duration,
+    pub max_reconnect_delay: Duration,
+    /// Consecutive requests without a response before a unit is
+    /// considered offline, 0 to never
+    pub offline_after: u32,
+    /// Unused addresses a client read may span to merge nearby ranges,
+    /// unless set for the device
+    pub max_read_gap: u16,
+    /// Read back values written by a client
+    pub verify_writes: bool,
+}
+
+impl ModbusOptions {
+    /// Options with the timing of a connection from the configuration
+    pub fn with_timing(&self, timing: &ClientTiming) -> Self {
+        ModbusOptions {
+            timeout: timing.timeout.unwrap_or(self.timeout),
+            retries: timing.retries.unwrap_or(self.retries),
+            reconnect_delay: timing.reconnect_delay.unwrap_or(self.reconnect_delay),
+            max_reconnect_delay: timing
+                .max_reconnect_delay
+                .unwrap_or(self.max_reconnect_delay),
+            ..self.clone()
+        }
+    }
+
+    fn frame_timing(&self, ser: &SerialStream) -> DynResult<FrameTiming> {
+        let standard = FrameTiming::new(ser.baud_rate()?);
+        Ok(FrameTiming {
+            gap: self.frame_gap.unwrap_or(standard.gap),
+            char_timeout: self.char_timeout,
+        })
+    }
 }
 
 pub async fn server_tcp(
     socket: SocketAddr,
     devices: Devices,
-    _options: ModbusOptions,
+    options: ModbusOptions,
 ) -> DynResult<()> {
     let listener = TcpListener::bind(socket).await?;
     let server = TcpServer::new(listener);
-    let on_connected =
-        async |stream, _addr| Ok(Some((ModbusService::new(devices.clone()), stream)));
+    let on_connected = async |stream, _addr| {
+        let service = ModbusService::new(devices.clone()).strict(options.strict);
+        Ok(Some((FaultInjector::new(service), stream)))
+    };
     let on_error = |error| {
         error!("Modbus processing failed: {}", error);
     };
@@ -193,282 +784,2597 @@ pub async fn server_tcp(
     }
 }
 
-pub async fn server_rtu(
-    ser: SerialStream,
+/// Answer datagrams from any peer
+pub async fn server_udp(
+    socket: SocketAddr,
     devices: Devices,
-    _options: ModbusOptions,
+    options: ModbusOptions,
 ) -> DynResult<()> {
-    let server = RtuServer::new(ser);
-    let service = ModbusService::new(devices);
-    server.serve_forever(service).await?;
-    Ok(())
-}
-
-enum ClientOp {
-    ReadHoldingRegisters(u8, u16, u16),
-    //WriteHoldingRegisters(u16, u16),
-    ReadInputRegisters(u8, u16, u16),
-    ReadCoils(u8, u16, u16),
-    //WriteCoils(u16, u16),
-    ReadDiscreteInputs(u8, u16, u16),
+    let udp = Arc::new(UdpSocket::bind(socket).await?);
+    let service = ModbusService::new(devices).strict(options.strict);
+    let injector = FaultInjector::new(service);
+    let mut buf = [0u8; udp::MAX_ADU_LEN];
+    loop {
+        let (n, peer) = udp.recv_from(&mut buf).await?;
+        let Some((header, pdu)) = udp::decode_adu(&buf[..n]) else {
+            debug!("Invalid datagram from {peer}");
+            continue;
+        };
+        let pdu = pdu.to_vec();
+        let (udp, injector) = (udp.clone(), injector.clone());
+        // Requests are handled concurrently since there's no connection
+        // keeping them in order
+        tokio::spawn(async move {
+            let injected = injector.inject_pdu(header.unit, &pdu).await;
+            if injected.drop_response {
+                return;
+            }
+            let reply = pdu::encode_result(pdu[0], &injected.result);
+            if let Err(e) = udp.send_to(&udp::encode_adu(&header, &reply), peer).await {
+                error!("Failed to send response to {peer}: {e}");
+            }
+        });
+    }
 }
 
-const READ_BITS_MAX_LEN: u16 = 2000;
-const READ_REGISTERS_MAX_LEN: u16 = 125;
-//const WRITE_REGISTERS_MAX_LEN: u16 = 123;
-//const WRITE_BITS_MAX_LEN: u16 = 1968;
+// A client that never completes the handshake would hold on to its
+// connection
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
 
-const CLIENT_TIMEOUT: Duration = Duration::from_millis(500);
+// Answer requests in MBAP frames on a stream, as the tokio-modbus server
+// does for the TCP connections it accepts itself
+async fn serve_mbap<S>(mut stream: S, injector: FaultInjector) -> std::io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let invalid = || std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid MBAP header");
+    let mut adu = [0u8; udp::MAX_ADU_LEN];
+    loop {
+        match stream.read_exact(&mut adu[..udp::MBAP_LEN]).await {
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        }
+        // The length counts the unit and the PDU
+        let len = 6 + usize::from(u16::from_be_bytes([adu[4], adu[5]]));
+        if len <= udp::MBAP_LEN || len > adu.len() {
+            return Err(invalid());
+        }
+        stream.read_exact(&mut adu[udp::MBAP_LEN..len]).await?;
+        let (header, pdu) = udp::decode_adu(&adu[..len]).ok_or_else(invalid)?;
+        let injected = injector.inject_pdu(header.unit, pdu).await;
+        if injected.drop_response {
+            continue;
+        }
+        let reply = pdu::encode_result(pdu[0], &injected.result);
+        stream.write_all(&udp::encode_adu(&header, &reply)).await?;
+    }
+}
 
-impl ClientOp {
-    pub async fn execute(&self, client: &mut Context, devices: &Devices) -> DynResult<()> {
-        match self {
-            ClientOp::ReadHoldingRegisters(unit, start, length) => {
-                match tokio::time::timeout(CLIENT_TIMEOUT, {
-                    client.set_slave(Slave(*unit));
-                    client.read_holding_registers(*start, *length)
-                })
-                .await
-                {
-                    Ok(Ok(Ok(data))) => {
-                        devices.tags_write(*unit, |tags| {
-                            tags.holding_registers.update(*start as usize, &data);
-                        })?;
-                    }
-                    Ok(Ok(Err(code))) => return Err(code.into()),
-                    Ok(Err(e)) => return Err(e.into()),
-                    Err(e) => return Err(e.into()),
-                }
-            }
-            /*
-            ClientOp::WriteHoldingRegisters(start, length) => {
-                let data = tags
-                    .holding_registers
-                    .get_array(|r| Vec::from(&r[*start as usize..(*start + *length) as usize]));
-                if *length == 1 {
-                    client.write_single_register(*start, data[0]).await?;
-                } else {
-                    client.write_multiple_registers(*start, &data).await?;
-                }
-            }*/
-            ClientOp::ReadInputRegisters(unit, start, length) => {
-                match tokio::time::timeout(CLIENT_TIMEOUT, {
-                    client.set_slave(Slave(*unit));
-                    client.read_input_registers(*start, *length)
-                })
-                .await
-                {
-                    Ok(Ok(Ok(data))) => devices.tags_write(*unit, |tags| {
-                        tags.input_registers.update(*start as usize, &data);
-                    })?,
-                    Ok(Ok(Err(code))) => return Err(code.into()),
-                    Ok(Err(e)) => return Err(e.into()),
-                    Err(e) => return Err(e.into()),
-                }
-            }
-            ClientOp::ReadCoils(unit, start, length) => {
-                match tokio::time::timeout(CLIENT_TIMEOUT, {
-                    client.set_slave(Slave(*unit));
-                    client.read_coils(*start, *length)
-                })
-                .await
-                {
-                    Ok(Ok(Ok(data))) => {
-                        devices.tags_write(*unit, |tags| {
-                            tags.coils.update(*start as usize, &data);
-                        })?;
+/// Modbus/TCP Security server. Clients must present a certificate
+/// signed by the configured CA.
+pub async fn server_tls(
+    socket: SocketAddr,
+    devices: Devices,
+    options: ModbusOptions,
+    tls_conf: TlsConfig,
+) -> DynResult<()> {
+    let acceptor = tls::acceptor(&tls_conf)?;
+    let listener = TcpListener::bind(socket).await?;
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        debug!("Accepted connection from {addr}");
+        let (acceptor, devices, tls_conf) = (acceptor.clone(), devices.clone(), tls_conf.clone());
+        let strict = options.strict;
+        // Each handshake runs in the task of its connection so a slow
+        // client doesn't hold up the others
+        tokio::spawn(async move {
+            let stream =
+                match time::timeout(TLS_HANDSHAKE_TIMEOUT, tls::accept(&acceptor, stream)).await {
+                    Ok(Ok(stream)) => stream,
+                    Ok(Err(e)) => {
+                        info!("TLS handshake with {addr} failed: {e}");
+                        return;
                     }
-                    Ok(Ok(Err(code))) => return Err(code.into()),
-                    Ok(Err(e)) => return Err(e.into()),
-                    Err(e) => return Err(e.into()),
-                }
-            }
-            /*
-            ClientOp::WriteCoils(start, length) => {
-                let data = tags
-                    .coils
-                    .get_array(|r| Vec::from(&r[*start as usize..(*start + *length) as usize]));
-                if *length == 1 {
-                    client.write_single_coil(*start, data[0]).await?;
-                } else {
-                    client.write_multiple_coils(*start, &data).await?;
-                }
-            }*/
-            ClientOp::ReadDiscreteInputs(unit, start, length) => {
-                match tokio::time::timeout(CLIENT_TIMEOUT, {
-                    client.set_slave(Slave(*unit));
-                    client.read_discrete_inputs(*start, *length)
-                })
-                .await
-                {
-                    Ok(Ok(Ok(data))) => {
-                        devices.tags_write(*unit, |tags| {
-                            tags.discrete_inputs.update(*start as usize, &data);
-                        })?;
+                    Err(_) => {
+                        info!("TLS handshake with {addr} timed out");
+                        return;
                     }
-                    Ok(Ok(Err(code))) => return Err(code.into()),
-                    Ok(Err(e)) => return Err(e.into()),
-                    Err(e) => return Err(e.into()),
-                }
+                };
+            let role = tls::peer_role(stream.ssl());
+            let may_write = tls_conf.may_write(role.as_deref());
+            info!("Client {addr} connected with role {role:?}, may write: {may_write}");
+            let service = ModbusService::new(devices)
+                .strict(strict)
+                .read_only(!may_write);
+            if let Err(e) = serve_mbap(stream, FaultInjector::new(service)).await {
+                error!("Modbus processing failed: {}", e);
             }
+        });
+    }
+}
+
+// Handle a request for one unit. Returns the response ADU, if any.
+async fn serve_line_request(
+    injector: &FaultInjector,
+    framing: LineFraming,
+    diag: &Diagnostics,
+    unit: u8,
+    broadcast: bool,
+    pdu: &[u8],
+) -> Option<Vec<u8>> {
+    let function = pdu[0];
+    diag.received(unit, broadcast);
+    let listen_only = diag.listen_only(unit);
+    if listen_only && !diagnostics::is_restart(function, &pdu[1..]) {
+        diag.ignored(unit);
+        return None;
+    }
+    let injected = injector.inject_pdu(unit, pdu).await;
+    let respond = !broadcast && !listen_only && !diag.listen_only(unit) && !injected.drop_response;
+    diag.completed(unit, function, &injected.result, respond);
+    if !respond {
+        return None;
+    }
+    let reply = pdu::encode_result(function, &injected.result);
+    Some(framing.encode(unit, &reply, injected.corrupt_crc))
+}
+
+/// Framing of requests and responses on a serial line
+#[derive(Clone, Copy)]
+enum LineFraming {
+    /// RTU frames, ended by a silent interval if the length can't be
+    /// determined from the content
+    Rtu(FrameTiming),
+    Ascii,
+}
+
+enum LineReader<R> {
+    Rtu(FrameReader<R>),
+    Ascii(ascii::FrameReader<R>),
+}
+
+impl<R> LineReader<R>
+where
+    R: AsyncRead + Unpin,
+{
+    async fn next(&mut self) -> std::io::Result<Option<Frame>> {
+        match self {
+            LineReader::Rtu(frames) => frames.next().await,
+            LineReader::Ascii(frames) => frames.next().await,
         }
-        Ok(())
     }
+}
 
-    fn push_range<F>(seq: &mut Vec<ClientOp>, range: &Range<u16>, max_len: u16, f: F)
+impl LineFraming {
+    fn reader<R>(self, reader: R) -> LineReader<R>
     where
-        F: Fn(u16, u16) -> ClientOp,
+        R: AsyncRead + Unpin,
     {
-        let mut start = range.start;
-        let mut length = range.end - range.start;
-        while start < range.end {
-            let op_len = length.min(max_len);
-            seq.push(f(start, op_len));
-            start += op_len;
-            length -= op_len;
+        match self {
+            LineFraming::Rtu(timing) => {
+                LineReader::Rtu(FrameReader::new(reader, timing, rtu_frame::request_len))
+            }
+            LineFraming::Ascii => LineReader::Ascii(ascii::FrameReader::new(reader)),
         }
     }
 
-    pub fn read_sequence(devices: &Devices) -> Vec<ClientOp> {
-        let mut seq = Vec::new();
-        for unit in devices.units() {
-            let ranges = devices.ranges(unit).unwrap();
-            for range in &ranges.holding_registers {
-                Self::push_range(&mut seq, range, READ_REGISTERS_MAX_LEN, |start, length| {
-                    ClientOp::ReadHoldingRegisters(unit, start, length)
-                });
-            }
-            for range in &ranges.input_registers {
-                Self::push_range(&mut seq, range, READ_REGISTERS_MAX_LEN, |start, length| {
-                    ClientOp::ReadInputRegisters(unit, start, length)
-                });
-            }
-            for range in &ranges.coils {
-                Self::push_range(&mut seq, range, READ_BITS_MAX_LEN, |start, length| {
-                    ClientOp::ReadCoils(unit, start, length)
-                });
-            }
-            for range in &ranges.discrete_inputs {
-                Self::push_range(&mut seq, range, READ_BITS_MAX_LEN, |start, length| {
-                    ClientOp::ReadDiscreteInputs(unit, start, length)
-                });
+    // Build a response frame, with an invalid checksum if `corrupt` is set
+    fn encode(self, unit: u8, pdu: &[u8], corrupt: bool) -> Vec<u8> {
+        match self {
+            LineFraming::Rtu(_) => {
+                let mut adu = rtu_frame::encode_frame(unit, pdu);
+                if corrupt {
+                    let last = adu.len() - 1;
+                    adu[last] ^= 0xff;
+                }
+                adu
             }
+            LineFraming::Ascii if corrupt => ascii::encode_frame_bad_lrc(unit, pdu),
+            LineFraming::Ascii => ascii::encode_frame(unit, pdu),
         }
-        seq
     }
 }
 
-async fn handle_poll(
-    unit: u8,
-    updated: &Updated,
-    client: &mut Context,
-    devices: &Devices,
+async fn serve_line<S>(stream: S, framing: LineFraming, injector: FaultInjector) -> DynResult<()>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    let Some(diag) = injector.service.diagnostics.clone() else {
+        return Err("Serial line server requires diagnostics".into());
+    };
+    let units: Vec<u8> = injector.service.devices.units().collect();
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut frames = framing.reader(reader);
+    while let Some(frame) = frames.next().await? {
+        let adu = match frame {
+            Frame::Adu(adu) => adu,
+            Frame::CrcError => {
+                debug!("Received frame with invalid CRC");
+                diag.bus_comm_error();
+                continue;
+            }
+            Frame::Overrun => {
+                debug!("Received frame is too long");
+                diag.bus_char_overrun();
+                continue;
+            }
+        };
+        diag.bus_message();
+        let unit = adu[0];
+        if unit == 0 {
+            for &unit in &units {
+                serve_line_request(&injector, framing, &diag, unit, true, &adu[1..]).await;
+            }
+        } else if units.contains(&unit)
+            && let Some(reply) =
+                serve_line_request(&injector, framing, &diag, unit, false, &adu[1..]).await
+        {
+            writer.write_all(&reply).await?;
+        }
+    }
+    Ok(())
+}
+
+pub async fn server_rtu(
+    ser: SerialStream,
+    devices: Devices,
+    options: ModbusOptions,
 ) -> DynResult<()> {
-    use Updated::*;
-    match updated {
-        HoldingRegisters(changes) => {
-            for range in changes {
-                let start = range.start;
-                let length = range.end - range.start;
-                let data = devices.tags_read(unit, |tags| {
-                    tags.holding_registers
-                        .get_array(|r| Vec::from(&r[start..start + length]))
+    let timing = options.frame_timing(&ser)?;
+    let diag = Arc::new(Diagnostics::new(devices.units()));
+    let service = ModbusService::with_diagnostics(devices, diag).strict(options.strict);
+    serve_line(ser, LineFraming::Rtu(timing), FaultInjector::new(service)).await
+}
+
+pub async fn server_ascii(
+    ser: SerialStream,
+    devices: Devices,
+    options: ModbusOptions,
+) -> DynResult<()> {
+    let diag = Arc::new(Diagnostics::new(devices.units()));
+    let service = ModbusService::with_diagnostics(devices, diag).strict(options.strict);
+    serve_line(ser, LineFraming::Ascii, FaultInjector::new(service)).await
+}
+
+// Frames sent over TCP normally arrive in one segment, so the gap only
+// ends frames with a length that can't be determined from the content.
+const TCP_FRAME_TIMING: FrameTiming = FrameTiming {
+    gap: Duration::from_millis(20),
+    char_timeout: None,
+};
+
+/// Serve RTU frames over TCP. All connections share the diagnostics,
+/// as if they were connected to the same serial line.
+pub async fn server_rtu_over_tcp(
+    socket: SocketAddr,
+    devices: Devices,
+    options: ModbusOptions,
+) -> DynResult<()> {
+    let listener = TcpListener::bind(socket).await?;
+    let diag = Arc::new(Diagnostics::new(devices.units()));
+    let service = ModbusService::with_diagnostics(devices, diag).strict(options.strict);
+    let injector = FaultInjector::new(service);
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        debug!("Accepted connection from {addr}");
+        let injector = injector.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve_line(stream, LineFraming::Rtu(TCP_FRAME_TIMING), injector).await {
+                error!("Modbus processing failed: {}", e);
+            }
+        });
+    }
+}
+
+// Time left to the units to handle a forwarded broadcast
+const BROADCAST_TURNAROUND: Duration = Duration::from_millis(100);
+
+/// Forwards requests received over TCP to units on a serial bus, or to
+/// another server in tap mode, one request at a time. Values passing
+/// through are mirrored into the device the request was addressed to,
+/// which is forwarded to the remote address of the device.
+/// Tags marked as overridden replace the values in flight.
+#[derive(Clone)]
+pub struct Gateway {
+    // None while not connected to the upstream server
+    bus: Arc<Mutex<Option<Context>>>,
+    // Modbus TCP server to connect to in tap mode
+    upstream: Option<SocketAddr>,
+    devices: Devices,
+    timeout: Duration,
+}
+
+impl Gateway {
+    pub fn rtu(ser: SerialStream, devices: Devices, options: &ModbusOptions) -> DynResult<Self> {
+        let timing = options.frame_timing(&ser)?;
+        Ok(Self::new(
+            rtu_frame::attach_slave(ser, Slave(0), timing),
+            devices,
+            options.timeout,
+        ))
+    }
+
+    pub fn ascii(ser: SerialStream, devices: Devices, options: &ModbusOptions) -> Self {
+        Self::new(ascii::attach_slave(ser, Slave(0)), devices, options.timeout)
+    }
+
+    /// Forward to a Modbus TCP server. The connection is made when the
+    /// first request arrives and remade after a failure.
+    pub fn tcp(upstream: SocketAddr, devices: Devices, options: &ModbusOptions) -> Self {
+        Gateway {
+            bus: Arc::new(Mutex::new(None)),
+            upstream: Some(upstream),
+            devices,
+            timeout: options.timeout,
+        }
+    }
+
+    fn new(bus: Context, devices: Devices, timeout: Duration) -> Self {
+        Gateway {
+            bus: Arc::new(Mutex::new(Some(bus))),
+            upstream: None,
+            devices,
+            timeout,
+        }
+    }
+
+    // The bus, connected to the upstream server first in tap mode
+    async fn connect<'a>(
+        &self,
+        bus: &'a mut Option<Context>,
+    ) -> Result<&'a mut Context, ExceptionCode> {
+        if bus.is_none()
+            && let Some(upstream) = self.upstream
+        {
+            match time::timeout(self.timeout, tcp::connect(upstream)).await {
+                Ok(Ok(ctx)) => *bus = Some(ctx),
+                Ok(Err(e)) => {
+                    debug!("Failed to connect to {upstream}: {e}");
+                    return Err(ExceptionCode::GatewayPathUnavailable);
+                }
+                Err(_) => {
+                    debug!("Timeout when connecting to {upstream}");
+                    return Err(ExceptionCode::GatewayPathUnavailable);
+                }
+            }
+        }
+        bus.as_mut().ok_or(ExceptionCode::GatewayPathUnavailable)
+    }
+
+    async fn call_bus(
+        &self,
+        slave: u8,
+        request: Request<'static>,
+    ) -> Result<Result<Response, ExceptionCode>, ExceptionCode> {
+        let mut bus = self.bus.lock().await;
+        let ctx = self.connect(&mut bus).await?;
+        ctx.set_slave(Slave(slave));
+        let result = match time::timeout(self.timeout, ctx.call(request)).await {
+            Ok(Ok(result)) => return Ok(result),
+            Ok(Err(e)) => {
+                debug!("Unit {slave}: Forwarding failed: {e}");
+                Err(ExceptionCode::GatewayPathUnavailable)
+            }
+            Err(_) => {
+                debug!("Unit {slave}: Forwarded request timed out");
+                Err(ExceptionCode::GatewayTargetDevice)
+            }
+        };
+        // A late response would be taken as the answer to the next request
+        if self.upstream.is_some() {
+            *bus = None;
+        }
+        result
+    }
+
+    // Units don't answer broadcasts. The bus is kept for a while so the
+    // units have handled the request before the next one is sent.
+    async fn broadcast(&self, request: Request<'static>) {
+        let mut bus = self.bus.lock().await;
+        let Ok(ctx) = self.connect(&mut bus).await else {
+            return;
+        };
+        ctx.set_slave(Slave::broadcast());
+        // A server in tap mode may answer anyway. The connection is
+        // remade if it doesn't, as the answer could come later.
+        let answered = time::timeout(BROADCAST_TURNAROUND, ctx.call(request)).await;
+        if !matches!(answered, Ok(Ok(_))) && self.upstream.is_some() {
+            *bus = None;
+        }
+    }
+
+    // Forward a request to the unit with the remote address of the
+    // device. Broadcasts are sent to all units and not answered.
+    async fn forward(
+        &self,
+        sreq: SlaveRequest<'static>,
+    ) -> Result<Option<Response>, ExceptionCode> {
+        let SlaveRequest { slave, request } = sreq;
+        if slave == 0 {
+            self.broadcast(request.clone()).await;
+            debug!("Broadcast {request:?}");
+            self.devices.record_traffic(Traffic {
+                unit: slave,
+                request: format!("{request:?}"),
+                response: "No response to broadcast".to_string(),
+            });
+            return Ok(None);
+        }
+        let mut forwarded = request.clone();
+        override_request(&self.devices, slave, &mut forwarded);
+        let remote = self.devices.remote_unit(slave).unwrap_or(slave);
+        let result = match self.call_bus(remote, forwarded.clone()).await {
+            Ok(Ok(mut response)) => {
+                override_response(&self.devices, slave, &request, &mut response);
+                mirror(&self.devices, slave, &forwarded, &response);
+                Ok(response)
+            }
+            Ok(Err(e)) | Err(e) => Err(e),
+        };
+        debug!("Unit {slave}: {request:?} -> {result:?}");
+        self.devices.record_traffic(Traffic {
+            unit: slave,
+            request: format!("{request:?}"),
+            response: match &result {
+                Ok(response) => format!("{response:?}"),
+                Err(e) => format!("{e}"),
+            },
+        });
+        result.map(Some)
+    }
+}
+
+// Replace values of overridden tags with the values in the device
+fn override_values<T: Default + Clone + Send + Sync + 'static>(
+    array: &ObservableArray<T>,
+    overrides: &RangeArray<u16>,
+    start: u16,
+    count: u16,
+    values: &mut [T],
+) {
+    let start = usize::from(start);
+    let end = start + values.len().min(usize::from(count));
+    if end > array.len() {
+        return;
+    }
+    array.get_array(|array| {
+        for range in overrides {
+            let low = usize::from(range.start).max(start);
+            let high = usize::from(range.end).min(end);
+            if low < high {
+                values[low - start..high - start].clone_from_slice(&array[low..high]);
+            }
+        }
+    });
+}
+
+fn override_request(devices: &Devices, unit: u8, request: &mut Request<'_>) {
+    let Ok(ranges) = devices.ranges(unit) else {
+        return;
+    };
+    let holding = &ranges.holding_register_overrides;
+    let coils = &ranges.coil_overrides;
+    let _ = devices.tags_read(unit, |tags| match request {
+        Request::WriteSingleRegister(addr, value) => {
+            let value = std::slice::from_mut(value);
+            override_values(&tags.holding_registers, holding, *addr, 1, value);
+        }
+        Request::WriteMultipleRegisters(start, values)
+        | Request::ReadWriteMultipleRegisters(_, _, start, values) => {
+            let values = values.to_mut();
+            override_values(&tags.holding_registers, holding, *start, u16::MAX, values);
+        }
+        Request::WriteSingleCoil(addr, value) => {
+            let value = std::slice::from_mut(value);
+            override_values(&tags.coils, coils, *addr, 1, value);
+        }
+        Request::WriteMultipleCoils(start, values) => {
+            let values = values.to_mut();
+            override_values(&tags.coils, coils, *start, u16::MAX, values);
+        }
+        _ => {}
+    });
+}
+
+fn override_response(devices: &Devices, unit: u8, request: &Request<'_>, response: &mut Response) {
+    let Ok(ranges) = devices.ranges(unit) else {
+        return;
+    };
+    let _ = devices.tags_read(unit, |tags| match (request, response) {
+        (Request::ReadHoldingRegisters(start, count), Response::ReadHoldingRegisters(data))
+        | (
+            Request::ReadWriteMultipleRegisters(start, count, _, _),
+            Response::ReadWriteMultipleRegisters(data),
+        ) => {
+            let overrides = &ranges.holding_register_overrides;
+            override_values(&tags.holding_registers, overrides, *start, *count, data);
+        }
+        (Request::ReadInputRegisters(start, count), Response::ReadInputRegisters(data)) => {
+            let overrides = &ranges.input_register_overrides;
+            override_values(&tags.input_registers, overrides, *start, *count, data);
+        }
+        (Request::ReadCoils(start, count), Response::ReadCoils(data)) => {
+            override_values(&tags.coils, &ranges.coil_overrides, *start, *count, data);
+        }
+        (Request::ReadDiscreteInputs(start, count), Response::ReadDiscreteInputs(data)) => {
+            let overrides = &ranges.discrete_input_overrides;
+            override_values(&tags.discrete_inputs, overrides, *start, *count, data);
+        }
+        // The client expects its own value echoed, not the replacement
+        (Request::WriteSingleRegister(_, value), Response::WriteSingleRegister(_, echo)) => {
+            *echo = *value;
+        }
+        (Request::WriteSingleCoil(_, value), Response::WriteSingleCoil(_, echo)) => {
+            *echo = *value;
+        }
+        _ => {}
+    });
+}
+
+// Copy values read or written by a forwarded request. Only units in the
+// configuration are updated. Overridden values have already been replaced
+// so the tags keep their values.
+fn mirror(devices: &Devices, unit: u8, request: &Request<'_>, response: &Response) {
+    fn update<T: Default + Clone + Send + Sync + 'static>(
+        array: &ObservableArray<T>,
+        start: u16,
+        count: u16,
+        data: &[T],
+    ) {
+        let data = &data[..data.len().min(usize::from(count))];
+        if usize::from(start) + data.len() <= array.len() {
+            array.update(usize::from(start), data);
+        }
+    }
+    if !devices.units().any(|u| u == unit) {
+        return;
+    }
+    let _ = devices.tags_write(unit, |tags| match (request, response) {
+        (Request::ReadHoldingRegisters(start, count), Response::ReadHoldingRegisters(data)) => {
+            update(&tags.holding_registers, *start, *count, data);
+        }
+        (Request::ReadInputRegisters(start, count), Response::ReadInputRegisters(data)) => {
+            update(&tags.input_registers, *start, *count, data);
+        }
+        (Request::ReadCoils(start, count), Response::ReadCoils(data)) => {
+            update(&tags.coils, *start, *count, data);
+        }
+        (Request::ReadDiscreteInputs(start, count), Response::ReadDiscreteInputs(data)) => {
+            update(&tags.discrete_inputs, *start, *count, data);
+        }
+        (Request::WriteSingleRegister(addr, value), _) => {
+            update(&tags.holding_registers, *addr, 1, &[*value]);
+        }
+        (Request::WriteMultipleRegisters(start, values), _) => {
+            update(&tags.holding_registers, *start, u16::MAX, values);
+        }
+        (Request::WriteSingleCoil(addr, value), _) => {
+            update(&tags.coils, *addr, 1, &[*value]);
+        }
+        (Request::WriteMultipleCoils(start, values), _) => {
+            update(&tags.coils, *start, u16::MAX, values);
+        }
+        (
+            Request::ReadWriteMultipleRegisters(read_start, count, write_start, values),
+            Response::ReadWriteMultipleRegisters(data),
+        ) => {
+            update(&tags.holding_registers, *write_start, u16::MAX, values);
+            update(&tags.holding_registers, *read_start, *count, data);
+        }
+        _ => {}
+    });
+}
+
+impl tokio_modbus::server::Service for Gateway {
+    type Request = SlaveRequest<'static>;
+    // None for broadcasts
+    type Response = Option<Response>;
+    type Exception = ExceptionCode;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Exception>> + Send>>;
+
+    fn call(&self, sreq: Self::Request) -> Self::Future {
+        let gateway = self.clone();
+        Box::pin(async move { gateway.forward(sreq).await })
+    }
+}
+
+/// Accept Modbus TCP connections and forward the requests
+pub async fn gateway_tcp(socket: SocketAddr, gateway: Gateway) -> DynResult<()> {
+    let listener = TcpListener::bind(socket).await?;
+    let server = TcpServer::new(listener);
+    let on_connected = async |stream, addr| {
+        debug!("Accepted gateway connection from {addr}");
+        Ok(Some((gateway.clone(), stream)))
+    };
+    let on_error = |error| {
+        error!("Gateway processing failed: {}", error);
+    };
+    server.serve(&on_connected, on_error).await?;
+    Ok(())
+}
+
+#[derive(Debug, PartialEq)]
+enum ClientOp {
+    ReadHoldingRegisters(u8, u16, u16),
+    WriteHoldingRegisters(u8, u16, Vec<u16>),
+    ReadInputRegisters(u8, u16, u16),
+    ReadCoils(u8, u16, u16),
+    WriteCoils(u8, u16, Vec<bool>),
+    ReadDiscreteInputs(u8, u16, u16),
+}
+
+/// Most values a read request can return
+pub const READ_BITS_MAX_LEN: u16 = 2000;
+pub const READ_REGISTERS_MAX_LEN: u16 = 125;
+const WRITE_REGISTERS_MAX_LEN: u16 = 123;
+const WRITE_BITS_MAX_LEN: u16 = 1968;
+
+impl ClientOp {
+    fn unit(&self) -> u8 {
+        match self {
+            ClientOp::ReadHoldingRegisters(unit, ..)
+            | ClientOp::WriteHoldingRegisters(unit, ..)
+            | ClientOp::ReadInputRegisters(unit, ..)
+            | ClientOp::ReadCoils(unit, ..)
+            | ClientOp::WriteCoils(unit, ..)
+            | ClientOp::ReadDiscreteInputs(unit, ..) => *unit,
+        }
+    }
+
+    /// Table and addresses read or written
+    fn range(&self) -> (Table, Range<u16>) {
+        let (table, start, length) = match self {
+            ClientOp::ReadHoldingRegisters(_, start, length) => {
+                (Table::HoldingRegisters, *start, *length)
+            }
+            ClientOp::WriteHoldingRegisters(_, start, values) => {
+                (Table::HoldingRegisters, *start, values.len() as u16)
+            }
+            ClientOp::ReadInputRegisters(_, start, length) => {
+                (Table::InputRegisters, *start, *length)
+            }
+            ClientOp::ReadCoils(_, start, length) => (Table::Coils, *start, *length),
+            ClientOp::WriteCoils(_, start, values) => (Table::Coils, *start, values.len() as u16),
+            ClientOp::ReadDiscreteInputs(_, start, length) => {
+                (Table::DiscreteInputs, *start, *length)
+            }
+        };
+        (table, start..start + length)
+    }
+
+    pub async fn execute(
+        &self,
+        client: &mut Context,
+        devices: &Devices,
+        timeout: Duration,
+    ) -> DynResult<()> {
+        client.set_slave(Slave(devices.remote_unit(self.unit())?));
+        match self {
+            ClientOp::ReadHoldingRegisters(unit, start, length) => {
+                match time::timeout(timeout, client.read_holding_registers(*start, *length)).await {
+                    Ok(Ok(Ok(data))) => {
+                        devices.tags_write(*unit, |tags| {
+                            tags.holding_registers.update(*start as usize, &data);
+                        })?;
+                    }
+                    Ok(Ok(Err(code))) => return Err(code.into()),
+                    Ok(Err(e)) => return Err(e.into()),
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            ClientOp::WriteHoldingRegisters(unit, start, values) => {
+                if let [value] = values[..] {
+                    time::timeout(timeout, client.write_single_register(*start, value)).await???;
+                } else {
+                    time::timeout(timeout, client.write_multiple_registers(*start, values))
+                        .await???;
+                }
+                // A poll may have replaced the changed values in the tags
+                // before they were written
+                devices.tags_write(*unit, |tags| {
+                    tags.holding_registers.update(*start as usize, values);
                 })?;
-                if length == 1 {
-                    client
-                        .write_single_register(start as u16, data[0])
-                        .await??;
+            }
+            ClientOp::ReadInputRegisters(unit, start, length) => {
+                match time::timeout(timeout, client.read_input_registers(*start, *length)).await {
+                    Ok(Ok(Ok(data))) => devices.tags_write(*unit, |tags| {
+                        tags.input_registers.update(*start as usize, &data);
+                    })?,
+                    Ok(Ok(Err(code))) => return Err(code.into()),
+                    Ok(Err(e)) => return Err(e.into()),
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            ClientOp::ReadCoils(unit, start, length) => {
+                match time::timeout(timeout, client.read_coils(*start, *length)).await {
+                    Ok(Ok(Ok(data))) => {
+                        devices.tags_write(*unit, |tags| {
+                            tags.coils.update(*start as usize, &data);
+                        })?;
+                    }
+                    Ok(Ok(Err(code))) => return Err(code.into()),
+                    Ok(Err(e)) => return Err(e.into()),
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            ClientOp::WriteCoils(unit, start, values) => {
+                if let [value] = values[..] {
+                    time::timeout(timeout, client.write_single_coil(*start, value)).await???;
                 } else {
-                    client
-                        .write_multiple_registers(start as u16, &data)
-                        .await??;
+                    time::timeout(timeout, client.write_multiple_coils(*start, values)).await???;
+                }
+                devices.tags_write(*unit, |tags| tags.coils.update(*start as usize, values))?;
+            }
+            ClientOp::ReadDiscreteInputs(unit, start, length) => {
+                match time::timeout(timeout, client.read_discrete_inputs(*start, *length)).await {
+                    Ok(Ok(Ok(data))) => {
+                        devices.tags_write(*unit, |tags| {
+                            tags.discrete_inputs.update(*start as usize, &data);
+                        })?;
+                    }
+                    Ok(Ok(Err(code))) => return Err(code.into()),
+                    Ok(Err(e)) => return Err(e.into()),
+                    Err(e) => return Err(e.into()),
                 }
             }
         }
-        Coils(changes) => {
-            for range in changes {
-                let start = range.start;
-                let length = range.end - range.start;
-                let data = devices.tags_read(unit, |tags| {
-                    tags.coils
-                        .get_array(|r| Vec::from(&r[start..start + length]))
-                })?;
+        Ok(())
+    }
 
-                if length == 1 {
-                    client.write_single_coil(start as u16, data[0]).await??;
-                } else {
-                    client.write_multiple_coils(start as u16, &data).await??;
+    fn push_range<F>(seq: &mut Vec<ClientOp>, range: &Range<u16>, max_len: u16, f: F)
+    where
+        F: Fn(u16, u16) -> ClientOp,
+    {
+        let mut start = range.start;
+        let mut length = range.end - range.start;
+        while start < range.end {
+            let op_len = length.min(max_len);
+            seq.push(f(start, op_len));
+            start += op_len;
+            length -= op_len;
+        }
+    }
+
+    // Ranges separated by at most max_gap unused addresses are read
+    // together, as long as the read isn't longer than max_len
+    fn push_ranges<F>(
+        seq: &mut Vec<ClientOp>,
+        ranges: &RangeArray<u16>,
+        max_gap: u16,
+        max_len: u16,
+        f: F,
+    ) where
+        F: Fn(u16, u16) -> ClientOp,
+    {
+        let mut pending: Option<Range<u16>> = None;
+        for range in ranges {
+            if let Some(merged) = &mut pending
+                && range.start - merged.end <= max_gap
+                && range.end - merged.start <= max_len
+            {
+                merged.end = range.end;
+            } else if let Some(merged) = pending.replace(range.clone()) {
+                Self::push_range(seq, &merged, max_len, &f);
+            }
+        }
+        if let Some(merged) = pending {
+            Self::push_range(seq, &merged, max_len, &f);
+        }
+    }
+
+    // Operations reading all ranges of a unit with the same poll setting
+    fn read_sequence(unit: u8, ranges: &PollRanges, sizes: &ReadSizes) -> Vec<ClientOp> {
+        let mut seq = Vec::new();
+        let gap = sizes.max_gap;
+        Self::push_ranges(
+            &mut seq,
+            &ranges.holding_registers,
+            gap,
+            sizes.max_registers,
+            |start, length| ClientOp::ReadHoldingRegisters(unit, start, length),
+        );
+        Self::push_ranges(
+            &mut seq,
+            &ranges.input_registers,
+            gap,
+            sizes.max_registers,
+            |start, length| ClientOp::ReadInputRegisters(unit, start, length),
+        );
+        Self::push_ranges(
+            &mut seq,
+            &ranges.coils,
+            gap,
+            sizes.max_bits,
+            |start, length| ClientOp::ReadCoils(unit, start, length),
+        );
+        Self::push_ranges(
+            &mut seq,
+            &ranges.discrete_inputs,
+            gap,
+            sizes.max_bits,
+            |start, length| ClientOp::ReadDiscreteInputs(unit, start, length),
+        );
+        seq
+    }
+}
+
+/// Sizes of the requests reading a unit
+struct ReadSizes {
+    max_gap: u16,
+    max_registers: u16,
+    max_bits: u16,
+}
+
+impl ReadSizes {
+    fn new(limits: &ReadLimits, default_gap: u16) -> Self {
+        ReadSizes {
+            max_gap: limits.max_gap.unwrap_or(default_gap),
+            max_registers: limits.max_registers.unwrap_or(READ_REGISTERS_MAX_LEN),
+            max_bits: limits.max_bits.unwrap_or(READ_BITS_MAX_LEN),
+        }
+    }
+}
+
+/// Operations reading the ranges of a unit with the same poll setting
+struct PollGroup {
+    unit: u8,
+    // None if only read on demand
+    interval: Option<Duration>,
+    ops: Vec<ClientOp>,
+    // Next operation of the current round
+    next_op: usize,
+    // When the current round is due, None if no round is pending
+    due: Option<Instant>,
+}
+
+/// Runs each poll group at its own interval. Operations are run one at a
+/// time from the group that has waited the longest, so groups with many
+/// operations don't hold up the others.
+struct PollSchedule {
+    groups: Vec<PollGroup>,
+    // Group of the last operation. Ties are resolved round-robin from here.
+    last: usize,
+}
+
+impl PollSchedule {
+    fn new(devices: &Devices, default_interval: Duration, default_gap: u16, now: Instant) -> Self {
+        let mut groups = Vec::new();
+        for unit in devices.units() {
+            let ranges = devices.ranges(unit).unwrap();
+            let sizes = ReadSizes::new(&devices.read_limits(unit).unwrap(), default_gap);
+            for (poll, ranges) in &ranges.polls {
+                let interval = match poll {
+                    Poll::Cyclic(interval) => Some(interval.unwrap_or(default_interval)),
+                    Poll::OnDemand => None,
+                    Poll::Never | Poll::Inherit => continue,
+                };
+                let ops = ClientOp::read_sequence(unit, ranges, &sizes);
+                if !ops.is_empty() {
+                    groups.push(PollGroup {
+                        unit,
+                        interval,
+                        ops,
+                        next_op: 0,
+                        due: Some(now),
+                    });
                 }
             }
         }
-        _ => {}
+        let last = groups.len().saturating_sub(1);
+        PollSchedule { groups, last }
+    }
+
+    /// Read the groups of a unit that are polled on demand
+    fn request(&mut self, unit: u8, now: Instant) {
+        for group in &mut self.groups {
+            if group.unit == unit && group.interval.is_none() && group.due.is_none() {
+                group.due = Some(now);
+            }
+        }
+    }
+
+    /// The next operation if one is due, otherwise when the next one is
+    /// due. None if nothing is scheduled.
+    fn next(&mut self, now: Instant) -> Result<&ClientOp, Option<Instant>> {
+        let count = self.groups.len();
+        let mut first: Option<(usize, Instant)> = None;
+        for index in (1..=count).map(|i| (self.last + i) % count) {
+            if let Some(due) = self.groups[index].due
+                && first.is_none_or(|(_, first_due)| due < first_due)
+            {
+                first = Some((index, due));
+            }
+        }
+        let Some((index, due)) = first else {
+            return Err(None);
+        };
+        if due > now {
+            return Err(Some(due));
+        }
+        self.last = index;
+        let group = &mut self.groups[index];
+        let op = group.next_op;
+        group.next_op += 1;
+        if group.next_op == group.ops.len() {
+            group.next_op = 0;
+            // Don't try to catch up after a round took longer than the
+            // interval
+            group.due = group.interval.map(|interval| (due + interval).max(now));
+        }
+        Ok(&group.ops[op])
+    }
+}
+
+// Read back written values, which then replace the values in the tags
+async fn verify_write(
+    op: &ClientOp,
+    client: &mut Context,
+    devices: &Devices,
+    timeout: Duration,
+) -> DynResult<()> {
+    let unit = op.unit();
+    let (read, written) = match op {
+        ClientOp::WriteHoldingRegisters(_, start, values) => (
+            ClientOp::ReadHoldingRegisters(unit, *start, values.len() as u16),
+            values.clone(),
+        ),
+        ClientOp::WriteCoils(_, start, values) => (
+            ClientOp::ReadCoils(unit, *start, values.len() as u16),
+            values.iter().map(|&b| b as u16).collect(),
+        ),
+        _ => return Ok(()),
+    };
+    read.execute(client, devices, timeout).await?;
+    let (table, range) = read.range();
+    let range = usize::from(range.start)..usize::from(range.end);
+    if devices.values(unit, table, range)? != written {
+        debug!("Unit {unit}: {read:?} returned other values than written");
+        return Err("Values read back differ from the values written".into());
     }
     Ok(())
 }
 
+/// Write values changed from the web interface to the unit they belong
+/// to
+async fn handle_write(
+    request: &WriteRequest,
+    client: &mut Context,
+    devices: &Devices,
+    options: &ModbusOptions,
+) -> DynResult<()> {
+    let unit = request.unit;
+    let length = u16::try_from(request.values.len()).ok();
+    let Some(end) = length.and_then(|length| request.start.checked_add(length)) else {
+        return Err("Write goes past the last address".into());
+    };
+    let range = request.start..end;
+    let values = |start: u16, length: u16| {
+        let offset = usize::from(start - request.start);
+        &request.values[offset..offset + usize::from(length)]
+    };
+    let mut seq = Vec::new();
+    match request.table {
+        Table::HoldingRegisters => ClientOp::push_range(
+            &mut seq,
+            &range,
+            WRITE_REGISTERS_MAX_LEN,
+            |start, length| {
+                ClientOp::WriteHoldingRegisters(unit, start, values(start, length).to_vec())
+            },
+        ),
+        Table::Coils => {
+            ClientOp::push_range(&mut seq, &range, WRITE_BITS_MAX_LEN, |start, length| {
+                let bits = values(start, length).iter().map(|&v| v != 0).collect();
+                ClientOp::WriteCoils(unit, start, bits)
+            })
+        }
+        Table::InputRegisters | Table::DiscreteInputs => {
+            return Err(devices::Error::ReadOnly(request.table).into());
+        }
+    }
+    // The tags hold the changed values, or the values of the remote unit
+    // if it has been polled since. Other values mean that the remote unit
+    // changed them after they were edited.
+    let range = usize::from(range.start)..usize::from(range.end);
+    let current = devices.values(unit, request.table, range)?;
+    let changed = current
+        .iter()
+        .zip(&request.values)
+        .zip(&request.previous)
+        .any(|((current, value), previous)| current != value && current != previous);
+    if changed {
+        return Err("Changed by the remote unit since edited".into());
+    }
+    for op in &seq {
+        let mut attempt = 0;
+        let res = loop {
+            let res = op.execute(client, devices, options.timeout).await;
+            if !retry(&res, &mut attempt, options.retries) {
+                break res;
+            }
+        };
+        record_status(devices, unit, &res, options.offline_after);
+        res?;
+        if options.verify_writes {
+            verify_write(op, client, devices, options.timeout).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Read all identification objects of a unit using stream access
+async fn read_identification(
+    client: &mut Context,
+    unit: u8,
+    timeout: Duration,
+) -> DynResult<DeviceIdentification> {
+    client.set_slave(Slave(unit));
+    let mut objects = BTreeMap::new();
+    let mut object_id = 0x00;
+    loop {
+        let resp = tokio::time::timeout(
+            timeout,
+            client.read_device_identification(ReadCode::Extended, object_id),
+        )
+        .await???;
+        for obj in resp.device_id_objects {
+            objects.insert(obj.id, String::from_utf8_lossy(&obj.value).into_owned());
+        }
+        if !resp.more_follows || resp.next_object_id <= object_id {
+            break;
+        }
+        object_id = resp.next_object_id;
+    }
+    Ok(DeviceIdentification { objects })
+}
+
+async fn scan_identification(client: &mut Context, devices: &Devices, timeout: Duration) {
+    for unit in devices.units() {
+        let remote_unit = devices.remote_unit(unit).unwrap_or(unit);
+        match read_identification(client, remote_unit, timeout).await {
+            Ok(ident) => {
+                for (id, value) in &ident.objects {
+                    info!(
+                        "Unit {unit}: {} (0x{id:02x}): {value}",
+                        DeviceIdentification::object_name(*id)
+                    );
+                }
+                if let Err(e) = devices.set_remote_identification(unit, ident) {
+                    error!("Failed to store device identification: {e}");
+                }
+            }
+            Err(e) => info!("Failed to read device identification from unit {unit}: {e}"),
+        }
+    }
+}
+
+// Keep track of which units answer. Exceptions count as answers.
+fn record_status(devices: &Devices, unit: u8, res: &DynResult<()>, offline_after: u32) {
+    let (responding, error) = match res {
+        Ok(()) => (true, None),
+        Err(e) => (
+            e.downcast_ref::<ExceptionCode>().is_some(),
+            Some(e.to_string()),
+        ),
+    };
+    if let Err(e) = devices.record_request(unit, responding, error, offline_after) {
+        error!("Failed to record status of unit {unit}: {e}");
+    }
+}
+
+// Whether an error means the connection is closed. tokio-modbus reports
+// it as a transport error.
+fn broken_pipe(e: &(dyn std::error::Error + Send + Sync + 'static)) -> bool {
+    let io_err = match e.downcast_ref::<tokio_modbus::Error>() {
+        Some(tokio_modbus::Error::Transport(e)) => Some(e),
+        _ => e.downcast_ref::<std::io::Error>(),
+    };
+    io_err.is_some_and(|e| e.kind() == std::io::ErrorKind::BrokenPipe)
+}
+
+// Whether to repeat a failed request. An exception is an answer and a
+// closed connection won't come back by repeating.
+fn retry(res: &DynResult<()>, attempt: &mut u32, retries: u32) -> bool {
+    let Err(e) = res else {
+        return false;
+    };
+    if *attempt >= retries || e.is::<ExceptionCode>() || broken_pipe(e.as_ref()) {
+        return false;
+    }
+    *attempt += 1;
+    debug!("Retrying request ({attempt} of {retries}): {e}");
+    true
+}
+
+// Keep track of which ranges are read successfully
+fn record_health(devices: &Devices, op: &ClientOp, res: &DynResult<()>) {
+    let result = match res {
+        Ok(()) => Ok(()),
+        Err(e) => Err(if let Some(code) = e.downcast_ref::<ExceptionCode>() {
+            RangeError::Exception(*code)
+        } else if e.is::<time::error::Elapsed>() {
+            RangeError::Timeout
+        } else {
+            RangeError::Io(e.to_string())
+        }),
+    };
+    let (table, range) = op.range();
+    if let Err(e) = devices.record_range(op.unit(), table, range, result) {
+        error!("Failed to record health of unit {}: {e}", op.unit());
+    }
+}
+
 async fn client_poll(
     client: &mut Context,
     devices: Devices,
     options: &ModbusOptions,
 ) -> DynResult<()> {
-    let seq = ClientOp::read_sequence(&devices);
-    let mut iter = seq.iter().cycle();
+    scan_identification(client, &devices, options.timeout).await;
+    let mut events = devices.subscribe();
+    let mut schedule = PollSchedule::new(
+        &devices,
+        options.poll_interval,
+        options.max_read_gap,
+        Instant::now(),
+    );
     loop {
-        let op = iter.next().unwrap();
-        if let Err(e) = op.execute(client, &devices).await {
-            error!("Failed to read from server: {e}");
-            if let Ok(io_err) = e.downcast::<std::io::Error>() {
-                if let std::io::ErrorKind::BrokenPipe = io_err.kind() {
-                    debug!("Error: {io_err:?}");
-                    return Err(io_err);
+        let due = match schedule.next(Instant::now()) {
+            Ok(op) => {
+                let mut attempt = 0;
+                let res = loop {
+                    let res = op.execute(client, &devices, options.timeout).await;
+                    if !retry(&res, &mut attempt, options.retries) {
+                        break res;
+                    }
+                };
+                record_status(&devices, op.unit(), &res, options.offline_after);
+                record_health(&devices, op, &res);
+                if let Err(e) = res {
+                    error!("Failed to read from unit {}: {e}", op.unit());
+                    if broken_pipe(e.as_ref()) {
+                        return Err(e);
+                    }
                 }
+                Some(Instant::now())
             }
-        }
+            Err(due) => due,
+        };
+        let wait = async {
+            match due {
+                Some(due) => time::sleep_until(due).await,
+                None => future::pending().await,
+            }
+        };
+        // Changed values are written before the next read
         tokio::select! {
-            _res = time::sleep(options.poll_interval) => (),
-            (unit, updated) = devices.updated() => {
-		if let Err(e) = handle_poll(unit, &updated, client, &devices).await {
-		    error!("Failed to send data to server: {e}");
-		}
+            biased;
+            event = events.recv() => match event {
+                Ok(Event::ReadRequested(unit)) => schedule.request(unit, Instant::now()),
+                Err(RecvError::Lagged(n)) => {
+                    error!("Missed {n} events, values may not have been read on demand");
+                }
+                _ => {}
+            },
+            request = devices.next_write() => {
+                let res = handle_write(&request, client, &devices, options).await;
+                if let Err(e) = &res {
+                    error!("Failed to write to unit {}: {e}", request.unit);
+                }
+                devices.report_write(WriteResult {
+                    request,
+                    error: res.err().map(|e| e.to_string()),
+                });
             }
+            _ = wait => (),
         }
     }
 }
 
-pub async fn client_rtu<T>(
+pub async fn client_rtu(
+    ser: SerialStream,
+    slave: Slave,
+    devices: Devices,
+    options: ModbusOptions,
+) -> DynResult<()> {
+    let timing = options.frame_timing(&ser)?;
+    let mut ctxt = rtu_frame::attach_slave(ser, slave, timing);
+    client_poll(&mut ctxt, devices, &options).await?;
+    Ok(())
+}
+
+pub async fn client_ascii<T>(
     ser: T,
     slave: Slave,
     devices: Devices,
     options: ModbusOptions,
 ) -> DynResult<()>
 where
-    T: AsyncRead + AsyncWrite + Debug + Unpin + Send + 'static,
+    T: AsyncRead + AsyncWrite + Send + 'static,
 {
-    let mut ctxt = rtu::attach_slave(ser, slave);
+    let mut ctxt = ascii::attach_slave(ser, slave);
     client_poll(&mut ctxt, devices, &options).await?;
     Ok(())
 }
 
+/// Delay between attempts to reconnect, doubled after each failure
+struct Backoff {
+    delay: Duration,
+    initial: Duration,
+    max: Duration,
+}
+
+impl Backoff {
+    fn new(options: &ModbusOptions) -> Self {
+        Backoff {
+            delay: options.reconnect_delay,
+            initial: options.reconnect_delay,
+            max: options.max_reconnect_delay,
+        }
+    }
+
+    /// Wait before reconnecting. Writes requested meanwhile fail.
+    async fn wait(&mut self, devices: &Devices) {
+        let sleep = time::sleep(self.delay);
+        tokio::pin!(sleep);
+        loop {
+            tokio::select! {
+                _ = &mut sleep => break,
+                request = devices.next_write() => {
+                    devices.report_write(WriteResult {
+                        request,
+                        error: Some("Not connected".to_string()),
+                    });
+                }
+            }
+        }
+        self.delay = self.delay.saturating_mul(2).min(self.max);
+    }
+
+    /// Start over after a successful connection
+    fn reset(&mut self) {
+        self.delay = self.initial;
+    }
+}
+
+/// Run a client, starting it again after a delay if it fails. The units
+/// it serves are marked as not responding meanwhile, so a connection
+/// that can't be set up doesn't stop clients using other connections.
+pub async fn client_restarting<F, Fut>(
+    name: &str,
+    devices: Devices,
+    options: ModbusOptions,
+    client: F,
+) -> DynResult<()>
+where
+    F: Fn(Devices, ModbusOptions) -> Fut,
+    Fut: Future<Output = DynResult<()>>,
+{
+    let mut backoff = Backoff::new(&options);
+    loop {
+        let started = Instant::now();
+        let res = client(devices.clone(), options.clone()).await;
+        let Err(e) = &res else {
+            return Ok(());
+        };
+        error!("{name}: {e}");
+        for unit in devices.units() {
+            record_status(&devices, unit, &res, options.offline_after);
+        }
+        // A client that ran for a while was connected, so don't keep
+        // the delay of earlier failures
+        if started.elapsed() >= options.max_reconnect_delay {
+            backoff.reset();
+        }
+        backoff.wait(&devices).await;
+    }
+}
+
+pub async fn client_rtu_over_tcp(
+    socket: SocketAddr,
+    slave: Slave,
+    devices: Devices,
+    options: ModbusOptions,
+) -> DynResult<()> {
+    let stream = TcpStream::connect(socket).await?;
+    let mut ctxt = rtu_frame::attach_slave(stream, slave, TCP_FRAME_TIMING);
+    client_poll(&mut ctxt, devices, &options).await
+}
+
+pub async fn client_udp(
+    socket: SocketAddr,
+    slave: Slave,
+    devices: Devices,
+    options: ModbusOptions,
+) -> DynResult<()> {
+    let mut ctxt = udp::connect_slave(socket, slave).await?;
+    client_poll(&mut ctxt, devices, &options).await?;
+    Ok(())
+}
+
+/// Poll devices over TLS. The certificate of the server is checked
+/// against `server_name`, or the address if not set.
+pub async fn client_tls(
+    socket: SocketAddr,
+    devices: Devices,
+    options: ModbusOptions,
+    connector: SslConnector,
+    server_name: Option<String>,
+) -> DynResult<()> {
+    let server_name = server_name.unwrap_or_else(|| socket.ip().to_string());
+    let stream = TcpStream::connect(socket).await?;
+    let stream = tls::connect(&connector, &server_name, stream)
+        .await
+        .map_err(|e| format!("TLS handshake with {socket} failed: {e}"))?;
+    let mut ctxt = tcp::attach_slave(stream, Slave(0));
+    client_poll(&mut ctxt, devices, &options).await
+}
+
 pub async fn client_tcp(
     socket: SocketAddr,
     devices: Devices,
     options: ModbusOptions,
 ) -> DynResult<()> {
-    loop {
-        match tcp::connect_slave(socket, Slave(0)).await {
-            Ok(mut ctxt) => {
-                if let Err(e) = client_poll(&mut ctxt, devices.clone(), &options).await {
-                    if let Ok(io_err) = e.downcast::<std::io::Error>() {
-                        if let std::io::ErrorKind::BrokenPipe = io_err.kind() {
-                        } else {
-                            break;
-                        }
-                    }
-                }
-            }
-            Err(_e) => {}
+    let mut ctxt = tcp::connect_slave(socket, Slave(0)).await?;
+    client_poll(&mut ctxt, devices, &options).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        ClientOp, FaultInjector, Gateway, LineFraming, ModbusOptions, ModbusService, PollSchedule,
+        ReadSizes, handle_write, record_health, record_status, retry, serve_line, serve_mbap,
+    };
+    use crate::ascii;
+    use crate::device_list::ResponseDelay;
+    use crate::device_list_xml::parse_device_list;
+    use crate::devices::{Devices, Event, Quality, RangeError, Table, WriteRequest};
+    use crate::diagnostics::Diagnostics;
+    use crate::error::DynResult;
+    use crate::rtu::{self, FrameTiming, encode_frame};
+    use crate::tag_list::Poll;
+    use roxmltree::Document;
+    use std::borrow::Cow;
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+    use tokio::time::{self, Duration, Instant};
+    use tokio_modbus::client::{Reader, Writer, tcp};
+    use tokio_modbus::prelude::{ConformityLevel, ReadCode, ReadDeviceIdentificationResponse};
+    use tokio_modbus::server::Service;
+    use tokio_modbus::slave::Slave;
+    use tokio_modbus::{ExceptionCode, Request, Response, SlaveRequest};
+
+    const LINE_TIMING: FrameTiming = FrameTiming {
+        gap: Duration::from_millis(5),
+        char_timeout: None,
+    };
+
+    const TIMEOUT: Duration = Duration::from_millis(500);
+
+    const DEVICES: &str = r#"
+<tag-list xmlns="http://www.elektro-kapsel.se/xml/modbus_config/v2">
+  <device addr="1">
+    <identification vendor-name="Fluffware" product-code="MB-1" revision="1.2"
+                    product-name="mb-tool">
+      <object id="0x80" value="Extended"/>
+    </identification>
+    <holding-registers>
+      <register addr="0" initial-value="0x1234"/>
+      <register-range addr-low="1" addr-high="3" initial-value="0x000100020003"/>
+      <register addr="4" initial-value="0x4444" access="read-only"/>
+      <register addr="5" access="write-only"/>
+    </holding-registers>
+    <coils>
+      <bit addr="0" initial-value="1" access="read-only"/>
+      <bit addr="1"/>
+    </coils>
+    <fifo-queues>
+      <fifo addr="0x100" initial-value="0x11 0x22 0x33"/>
+    </fifo-queues>
+    <files>
+      <file number="4">
+        <register-range addr-low="1" addr-high="2" initial-value="0x0a0b0c0d"/>
+      </file>
+    </files>
+  </device>
+</tag-list>
+"#;
+
+    fn options(verify_writes: bool) -> ModbusOptions {
+        ModbusOptions {
+            poll_interval: Duration::from_secs(1),
+            strict: false,
+            frame_gap: None,
+            char_timeout: None,
+            timeout: TIMEOUT,
+            retries: 0,
+            reconnect_delay: Duration::from_secs(1),
+            max_reconnect_delay: Duration::from_secs(1),
+            offline_after: 3,
+            max_read_gap: 0,
+            verify_writes,
+        }
+    }
+
+    fn devices(xml: &str) -> Devices {
+        let doc = Document::parse(xml).unwrap();
+        let device_list = parse_device_list(&doc.root_element()).unwrap();
+        Devices::new(&device_list)
+    }
+
+    async fn call(
+        service: &ModbusService,
+        request: Request<'static>,
+    ) -> Result<Response, tokio_modbus::ExceptionCode> {
+        service.call(SlaveRequest { slave: 1, request }).await
+    }
+
+    #[tokio::test]
+    async fn mask_write_test() {
+        let service = ModbusService::new(devices(DEVICES));
+        assert_eq!(
+            call(&service, Request::MaskWriteRegister(0, 0xff0f, 0x00a5)).await,
+            Ok(Response::MaskWriteRegister(0, 0xff0f, 0x00a5))
+        );
+        assert_eq!(
+            call(&service, Request::ReadHoldingRegisters(0, 1)).await,
+            Ok(Response::ReadHoldingRegisters(vec![0x12a4]))
+        );
+    }
+
+    #[tokio::test]
+    async fn read_write_test() {
+        let service = ModbusService::new(devices(DEVICES));
+        assert_eq!(
+            call(
+                &service,
+                Request::ReadWriteMultipleRegisters(1, 3, 2, Cow::Owned(vec![7, 8]))
+            )
+            .await,
+            Ok(Response::ReadWriteMultipleRegisters(vec![1, 7, 8]))
+        );
+        assert_eq!(
+            call(
+                &service,
+                Request::ReadWriteMultipleRegisters(65535, 2, 0, Cow::Owned(vec![0]))
+            )
+            .await,
+            Err(tokio_modbus::ExceptionCode::IllegalDataAddress)
+        );
+    }
+
+    #[tokio::test]
+    async fn read_only_test() {
+        let service = ModbusService::new(devices(DEVICES)).read_only(true);
+        assert_eq!(
+            call(&service, Request::WriteSingleRegister(0, 1)).await,
+            Err(ExceptionCode::IllegalFunction)
+        );
+        assert_eq!(
+            call(
+                &service,
+                Request::ReadWriteMultipleRegisters(0, 1, 1, Cow::Owned(vec![1]))
+            )
+            .await,
+            Err(ExceptionCode::IllegalFunction)
+        );
+        assert_eq!(
+            call(
+                &service,
+                Request::Custom(0x15, Cow::Owned(vec![9, 6, 0, 1, 0, 0, 0, 1, 0, 0]))
+            )
+            .await,
+            Err(ExceptionCode::IllegalFunction)
+        );
+        assert_eq!(
+            call(&service, Request::ReadHoldingRegisters(0, 1)).await,
+            Ok(Response::ReadHoldingRegisters(vec![0x1234]))
+        );
+    }
+
+    #[tokio::test]
+    async fn access_test() {
+        use tokio_modbus::ExceptionCode::IllegalDataAddress;
+        let service = ModbusService::new(devices(DEVICES));
+        assert_eq!(
+            call(&service, Request::WriteSingleRegister(4, 1)).await,
+            Err(IllegalDataAddress)
+        );
+        assert_eq!(
+            call(
+                &service,
+                Request::WriteMultipleRegisters(3, Cow::Owned(vec![1, 2]))
+            )
+            .await,
+            Err(IllegalDataAddress)
+        );
+        assert_eq!(
+            call(&service, Request::MaskWriteRegister(4, 0, 0)).await,
+            Err(IllegalDataAddress)
+        );
+        assert_eq!(
+            call(&service, Request::ReadHoldingRegisters(4, 1)).await,
+            Ok(Response::ReadHoldingRegisters(vec![0x4444]))
+        );
+        assert_eq!(
+            call(&service, Request::WriteSingleRegister(5, 7)).await,
+            Ok(Response::WriteSingleRegister(5, 7))
+        );
+        assert_eq!(
+            call(&service, Request::ReadHoldingRegisters(3, 3)).await,
+            Err(IllegalDataAddress)
+        );
+        assert_eq!(
+            call(
+                &service,
+                Request::ReadWriteMultipleRegisters(0, 2, 4, Cow::Owned(vec![0]))
+            )
+            .await,
+            Err(IllegalDataAddress)
+        );
+        assert_eq!(
+            call(&service, Request::WriteSingleCoil(0, false)).await,
+            Err(IllegalDataAddress)
+        );
+        assert_eq!(
+            call(
+                &service,
+                Request::WriteMultipleCoils(0, Cow::Owned(vec![false, true]))
+            )
+            .await,
+            Err(IllegalDataAddress)
+        );
+        assert_eq!(
+            call(&service, Request::WriteSingleCoil(1, true)).await,
+            Ok(Response::WriteSingleCoil(1, true))
+        );
+        assert_eq!(
+            call(&service, Request::ReadCoils(0, 2)).await,
+            Ok(Response::ReadCoils(vec![true, true]))
+        );
+    }
+
+    // Tags at the end of the address space
+    const LAST_ADDRESS_DEVICES: &str = r#"
+<tag-list xmlns="http://www.elektro-kapsel.se/xml/modbus_config/v2">
+  <device addr="1">
+    <holding-registers>
+      <register addr="65533" initial-value="7"/>
+      <register addr="65534" access="write-only"/>
+    </holding-registers>
+  </device>
+</tag-list>
+"#;
+
+    #[tokio::test]
+    async fn last_address_test() {
+        use tokio_modbus::ExceptionCode::IllegalDataAddress;
+        let service = ModbusService::new(devices(LAST_ADDRESS_DEVICES));
+        assert_eq!(
+            call(&service, Request::ReadHoldingRegisters(65535, 1)).await,
+            Ok(Response::ReadHoldingRegisters(vec![0]))
+        );
+        assert_eq!(
+            call(&service, Request::WriteSingleRegister(65535, 3)).await,
+            Ok(Response::WriteSingleRegister(65535, 3))
+        );
+        assert_eq!(
+            call(&service, Request::ReadHoldingRegisters(65533, 3)).await,
+            Err(IllegalDataAddress)
+        );
+        assert_eq!(
+            call(&service, Request::ReadHoldingRegisters(65535, 2)).await,
+            Err(IllegalDataAddress)
+        );
+        assert_eq!(
+            call(
+                &service,
+                Request::WriteMultipleRegisters(65535, Cow::Owned(vec![1, 2]))
+            )
+            .await,
+            Err(IllegalDataAddress)
+        );
+        assert_eq!(
+            call(&service, Request::ReadCoils(65000, 2000)).await,
+            Err(IllegalDataAddress)
+        );
+    }
+
+    #[tokio::test]
+    async fn strict_test() {
+        use tokio_modbus::ExceptionCode::IllegalDataAddress;
+        let service = ModbusService::new(devices(DEVICES));
+        assert_eq!(
+            call(&service, Request::ReadInputRegisters(0, 2)).await,
+            Ok(Response::ReadInputRegisters(vec![0, 0]))
+        );
+        let service = service.strict(true);
+        assert_eq!(
+            call(&service, Request::ReadInputRegisters(0, 2)).await,
+            Err(IllegalDataAddress)
+        );
+        assert_eq!(
+            call(&service, Request::ReadHoldingRegisters(0, 5)).await,
+            Ok(Response::ReadHoldingRegisters(vec![
+                0x1234, 1, 2, 3, 0x4444
+            ]))
+        );
+        assert_eq!(
+            call(&service, Request::ReadHoldingRegisters(3, 4)).await,
+            Err(IllegalDataAddress)
+        );
+        assert_eq!(
+            call(&service, Request::WriteSingleRegister(6, 1)).await,
+            Err(IllegalDataAddress)
+        );
+        assert_eq!(
+            call(&service, Request::WriteSingleCoil(1, true)).await,
+            Ok(Response::WriteSingleCoil(1, true))
+        );
+        assert_eq!(
+            call(
+                &service,
+                Request::WriteMultipleCoils(1, Cow::Owned(vec![false, true]))
+            )
+            .await,
+            Err(IllegalDataAddress)
+        );
+
+        let service = ModbusService::new(devices(LAST_ADDRESS_DEVICES)).strict(true);
+        assert_eq!(
+            call(&service, Request::ReadHoldingRegisters(65533, 1)).await,
+            Ok(Response::ReadHoldingRegisters(vec![7]))
+        );
+        assert_eq!(
+            call(&service, Request::WriteSingleRegister(65534, 1)).await,
+            Ok(Response::WriteSingleRegister(65534, 1))
+        );
+        assert_eq!(
+            call(&service, Request::ReadHoldingRegisters(65535, 1)).await,
+            Err(IllegalDataAddress)
+        );
+        assert_eq!(
+            call(
+                &service,
+                Request::WriteMultipleRegisters(65534, Cow::Owned(vec![1, 2]))
+            )
+            .await,
+            Err(IllegalDataAddress)
+        );
+        assert_eq!(
+            call(&service, Request::WriteSingleRegister(65535, 1)).await,
+            Err(IllegalDataAddress)
+        );
+    }
+
+    async fn read_device_id(
+        service: &ModbusService,
+        read_code: ReadCode,
+        object_id: u8,
+    ) -> ReadDeviceIdentificationResponse {
+        match call(
+            service,
+            Request::ReadDeviceIdentification(read_code, object_id),
+        )
+        .await
+        {
+            Ok(Response::ReadDeviceIdentification(resp)) => resp,
+            r => panic!("Unexpected response: {r:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn device_id_test() {
+        let service = ModbusService::new(devices(DEVICES));
+        let resp = read_device_id(&service, ReadCode::Basic, 0).await;
+        assert_eq!(
+            resp.conformity_level,
+            ConformityLevel::ExtendedIdentification
+        );
+        assert!(!resp.more_follows);
+        let ids: Vec<u8> = resp.device_id_objects.iter().map(|o| o.id).collect();
+        assert_eq!(ids, [0x00, 0x01, 0x02]);
+        assert_eq!(resp.device_id_objects[0].value_as_str(), Some("Fluffware"));
+
+        // Unknown objects restart the stream from the beginning
+        let resp = read_device_id(&service, ReadCode::Regular, 0x05).await;
+        let ids: Vec<u8> = resp.device_id_objects.iter().map(|o| o.id).collect();
+        assert_eq!(ids, [0x00, 0x01, 0x02, 0x04]);
+
+        let resp = read_device_id(&service, ReadCode::Extended, 0x02).await;
+        let ids: Vec<u8> = resp.device_id_objects.iter().map(|o| o.id).collect();
+        assert_eq!(ids, [0x02, 0x04, 0x80]);
+
+        let resp = read_device_id(&service, ReadCode::Specific, 0x80).await;
+        assert_eq!(resp.device_id_objects[0].value_as_str(), Some("Extended"));
+        assert_eq!(
+            call(
+                &service,
+                Request::ReadDeviceIdentification(ReadCode::Specific, 0x03)
+            )
+            .await,
+            Err(tokio_modbus::ExceptionCode::IllegalDataAddress)
+        );
+    }
+
+    async fn custom(
+        service: &ModbusService,
+        function: u8,
+        data: &[u8],
+    ) -> Result<Vec<u8>, ExceptionCode> {
+        let req = Request::Custom(function, Cow::Owned(data.to_vec()));
+        match call(service, req).await {
+            Ok(Response::Custom(f, data)) if f == function => Ok(data.to_vec()),
+            Ok(r) => panic!("Unexpected response {r:?}"),
+            Err(e) => Err(e),
+        }
+    }
+
+    #[tokio::test]
+    async fn fifo_test() {
+        let service = ModbusService::new(devices(DEVICES));
+        assert_eq!(
+            custom(&service, 0x18, &[0x01, 0x00]).await,
+            Ok(vec![0, 8, 0, 3, 0, 0x11, 0, 0x22, 0, 0x33])
+        );
+        assert_eq!(
+            custom(&service, 0x18, &[0x01, 0x01]).await,
+            Err(ExceptionCode::IllegalDataAddress)
+        );
+    }
+
+    #[tokio::test]
+    async fn file_record_test() {
+        let service = ModbusService::new(devices(DEVICES));
+        // Read records 0-2 and 2 of file 4
+        let read = [14, 6, 0, 4, 0, 0, 0, 3, 6, 0, 4, 0, 2, 0, 1];
+        assert_eq!(
+            custom(&service, 0x14, &read).await,
+            Ok(vec![
+                12, 7, 6, 0, 0, 0x0a, 0x0b, 0x0c, 0x0d, 3, 6, 0x0c, 0x0d
+            ])
+        );
+        let write = [11, 6, 0, 4, 0x27, 0x0e, 0, 2, 0x12, 0x34, 0x56, 0x78];
+        assert_eq!(custom(&service, 0x15, &write).await, Ok(write.to_vec()));
+        assert_eq!(
+            custom(&service, 0x14, &[7, 6, 0, 4, 0x27, 0x0e, 0, 2]).await,
+            Ok(vec![6, 5, 6, 0x12, 0x34, 0x56, 0x78])
+        );
+        // Past the last record
+        assert_eq!(
+            custom(&service, 0x14, &[7, 6, 0, 4, 0x27, 0x0f, 0, 2]).await,
+            Err(ExceptionCode::IllegalDataAddress)
+        );
+        // Missing file
+        assert_eq!(
+            custom(&service, 0x14, &[7, 6, 0, 5, 0, 0, 0, 1]).await,
+            Err(ExceptionCode::IllegalDataAddress)
+        );
+        // Data shorter than the record length
+        assert_eq!(
+            custom(&service, 0x15, &[10, 6, 0, 4, 0, 0, 0, 2, 0x12, 0x34, 0x56]).await,
+            Err(ExceptionCode::IllegalDataValue)
+        );
+    }
+
+    // Send a request frame and return the reply PDU, if any
+    async fn rtu_request(line: &mut DuplexStream, unit: u8, pdu: &[u8]) -> Option<Vec<u8>> {
+        line.write_all(&encode_frame(unit, pdu)).await.unwrap();
+        let mut buf = [0u8; 256];
+        let n = time::timeout(Duration::from_millis(100), line.read(&mut buf))
+            .await
+            .ok()?
+            .unwrap();
+        assert_eq!(buf[0], unit);
+        assert_eq!(encode_frame(unit, &buf[1..n - 2]), &buf[..n]);
+        Some(buf[1..n - 2].to_vec())
+    }
+
+    #[tokio::test]
+    async fn response_delay_test() {
+        let devices = devices(
+            r#"
+<tag-list xmlns="http://www.elektro-kapsel.se/xml/modbus_config/v2">
+  <device addr="1" response-delay="40-60"/>
+</tag-list>
+"#,
+        );
+        let service = ModbusService::new(devices.clone());
+        let start = time::Instant::now();
+        assert_eq!(
+            call(&service, Request::ReadHoldingRegisters(0, 1)).await,
+            Ok(Response::ReadHoldingRegisters(vec![0]))
+        );
+        assert!(start.elapsed() >= Duration::from_millis(40));
+        devices
+            .set_response_delay(1, ResponseDelay::default())
+            .unwrap();
+        let start = time::Instant::now();
+        call(&service, Request::ReadHoldingRegisters(0, 1))
+            .await
+            .unwrap();
+        assert!(start.elapsed() < Duration::from_millis(40));
+    }
+
+    const FAULTS: &str = r#"
+<tag-list xmlns="http://www.elektro-kapsel.se/xml/modbus_config/v2">
+  <device addr="1">
+    <faults>
+      <exception function="3" addr-low="2" addr-high="3" code="6"/>
+      <drop-response function="6"/>
+      <corrupt-crc function="4" enabled="false"/>
+    </faults>
+    <holding-registers>
+      <register-range addr-low="0" addr-high="3" initial-value="0x0001000200030004"/>
+    </holding-registers>
+  </device>
+</tag-list>
+"#;
+
+    #[tokio::test]
+    async fn fault_injection_test() {
+        let devices = devices(FAULTS);
+        let diag = Arc::new(Diagnostics::new(devices.units()));
+        let service = ModbusService::with_diagnostics(devices.clone(), diag.clone());
+        let injector = FaultInjector::new(service);
+        let call = |request| injector.call(SlaveRequest { slave: 1, request });
+        assert_eq!(
+            call(Request::ReadHoldingRegisters(0, 2)).await,
+            Ok(Some(Response::ReadHoldingRegisters(vec![1, 2])))
+        );
+        assert_eq!(
+            call(Request::ReadHoldingRegisters(1, 2)).await,
+            Err(ExceptionCode::ServerDeviceBusy)
+        );
+        // The write is done even though there's no response
+        assert_eq!(call(Request::WriteSingleRegister(0, 7)).await, Ok(None));
+        devices.set_fault_enabled(1, 0, false).unwrap();
+        assert_eq!(
+            call(Request::ReadHoldingRegisters(0, 2)).await,
+            Ok(Some(Response::ReadHoldingRegisters(vec![7, 2])))
+        );
+
+        devices.set_fault_enabled(1, 2, true).unwrap();
+        let (mut line, server) = tokio::io::duplex(1024);
+        tokio::spawn(serve_line(
+            server,
+            LineFraming::Rtu(LINE_TIMING),
+            injector.clone(),
+        ));
+        line.write_all(&encode_frame(1, &[0x04, 0, 0, 0, 1]))
+            .await
+            .unwrap();
+        let mut buf = [0u8; 256];
+        let n = line.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..3], &[1, 0x04, 2]);
+        assert_ne!(encode_frame(1, &buf[1..n - 2]), &buf[..n]);
+
+        // Busy exceptions sent on the line are counted
+        devices.set_fault_enabled(1, 0, true).unwrap();
+        assert_eq!(
+            rtu_request(&mut line, 1, &[0x03, 0, 1, 0, 2]).await,
+            Some(vec![0x83, 0x06])
+        );
+        let counters = diag.counters(1).unwrap();
+        assert_eq!((counters.bus_exception, counters.server_busy), (1, 1));
+    }
+
+    #[tokio::test]
+    async fn mbap_test() {
+        let devices = devices(FAULTS);
+        let injector = FaultInjector::new(ModbusService::new(devices));
+        let (client, server) = tokio::io::duplex(1024);
+        let server = tokio::spawn(serve_mbap(server, injector.clone()));
+        let mut ctxt = tcp::attach_slave(client, Slave(1));
+        assert_eq!(
+            ctxt.read_holding_registers(0, 2).await.unwrap(),
+            Ok(vec![1, 2])
+        );
+        assert_eq!(
+            ctxt.read_holding_registers(1, 2).await.unwrap(),
+            Err(ExceptionCode::ServerDeviceBusy)
+        );
+        drop(ctxt);
+        assert!(server.await.unwrap().is_ok());
+
+        // The length must cover the unit and a function code
+        let (mut client, server) = tokio::io::duplex(1024);
+        let server = tokio::spawn(serve_mbap(server, injector));
+        client.write_all(&[0, 1, 0, 0, 0, 1, 1]).await.unwrap();
+        assert!(server.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn serial_diagnostics_test() {
+        let devices = devices(DEVICES);
+        let diag = Arc::new(Diagnostics::new(devices.units()));
+        let service = ModbusService::with_diagnostics(devices, diag.clone());
+        let (mut line, server) = tokio::io::duplex(1024);
+        tokio::spawn(serve_line(
+            server,
+            LineFraming::Rtu(LINE_TIMING),
+            FaultInjector::new(service),
+        ));
+
+        // Return query data
+        assert_eq!(
+            rtu_request(&mut line, 1, &[0x08, 0x00, 0x00, 0xa5, 0x37, 0x01]).await,
+            Some(vec![0x08, 0x00, 0x00, 0xa5, 0x37, 0x01])
+        );
+        // Request for another unit on the bus
+        assert_eq!(rtu_request(&mut line, 7, &[0x03, 0, 0, 0, 1]).await, None);
+        // CRC error
+        line.write_all(&[1, 0x03, 0, 0, 0, 1, 0, 0]).await.unwrap();
+        time::sleep(Duration::from_millis(20)).await;
+        // Exception
+        assert_eq!(
+            rtu_request(&mut line, 1, &[0x07]).await,
+            Some(vec![0x87, 0x01])
+        );
+        // Bus message count
+        assert_eq!(
+            rtu_request(&mut line, 1, &[0x08, 0x00, 0x0b, 0, 0]).await,
+            Some(vec![0x08, 0x00, 0x0b, 0, 4])
+        );
+        // Bus communication error count
+        assert_eq!(
+            rtu_request(&mut line, 1, &[0x08, 0x00, 0x0c, 0, 0]).await,
+            Some(vec![0x08, 0x00, 0x0c, 0, 1])
+        );
+        // Bus exception error count
+        assert_eq!(
+            rtu_request(&mut line, 1, &[0x08, 0x00, 0x0d, 0, 0]).await,
+            Some(vec![0x08, 0x00, 0x0d, 0, 1])
+        );
+        // Server message count
+        assert_eq!(
+            rtu_request(&mut line, 1, &[0x08, 0x00, 0x0e, 0, 0]).await,
+            Some(vec![0x08, 0x00, 0x0e, 0, 6])
+        );
+        // Comm event counter counts successful requests, five so far
+        assert_eq!(
+            rtu_request(&mut line, 1, &[0x0b]).await,
+            Some(vec![0x0b, 0, 0, 0, 5])
+        );
+        assert_eq!(
+            rtu_request(&mut line, 1, &[0x0c]).await,
+            Some(
+                [
+                    &[0x0c, 6 + 16, 0, 0, 0, 5, 0, 9][..],
+                    &[0x80, 0x40, 0x80, 0x40, 0x80, 0x40, 0x80, 0x40, 0x80],
+                    &[0x40, 0x80, 0x41, 0x80, 0x82, 0x40, 0x80]
+                ]
+                .concat()
+            )
+        );
+        // Listen only mode
+        assert_eq!(
+            rtu_request(&mut line, 1, &[0x08, 0x00, 0x04, 0, 0]).await,
+            None
+        );
+        assert_eq!(rtu_request(&mut line, 1, &[0x03, 0, 0, 0, 1]).await, None);
+        // Neither request was answered
+        assert_eq!(diag.counters(1).unwrap().server_no_response, 2);
+        assert_eq!(
+            rtu_request(&mut line, 1, &[0x08, 0x00, 0x01, 0, 0]).await,
+            None
+        );
+        assert_eq!(
+            rtu_request(&mut line, 1, &[0x03, 0, 0, 0, 1]).await,
+            Some(vec![0x03, 2, 0x12, 0x34])
+        );
+        // Counters were cleared by the restart
+        assert_eq!(
+            rtu_request(&mut line, 1, &[0x08, 0x00, 0x0e, 0, 0]).await,
+            Some(vec![0x08, 0x00, 0x0e, 0, 2])
+        );
+        // The restart request itself was not answered
+        assert_eq!(
+            rtu_request(&mut line, 1, &[0x08, 0x00, 0x0f, 0, 0]).await,
+            Some(vec![0x08, 0x00, 0x0f, 0, 1])
+        );
+        // The comm event counter restarted too
+        assert_eq!(
+            rtu_request(&mut line, 1, &[0x0b]).await,
+            Some(vec![0x0b, 0, 0, 0, 4])
+        );
+    }
+
+    #[tokio::test]
+    async fn ascii_test() {
+        let devices = devices(DEVICES);
+        let diag = Arc::new(Diagnostics::new(devices.units()));
+        let service = ModbusService::with_diagnostics(devices, diag);
+        let (mut line, server) = tokio::io::duplex(1024);
+        tokio::spawn(serve_line(
+            server,
+            LineFraming::Ascii,
+            FaultInjector::new(service.clone()),
+        ));
+        line.write_all(b":010300000002FA\r\n").await.unwrap();
+        let mut buf = [0u8; 256];
+        let n = line.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b":01030412340001B1\r\n");
+
+        let (client, server) = tokio::io::duplex(1024);
+        tokio::spawn(serve_line(
+            server,
+            LineFraming::Ascii,
+            FaultInjector::new(service),
+        ));
+        let mut ctxt = ascii::attach_slave(client, Slave(1));
+        assert_eq!(
+            ctxt.read_holding_registers(1, 3).await.unwrap(),
+            Ok(vec![1, 2, 3])
+        );
+        // Register 4 is read-only
+        assert_eq!(
+            ctxt.write_single_register(4, 1).await.unwrap(),
+            Err(ExceptionCode::IllegalDataAddress)
+        );
+    }
+
+    #[tokio::test]
+    async fn rtu_client_test() {
+        let devices = devices(DEVICES);
+        let diag = Arc::new(Diagnostics::new(devices.units()));
+        let service = ModbusService::with_diagnostics(devices, diag);
+        let (client, server) = tokio::io::duplex(1024);
+        tokio::spawn(serve_line(
+            server,
+            LineFraming::Rtu(LINE_TIMING),
+            FaultInjector::new(service),
+        ));
+        let mut ctxt = rtu::attach_slave(client, Slave(1), LINE_TIMING);
+        assert_eq!(
+            ctxt.read_holding_registers(1, 3).await.unwrap(),
+            Ok(vec![1, 2, 3])
+        );
+        assert_eq!(
+            ctxt.write_single_register(4, 1).await.unwrap(),
+            Err(ExceptionCode::IllegalDataAddress)
+        );
+    }
+
+    const BUS: &str = r#"
+<tag-list xmlns="http://www.elektro-kapsel.se/xml/modbus_config/v2">
+  <device addr="1">
+    <holding-registers>
+      <register addr="0" initial-value="1"/>
+    </holding-registers>
+  </device>
+  <device addr="2">
+    <holding-registers>
+      <register addr="0" initial-value="2"/>
+    </holding-registers>
+  </device>
+</tag-list>
+"#;
+
+    const BUS_CLIENT: &str = r#"
+<tag-list xmlns="http://www.elektro-kapsel.se/xml/modbus_config/v2">
+  <device addr="1">
+    <holding-registers>
+      <register addr="0"/>
+    </holding-registers>
+  </device>
+  <device addr="2">
+    <holding-registers>
+      <register addr="0"/>
+    </holding-registers>
+  </device>
+  <device addr="3">
+    <holding-registers>
+      <register addr="0"/>
+    </holding-registers>
+  </device>
+</tag-list>
+"#;
+
+    #[tokio::test]
+    async fn multi_unit_test() {
+        let server_devices = devices(BUS);
+        let diag = Arc::new(Diagnostics::new(server_devices.units()));
+        let service = ModbusService::with_diagnostics(server_devices.clone(), diag);
+        let (client, server) = tokio::io::duplex(1024);
+        tokio::spawn(serve_line(
+            server,
+            LineFraming::Rtu(LINE_TIMING),
+            FaultInjector::new(service),
+        ));
+        let devices = devices(BUS_CLIENT);
+        let mut ctxt = rtu::attach_slave(client, Slave(1), LINE_TIMING);
+        let now = Instant::now();
+        let mut schedule = PollSchedule::new(&devices, Duration::from_secs(1), 0, now);
+        while let Ok(op) = schedule.next(now) {
+            let res = op.execute(&mut ctxt, &devices, TIMEOUT).await;
+            record_status(&devices, op.unit(), &res, 3);
+            record_health(&devices, op, &res);
+        }
+        let holding = |devices: &Devices, unit| {
+            devices
+                .tags_read(unit, |tags| tags.holding_registers.get_array(|r| r[0]))
+                .unwrap()
+        };
+        assert_eq!(holding(&devices, 1), 1);
+        assert_eq!(holding(&devices, 2), 2);
+        assert_eq!(devices.unit_status(2).unwrap().responding, Some(true));
+        // Unit 3 isn't on the bus
+        let status = devices.unit_status(3).unwrap();
+        assert_eq!((status.responding, status.failures), (Some(false), 1));
+        let health = devices.range_health(2).unwrap();
+        assert_eq!(health.len(), 1);
+        assert_eq!(
+            (health[0].table, health[0].start, health[0].length),
+            (Table::HoldingRegisters, 0, 1)
+        );
+        assert_eq!(health[0].quality, Quality::Good);
+        assert!(health[0].last_success.is_some());
+        let health = devices.range_health(3).unwrap();
+        assert_eq!(health[0].quality, Quality::Bad);
+        assert_eq!(health[0].last_error, Some(RangeError::Timeout));
+
+        // Writes go to the unit the value belongs to
+        let mut request = WriteRequest {
+            origin: None,
+            unit: 2,
+            table: Table::HoldingRegisters,
+            start: 0,
+            values: vec![7],
+            previous: vec![2],
         };
-        tokio::time::sleep(Duration::from_secs(2)).await;
+        handle_write(&request, &mut ctxt, &devices, &options(true))
+            .await
+            .unwrap();
+        assert_eq!(holding(&server_devices, 1), 1);
+        assert_eq!(holding(&server_devices, 2), 7);
+        assert_eq!(devices.unit_status(2).unwrap().last_error, None);
+
+        request.table = Table::InputRegisters;
+        let err = handle_write(&request, &mut ctxt, &devices, &options(false))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Input registers are read only in client mode"
+        );
+    }
+
+    #[tokio::test]
+    async fn poll_write_test() {
+        let server_devices = devices(BUS);
+        let diag = Arc::new(Diagnostics::new(server_devices.units()));
+        let service = ModbusService::with_diagnostics(server_devices.clone(), diag);
+        let (client, server) = tokio::io::duplex(1024);
+        tokio::spawn(serve_line(
+            server,
+            LineFraming::Rtu(LINE_TIMING),
+            FaultInjector::new(service),
+        ));
+        let devices = devices(BUS_CLIENT).select(&[1]);
+        let mut ctxt = rtu::attach_slave(client, Slave(1), LINE_TIMING);
+        let holding = |devices: &Devices| {
+            devices
+                .tags_read(1, |tags| tags.holding_registers.get_array(|r| r[0]))
+                .unwrap()
+        };
+        let poll = ClientOp::ReadHoldingRegisters(1, 0, 1);
+        poll.execute(&mut ctxt, &devices, TIMEOUT).await.unwrap();
+        assert_eq!(holding(&devices), 1);
+
+        // A poll between the change and the write puts the value of the
+        // remote unit back in the tags, but the changed value is written
+        devices
+            .write_values(None, 1, Table::HoldingRegisters, 0, &[9])
+            .unwrap();
+        assert_eq!(holding(&devices), 9);
+        poll.execute(&mut ctxt, &devices, TIMEOUT).await.unwrap();
+        assert_eq!(holding(&devices), 1);
+        let request = devices.next_write().await;
+        assert_eq!(
+            (request.values.clone(), request.previous.clone()),
+            (vec![9], vec![1])
+        );
+        handle_write(&request, &mut ctxt, &devices, &options(false))
+            .await
+            .unwrap();
+        assert_eq!(holding(&server_devices), 9);
+        assert_eq!(holding(&devices), 9);
+
+        // The remote unit changes the value before the change is written
+        devices
+            .write_values(None, 1, Table::HoldingRegisters, 0, &[5])
+            .unwrap();
+        server_devices
+            .tags_write(1, |tags| tags.holding_registers.update(0, &[3]))
+            .unwrap();
+        poll.execute(&mut ctxt, &devices, TIMEOUT).await.unwrap();
+        let request = devices.next_write().await;
+        let err = handle_write(&request, &mut ctxt, &devices, &options(false))
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Changed by the remote unit since edited");
+        assert_eq!(holding(&server_devices), 3);
+        assert_eq!(holding(&devices), 3);
+
+        // Tables the client can't write are rejected before being changed
+        let err = devices
+            .write_values(None, 1, Table::DiscreteInputs, 0, &[1])
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Discrete inputs are read only in client mode"
+        );
+    }
+
+    #[test]
+    fn retry_test() {
+        let timed_out: DynResult<()> =
+            Err(std::io::Error::from(std::io::ErrorKind::TimedOut).into());
+        let mut attempt = 0;
+        assert!(retry(&timed_out, &mut attempt, 2));
+        assert!(retry(&timed_out, &mut attempt, 2));
+        assert!(!retry(&timed_out, &mut attempt, 2));
+        assert_eq!(attempt, 2);
+
+        let mut attempt = 0;
+        assert!(!retry(&Ok(()), &mut attempt, 2));
+        assert!(!retry(
+            &Err(ExceptionCode::IllegalDataAddress.into()),
+            &mut attempt,
+            2
+        ));
+        let broken: DynResult<()> =
+            Err(std::io::Error::from(std::io::ErrorKind::BrokenPipe).into());
+        assert!(!retry(&broken, &mut attempt, 2));
+        // As returned by the tokio-modbus clients
+        let closed = std::io::Error::from(std::io::ErrorKind::BrokenPipe);
+        let broken: DynResult<()> = Err(tokio_modbus::Error::Transport(closed).into());
+        assert!(!retry(&broken, &mut attempt, 2));
+        assert_eq!(attempt, 0);
+    }
+
+    #[test]
+    fn offline_test() {
+        let devices = devices(BUS_CLIENT);
+        let mut events = devices.subscribe();
+        let timed_out: DynResult<()> =
+            Err(std::io::Error::from(std::io::ErrorKind::TimedOut).into());
+        record_status(&devices, 1, &timed_out, 3);
+        assert!(matches!(events.try_recv(), Ok(Event::UnitStatusChanged(1))));
+        record_status(&devices, 1, &timed_out, 3);
+        assert!(events.try_recv().is_err());
+        let status = devices.unit_status(1).unwrap();
+        assert_eq!((status.consecutive_failures, status.offline), (2, false));
+        record_status(&devices, 1, &timed_out, 3);
+        assert!(matches!(events.try_recv(), Ok(Event::UnitStatusChanged(1))));
+        assert!(devices.unit_status(1).unwrap().offline);
+
+        // An exception is an answer
+        record_status(
+            &devices,
+            1,
+            &Err(ExceptionCode::IllegalDataAddress.into()),
+            3,
+        );
+        let status = devices.unit_status(1).unwrap();
+        assert_eq!((status.consecutive_failures, status.offline), (0, false));
+
+        // Never offline
+        for _ in 0..5 {
+            record_status(&devices, 2, &timed_out, 0);
+        }
+        assert!(!devices.unit_status(2).unwrap().offline);
+    }
+
+    const GATEWAY: &str = r#"
+<tag-list xmlns="http://www.elektro-kapsel.se/xml/modbus_config/v2">
+  <device addr="1">
+    <holding-registers>
+      <register addr="0"/>
+    </holding-registers>
+  </device>
+  <device addr="2">
+    <holding-registers>
+      <register addr="0"/>
+    </holding-registers>
+  </device>
+  <device addr="5">
+    <connection transport="tcp" host="localhost" unit="2"/>
+    <holding-registers>
+      <register addr="0"/>
+    </holding-registers>
+  </device>
+</tag-list>
+"#;
+
+    #[tokio::test]
+    async fn gateway_test() {
+        let bus_devices = devices(BUS);
+        let diag = Arc::new(Diagnostics::new(bus_devices.units()));
+        let service = ModbusService::with_diagnostics(bus_devices.clone(), diag);
+        let (client, server) = tokio::io::duplex(1024);
+        tokio::spawn(serve_line(
+            server,
+            LineFraming::Rtu(LINE_TIMING),
+            FaultInjector::new(service),
+        ));
+        let devices = devices(GATEWAY);
+        let mut traffic = devices.subscribe_traffic();
+        let gateway = Gateway::new(
+            rtu::attach_slave(client, Slave(0), LINE_TIMING),
+            devices.clone(),
+            TIMEOUT,
+        );
+        let request = |slave, request| SlaveRequest { slave, request };
+        let holding = |devices: &Devices, unit| {
+            devices
+                .tags_read(unit, |tags| tags.holding_registers.get_array(|r| r[0]))
+                .unwrap()
+        };
+        assert_eq!(
+            gateway
+                .call(request(2, Request::ReadHoldingRegisters(0, 1)))
+                .await,
+            Ok(Some(Response::ReadHoldingRegisters(vec![2])))
+        );
+        assert_eq!(holding(&devices, 2), 2);
+        assert_eq!(holding(&devices, 1), 0);
+
+        assert_eq!(
+            gateway
+                .call(request(1, Request::WriteSingleRegister(0, 9)))
+                .await,
+            Ok(Some(Response::WriteSingleRegister(0, 9)))
+        );
+        assert_eq!(holding(&bus_devices, 1), 9);
+        assert_eq!(holding(&devices, 1), 9);
+
+        // Unit 5 is unit 2 on the bus
+        assert_eq!(
+            gateway
+                .call(request(5, Request::WriteSingleRegister(0, 6)))
+                .await,
+            Ok(Some(Response::WriteSingleRegister(0, 6)))
+        );
+        assert_eq!(holding(&bus_devices, 2), 6);
+        assert_eq!(holding(&devices, 5), 6);
+        assert_eq!(holding(&devices, 2), 2);
+
+        // Broadcasts reach all units and aren't answered
+        assert_eq!(
+            gateway
+                .call(request(0, Request::WriteSingleRegister(0, 4)))
+                .await,
+            Ok(None)
+        );
+        assert_eq!(holding(&bus_devices, 1), 4);
+        assert_eq!(holding(&bus_devices, 2), 4);
+        let traffic = std::iter::from_fn(|| traffic.try_recv().ok())
+            .last()
+            .unwrap();
+        assert_eq!(traffic.unit, 0);
+        assert_eq!(traffic.response, "No response to broadcast");
+        // The bus is still in step
+        assert_eq!(
+            gateway
+                .call(request(2, Request::ReadHoldingRegisters(0, 1)))
+                .await,
+            Ok(Some(Response::ReadHoldingRegisters(vec![4])))
+        );
+
+        // Unit 3 isn't on the bus
+        assert_eq!(
+            gateway
+                .call(request(3, Request::ReadHoldingRegisters(0, 1)))
+                .await,
+            Err(ExceptionCode::GatewayTargetDevice)
+        );
+    }
+
+    const TAP: &str = r#"
+<tag-list xmlns="http://www.elektro-kapsel.se/xml/modbus_config/v2">
+  <device addr="1">
+    <holding-registers>
+      <register addr="0"/>
+      <register addr="1" initial-value="42" override="true"/>
+    </holding-registers>
+    <coils>
+      <bit addr="0" initial-value="1" override="true"/>
+    </coils>
+  </device>
+</tag-list>
+"#;
+
+    #[tokio::test]
+    async fn override_test() {
+        let bus_devices = devices(BUS);
+        let diag = Arc::new(Diagnostics::new(bus_devices.units()));
+        let service = ModbusService::with_diagnostics(bus_devices.clone(), diag);
+        let (client, server) = tokio::io::duplex(1024);
+        tokio::spawn(serve_line(
+            server,
+            LineFraming::Rtu(LINE_TIMING),
+            FaultInjector::new(service),
+        ));
+        let devices = devices(TAP);
+        let mut traffic = devices.subscribe_traffic();
+        let gateway = Gateway::new(
+            rtu::attach_slave(client, Slave(0), LINE_TIMING),
+            devices.clone(),
+            TIMEOUT,
+        );
+        let request = |request| SlaveRequest { slave: 1, request };
+        let holding = |devices: &Devices| {
+            devices
+                .tags_read(1, |tags| {
+                    tags.holding_registers.get_array(|r| r[0..2].to_vec())
+                })
+                .unwrap()
+        };
+
+        assert_eq!(
+            gateway
+                .call(request(Request::ReadHoldingRegisters(0, 2)))
+                .await,
+            Ok(Some(Response::ReadHoldingRegisters(vec![1, 42])))
+        );
+        let traffic = traffic.try_recv().unwrap();
+        assert_eq!(traffic.request, "ReadHoldingRegisters(0, 2)");
+        assert_eq!(traffic.response, "ReadHoldingRegisters([1, 42])");
+
+        assert_eq!(
+            gateway
+                .call(request(Request::WriteMultipleRegisters(
+                    0,
+                    Cow::Owned(vec![7, 8])
+                )))
+                .await,
+            Ok(Some(Response::WriteMultipleRegisters(0, 2)))
+        );
+        assert_eq!(holding(&bus_devices), vec![7, 42]);
+
+        // The client gets its own value back
+        assert_eq!(
+            gateway
+                .call(request(Request::WriteSingleRegister(1, 9)))
+                .await,
+            Ok(Some(Response::WriteSingleRegister(1, 9)))
+        );
+        assert_eq!(holding(&bus_devices), vec![7, 42]);
+        assert_eq!(holding(&devices), vec![7, 42]);
+
+        // Coils are padded to whole bytes
+        let Ok(Some(Response::ReadCoils(coils))) =
+            gateway.call(request(Request::ReadCoils(0, 2))).await
+        else {
+            panic!("Failed to read coils");
+        };
+        assert_eq!(coils[..2], [true, false]);
+    }
+
+    const POLL: &str = r#"
+<tag-list xmlns="http://www.elektro-kapsel.se/xml/modbus_config/v2">
+  <device addr="1" poll-interval="1000">
+    <holding-registers>
+      <register addr="0"/>
+      <group base-addr="10" poll-interval="200">
+        <register addr="0"/>
+        <register addr="200"/>
+        <group base-addr="10" poll="on-demand">
+          <register addr="0"/>
+        </group>
+      </group>
+    </holding-registers>
+    <coils>
+      <group poll="never">
+        <bit addr="0"/>
+      </group>
+    </coils>
+  </device>
+  <device addr="2">
+    <coils>
+      <bit addr="5"/>
+    </coils>
+  </device>
+</tag-list>
+"#;
+
+    #[test]
+    fn poll_schedule_test() {
+        use ClientOp::*;
+        let devices = devices(POLL);
+        let t0 = Instant::now();
+        let ms = Duration::from_millis;
+        let mut schedule = PollSchedule::new(&devices, ms(100), 0, t0);
+
+        // Every group is due at start and gets a turn
+        assert_eq!(schedule.next(t0), Ok(&ReadHoldingRegisters(1, 10, 1)));
+        assert_eq!(schedule.next(t0), Ok(&ReadHoldingRegisters(1, 0, 1)));
+        assert_eq!(schedule.next(t0), Ok(&ReadHoldingRegisters(1, 20, 1)));
+        assert_eq!(schedule.next(t0), Ok(&ReadCoils(2, 5, 1)));
+        assert_eq!(schedule.next(t0), Ok(&ReadHoldingRegisters(1, 210, 1)));
+        assert_eq!(schedule.next(t0), Err(Some(t0 + ms(100))));
+
+        assert_eq!(schedule.next(t0 + ms(150)), Ok(&ReadCoils(2, 5, 1)));
+        assert_eq!(schedule.next(t0 + ms(150)), Err(Some(t0 + ms(200))));
+        let now = t0 + ms(200);
+        assert_eq!(schedule.next(now), Ok(&ReadHoldingRegisters(1, 10, 1)));
+        assert_eq!(schedule.next(now), Ok(&ReadCoils(2, 5, 1)));
+        assert_eq!(schedule.next(now), Ok(&ReadHoldingRegisters(1, 210, 1)));
+        assert_eq!(schedule.next(now), Err(Some(t0 + ms(300))));
+
+        // Read once when requested
+        let now = t0 + ms(250);
+        schedule.request(1, now);
+        assert_eq!(schedule.next(now), Ok(&ReadHoldingRegisters(1, 20, 1)));
+        assert_eq!(schedule.next(now), Err(Some(t0 + ms(300))));
+    }
+
+    const READ_LIMITS: &str = r#"
+<tag-list xmlns="http://www.elektro-kapsel.se/xml/modbus_config/v2">
+  <device addr="1">
+    <holding-registers>
+      <register addr="0"/>
+      <register addr="2"/>
+      <register addr="4"/>
+      <register addr="6"/>
+      <register addr="20"/>
+    </holding-registers>
+    <coils>
+      <bit addr="0"/>
+      <bit addr="3"/>
+    </coils>
+  </device>
+  <device addr="2" max-read-gap="0" max-read-registers="4">
+    <holding-registers>
+      <register addr="0"/>
+      <register addr="2"/>
+      <register-range addr-low="10" addr-high="14"/>
+    </holding-registers>
+  </device>
+</tag-list>
+"#;
+
+    #[test]
+    fn read_sequence_test() {
+        use ClientOp::*;
+        let devices = devices(READ_LIMITS);
+        let ops = |unit, default_gap| {
+            let sizes = ReadSizes::new(&devices.read_limits(unit).unwrap(), default_gap);
+            let ranges = devices.ranges(unit).unwrap();
+            ClientOp::read_sequence(unit, &ranges.polls[&Poll::Cyclic(None)], &sizes)
+        };
+        assert_eq!(
+            ops(1, 0),
+            [
+                ReadHoldingRegisters(1, 0, 1),
+                ReadHoldingRegisters(1, 2, 1),
+                ReadHoldingRegisters(1, 4, 1),
+                ReadHoldingRegisters(1, 6, 1),
+                ReadHoldingRegisters(1, 20, 1),
+                ReadCoils(1, 0, 1),
+                ReadCoils(1, 3, 1),
+            ]
+        );
+        assert_eq!(
+            ops(1, 2),
+            [
+                ReadHoldingRegisters(1, 0, 7),
+                ReadHoldingRegisters(1, 20, 1),
+                ReadCoils(1, 0, 4),
+            ]
+        );
+        // The device doesn't read across holes and reads at most 4
+        // registers at a time
+        assert_eq!(
+            ops(2, 10),
+            [
+                ReadHoldingRegisters(2, 0, 1),
+                ReadHoldingRegisters(2, 2, 1),
+                ReadHoldingRegisters(2, 10, 4),
+                ReadHoldingRegisters(2, 14, 1),
+            ]
+        );
     }
-    Ok(())
 }
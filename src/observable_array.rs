@@ -1,6 +1,7 @@
 use crate::range_array::RangeArray;
 #[allow(unused_imports)]
 use log::debug;
+use std::ops::Range;
 use std::pin::Pin;
 use std::sync::{Arc, RwLock};
 use tokio::sync::Notify;
@@ -58,10 +59,23 @@ where
 
     pub fn update(&mut self, start: usize, data: &[T], exclude: usize) {
         self.array[start..start + data.len()].clone_from_slice(data);
+        self.notify(&(start..start + data.len()), exclude);
+    }
+
+    pub fn modify<F, R>(&mut self, changed: &Range<usize>, f: F, exclude: usize) -> R
+    where
+        F: FnOnce(&mut [T]) -> R,
+    {
+        let res = f(&mut self.array);
+        self.notify(changed, exclude);
+        res
+    }
+
+    fn notify(&mut self, changed: &Range<usize>, exclude: usize) {
         for (index, observer) in self.observers.iter_mut().enumerate() {
             if index != exclude {
                 if let Some(observer) = observer {
-                    observer.changed.union(&(start..start + data.len()));
+                    observer.changed.union(changed);
                     observer.notify.notify_one();
                 }
             }
@@ -145,6 +159,16 @@ where
         base.update(start, data, self.index);
     }
 
+    /// Modify the array while holding the lock. Observers are notified
+    /// that the range `changed` has been updated.
+    pub fn modify<F, R>(&self, changed: Range<usize>, f: F) -> R
+    where
+        F: FnOnce(&mut [T]) -> R,
+    {
+        let mut base = self.base.write().unwrap();
+        base.modify(&changed, f, self.index)
+    }
+
     pub fn get_array<F, R>(&self, f: F) -> R
     where
         F: FnOnce(&[T]) -> R,
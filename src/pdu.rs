@@ -0,0 +1,292 @@
+// Encoding of Modbus PDUs. tokio-modbus only exposes decoding, so
+// transports implemented here need their own encoders.
+
+use tokio_modbus::bytes::Bytes;
+use tokio_modbus::prelude::ReadDeviceIdentificationResponse;
+use tokio_modbus::{ExceptionCode, ExceptionResponse, ProtocolError};
+use tokio_modbus::{Request, Response};
+
+/// Encoding value for an ON coil in single coil requests and responses
+const COIL_ON: u16 = 0xff00;
+
+const MEI_READ_DEVICE_IDENTIFICATION: u8 = 0x0e;
+
+fn put_u16(buf: &mut Vec<u8>, v: u16) {
+    buf.extend_from_slice(&v.to_be_bytes());
+}
+
+fn put_coil(buf: &mut Vec<u8>, v: bool) {
+    put_u16(buf, if v { COIL_ON } else { 0 });
+}
+
+fn put_packed_coils(buf: &mut Vec<u8>, coils: &[bool]) {
+    buf.push(coils.len().div_ceil(8) as u8);
+    for chunk in coils.chunks(8) {
+        let mut byte = 0u8;
+        for (i, c) in chunk.iter().enumerate() {
+            if *c {
+                byte |= 1 << i;
+            }
+        }
+        buf.push(byte);
+    }
+}
+
+fn put_registers(buf: &mut Vec<u8>, regs: &[u16]) {
+    buf.push((regs.len() * 2) as u8);
+    for r in regs {
+        put_u16(buf, *r);
+    }
+}
+
+pub fn encode_request(req: &Request) -> Vec<u8> {
+    use Request::*;
+    let mut buf = vec![req.function_code().value()];
+    match req {
+        ReadCoils(addr, count)
+        | ReadDiscreteInputs(addr, count)
+        | ReadInputRegisters(addr, count)
+        | ReadHoldingRegisters(addr, count) => {
+            put_u16(&mut buf, *addr);
+            put_u16(&mut buf, *count);
+        }
+        WriteSingleCoil(addr, value) => {
+            put_u16(&mut buf, *addr);
+            put_coil(&mut buf, *value);
+        }
+        WriteMultipleCoils(addr, values) => {
+            put_u16(&mut buf, *addr);
+            put_u16(&mut buf, values.len() as u16);
+            put_packed_coils(&mut buf, values);
+        }
+        WriteSingleRegister(addr, value) => {
+            put_u16(&mut buf, *addr);
+            put_u16(&mut buf, *value);
+        }
+        WriteMultipleRegisters(addr, values) => {
+            put_u16(&mut buf, *addr);
+            put_u16(&mut buf, values.len() as u16);
+            put_registers(&mut buf, values);
+        }
+        ReportServerId => {}
+        MaskWriteRegister(addr, and_mask, or_mask) => {
+            put_u16(&mut buf, *addr);
+            put_u16(&mut buf, *and_mask);
+            put_u16(&mut buf, *or_mask);
+        }
+        ReadWriteMultipleRegisters(read_addr, read_count, write_addr, values) => {
+            put_u16(&mut buf, *read_addr);
+            put_u16(&mut buf, *read_count);
+            put_u16(&mut buf, *write_addr);
+            put_u16(&mut buf, values.len() as u16);
+            put_registers(&mut buf, values);
+        }
+        ReadDeviceIdentification(read_code, object_id) => {
+            buf.push(MEI_READ_DEVICE_IDENTIFICATION);
+            buf.push(read_code.value());
+            buf.push(*object_id);
+        }
+        Custom(_, data) => buf.extend_from_slice(data),
+    }
+    buf
+}
+
+pub fn encode_response(resp: &Response) -> Vec<u8> {
+    use Response::*;
+    let mut buf = vec![resp.function_code().value()];
+    match resp {
+        ReadCoils(values) | ReadDiscreteInputs(values) => put_packed_coils(&mut buf, values),
+        ReadInputRegisters(values)
+        | ReadHoldingRegisters(values)
+        | ReadWriteMultipleRegisters(values) => put_registers(&mut buf, values),
+        WriteSingleCoil(addr, value) => {
+            put_u16(&mut buf, *addr);
+            put_coil(&mut buf, *value);
+        }
+        WriteMultipleCoils(addr, count) | WriteMultipleRegisters(addr, count) => {
+            put_u16(&mut buf, *addr);
+            put_u16(&mut buf, *count);
+        }
+        WriteSingleRegister(addr, value) => {
+            put_u16(&mut buf, *addr);
+            put_u16(&mut buf, *value);
+        }
+        ReportServerId(id, running, data) => {
+            buf.push((data.len() + 2) as u8);
+            buf.push(*id);
+            buf.push(if *running { 0xff } else { 0x00 });
+            buf.extend_from_slice(data);
+        }
+        MaskWriteRegister(addr, and_mask, or_mask) => {
+            put_u16(&mut buf, *addr);
+            put_u16(&mut buf, *and_mask);
+            put_u16(&mut buf, *or_mask);
+        }
+        ReadDeviceIdentification(ReadDeviceIdentificationResponse {
+            read_code,
+            conformity_level,
+            more_follows,
+            next_object_id,
+            device_id_objects,
+        }) => {
+            buf.push(MEI_READ_DEVICE_IDENTIFICATION);
+            buf.push(read_code.value());
+            buf.push(conformity_level.value());
+            buf.push(if *more_follows { 0xff } else { 0x00 });
+            buf.push(*next_object_id);
+            buf.push(device_id_objects.len() as u8);
+            for obj in device_id_objects {
+                buf.push(obj.id);
+                buf.push(obj.value.len() as u8);
+                buf.extend_from_slice(&obj.value);
+            }
+        }
+        Custom(_, data) => buf.extend_from_slice(data),
+    }
+    buf
+}
+
+pub fn encode_exception(function: u8, exception: ExceptionCode) -> Vec<u8> {
+    vec![function | 0x80, exception.into()]
+}
+
+/// Encode the response or exception for a request with function code
+/// `function`
+pub fn encode_result(function: u8, result: &Result<Response, ExceptionCode>) -> Vec<u8> {
+    match result {
+        Ok(response) => encode_response(response),
+        Err(exception) => encode_exception(function, *exception),
+    }
+}
+
+// Whether a response carries what was asked for: as many values as were
+// read, or the address and values written
+fn answers(request: &Request, response: &Response) -> bool {
+    use Request as Req;
+    use Response as Resp;
+    match (request, response) {
+        (Req::ReadCoils(_, count), Resp::ReadCoils(coils))
+        | (Req::ReadDiscreteInputs(_, count), Resp::ReadDiscreteInputs(coils)) => {
+            // Coils are packed in whole bytes
+            coils.len() == usize::from(*count).div_ceil(8) * 8
+        }
+        (Req::ReadHoldingRegisters(_, count), Resp::ReadHoldingRegisters(words))
+        | (Req::ReadInputRegisters(_, count), Resp::ReadInputRegisters(words))
+        | (
+            Req::ReadWriteMultipleRegisters(_, count, _, _),
+            Resp::ReadWriteMultipleRegisters(words),
+        ) => words.len() == usize::from(*count),
+        (Req::WriteSingleCoil(addr, value), Resp::WriteSingleCoil(resp_addr, resp_value)) => {
+            (addr, value) == (resp_addr, resp_value)
+        }
+        (
+            Req::WriteSingleRegister(addr, value),
+            Resp::WriteSingleRegister(resp_addr, resp_value),
+        ) => (addr, value) == (resp_addr, resp_value),
+        (Req::WriteMultipleCoils(addr, coils), Resp::WriteMultipleCoils(resp_addr, count)) => {
+            (*addr, coils.len()) == (*resp_addr, usize::from(*count))
+        }
+        (
+            Req::WriteMultipleRegisters(addr, words),
+            Resp::WriteMultipleRegisters(resp_addr, count),
+        ) => (*addr, words.len()) == (*resp_addr, usize::from(*count)),
+        (
+            Req::MaskWriteRegister(addr, and, or),
+            Resp::MaskWriteRegister(resp_addr, resp_and, resp_or),
+        ) => (addr, and, or) == (resp_addr, resp_and, resp_or),
+        _ => true,
+    }
+}
+
+/// Decode a response to `request` the way tokio-modbus clients do.
+/// Responses not matching the request are errors.
+pub fn decode_response(request: &Request, pdu: &[u8]) -> tokio_modbus::Result<Response> {
+    let function = request.function_code();
+    let bytes = Bytes::copy_from_slice(pdu);
+    let result = if pdu.first().is_some_and(|f| f & 0x80 != 0) {
+        Err(ExceptionResponse::try_from(bytes)?)
+    } else {
+        Ok(Response::try_from(bytes)?)
+    };
+    let response_function = match &result {
+        Ok(response) => response.function_code(),
+        Err(exception) => exception.function,
+    };
+    if response_function != function {
+        return Err(ProtocolError::FunctionCodeMismatch {
+            request: function,
+            result,
+        }
+        .into());
+    }
+    if let Ok(response) = &result
+        && !answers(request, response)
+    {
+        return Err(ProtocolError::HeaderMismatch {
+            message: "Response doesn't match the request".to_string(),
+            result,
+        }
+        .into());
+    }
+    Ok(result.map_err(|e| e.exception))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip_test() {
+        let reqs = [
+            Request::ReadCoils(3, 10),
+            Request::WriteMultipleCoils(
+                7,
+                vec![true, false, true, true, false, false, true, false, true].into(),
+            ),
+            Request::WriteMultipleRegisters(0x1234, vec![1, 2, 0xffff].into()),
+            Request::ReadWriteMultipleRegisters(1, 2, 3, vec![4, 5].into()),
+            Request::MaskWriteRegister(4, 0xf0f0, 0x0101),
+            Request::WriteSingleCoil(9, true),
+        ];
+        for req in reqs {
+            let bytes = encode_request(&req);
+            assert_eq!(Request::try_from(Bytes::from(bytes)).unwrap(), req);
+        }
+        let resps = [
+            Response::ReadHoldingRegisters(vec![1, 2, 3]),
+            Response::ReadCoils(vec![true, false, false, true, false, false, false, false]),
+            Response::WriteSingleCoil(2, false),
+            Response::MaskWriteRegister(4, 0xf0f0, 0x0101),
+            Response::Custom(0x08, Bytes::from_static(&[0, 0, 0x12, 0x34])),
+        ];
+        for resp in resps {
+            let bytes = encode_response(&resp);
+            assert_eq!(Response::try_from(Bytes::from(bytes)).unwrap(), resp);
+        }
+        assert_eq!(
+            encode_exception(0x03, ExceptionCode::IllegalDataAddress),
+            vec![0x83, 0x02]
+        );
+        let read = Request::ReadHoldingRegisters(0, 1);
+        assert_eq!(
+            decode_response(&read, &[0x83, 0x02]).unwrap(),
+            Err(ExceptionCode::IllegalDataAddress)
+        );
+        assert_eq!(
+            decode_response(&read, &[0x03, 2, 0, 1]).unwrap(),
+            Ok(Response::ReadHoldingRegisters(vec![1]))
+        );
+        assert!(decode_response(&Request::ReadCoils(0, 1), &[0x03, 2, 0, 1]).is_err());
+        // Wrong byte count or address
+        assert!(decode_response(&read, &[0x03, 4, 0, 1, 0, 2]).is_err());
+        assert_eq!(
+            decode_response(&Request::ReadCoils(0, 9), &[0x01, 2, 1, 1])
+                .unwrap()
+                .map(|r| matches!(r, Response::ReadCoils(_))),
+            Ok(true)
+        );
+        assert!(decode_response(&Request::ReadCoils(0, 9), &[0x01, 1, 1]).is_err());
+        let write = Request::WriteSingleRegister(4, 1);
+        assert!(decode_response(&write, &[0x06, 0, 5, 0, 1]).is_err());
+    }
+}
@@ -76,6 +76,21 @@ where
     pub fn is_empty(&self) -> bool {
         self.array.is_empty()
     }
+
+    /// Check if any part of `r` is covered by the array
+    pub fn overlaps(&self, r: &Range<T>) -> bool {
+        let p = self.array.partition_point(|v| v.end <= r.start);
+        p < self.array.len() && self.array[p].start < r.end && r.start < r.end
+    }
+
+    /// Check if all of `r` is covered by the array
+    pub fn contains(&self, r: &Range<T>) -> bool {
+        if r.start >= r.end {
+            return true;
+        }
+        let p = self.array.partition_point(|v| v.end <= r.start);
+        p < self.array.len() && self.array[p].start <= r.start && r.end <= self.array[p].end
+    }
 }
 
 impl<T> Default for RangeArray<T>
@@ -150,3 +165,23 @@ fn range_array_test() {
         }
     );
 }
+
+#[test]
+fn range_array_overlaps_test() {
+    let mut a = RangeArray::new();
+    a.union(&(2..4));
+    a.union(&(8..10));
+    assert!(!a.overlaps(&(0..2)));
+    assert!(a.overlaps(&(0..3)));
+    assert!(a.overlaps(&(3..8)));
+    assert!(!a.overlaps(&(4..8)));
+    assert!(a.overlaps(&(9..20)));
+    assert!(!a.overlaps(&(10..20)));
+    assert!(!a.overlaps(&(3..3)));
+    assert!(a.contains(&(2..4)));
+    assert!(a.contains(&(3..4)));
+    assert!(!a.contains(&(3..5)));
+    assert!(!a.contains(&(0..3)));
+    assert!(!a.contains(&(2..10)));
+    assert!(a.contains(&(5..5)));
+}
@@ -0,0 +1,89 @@
+use crate::observable_array::ObservableArray;
+use crate::range_array::RangeArray;
+use crate::register_value;
+use crate::tag_list::{TagDefList, TagSequence, FIFO_MAX_LEN};
+use futures::future;
+use log::error;
+use std::collections::BTreeMap;
+use std::pin::Pin;
+
+/// Number of records in a file, record numbers are 0 to 9999
+pub const FILE_RECORDS: usize = 10000;
+
+pub enum Updated {
+    Fifo(u16),
+    FileRecords(u16, RangeArray<usize>),
+}
+
+/// FIFO queues and file records of a device. A FIFO queue is stored
+/// with the number of queued values first, followed by the values.
+#[derive(Clone)]
+pub struct Records {
+    pub fifos: BTreeMap<u16, ObservableArray<u16>>,
+    pub files: BTreeMap<u16, ObservableArray<u16>>,
+}
+
+/// Get the values in a FIFO queue
+pub fn fifo_values(fifo: &ObservableArray<u16>) -> Vec<u16> {
+    fifo.get_array(|a| Vec::from(&a[1..1 + usize::from(a[0])]))
+}
+
+/// Replace the values in a FIFO queue. Values that don't fit are dropped.
+pub fn set_fifo_values(fifo: &ObservableArray<u16>, values: &[u16]) {
+    let values = &values[..values.len().min(FIFO_MAX_LEN)];
+    let mut data = Vec::with_capacity(values.len() + 1);
+    data.push(values.len() as u16);
+    data.extend_from_slice(values);
+    fifo.update(0, &data);
+}
+
+impl Records {
+    pub fn new(init: &TagDefList) -> Records {
+        let mut fifos = BTreeMap::new();
+        for def in &init.fifo_queues {
+            let fifo = ObservableArray::new(FIFO_MAX_LEN + 1);
+            set_fifo_values(&fifo, &def.initial_value);
+            fifos.insert(def.address, fifo);
+        }
+        let mut files = BTreeMap::new();
+        for def in &init.files {
+            let records = ObservableArray::new(FILE_RECORDS);
+            for (reg, ctxt) in def.records.tag_iter() {
+                if let Some(value_str) = reg.initial_value.as_ref() {
+                    match register_value::parse(reg, value_str) {
+                        Ok(v) => records.update((reg.address_low + ctxt.base_address) as usize, &v),
+                        Err(e) => error!(
+                            "Failed to parse initial value for record {} in file {}: {}",
+                            reg.address_low, def.number, e
+                        ),
+                    }
+                }
+            }
+            files.insert(def.number, records);
+        }
+        Records { fifos, files }
+    }
+
+    pub fn updated(&self) -> Pin<Box<dyn Future<Output = Updated> + Send + 'static>> {
+        let mut updates: Vec<Pin<Box<dyn Future<Output = Updated> + Send>>> = Vec::new();
+        for (addr, fifo) in &self.fifos {
+            let addr = *addr;
+            let updated = fifo.updated();
+            updates.push(Box::pin(async move {
+                updated.await;
+                Updated::Fifo(addr)
+            }));
+        }
+        for (number, records) in &self.files {
+            let number = *number;
+            let updated = records.updated();
+            updates.push(Box::pin(async move {
+                Updated::FileRecords(number, updated.await)
+            }));
+        }
+        if updates.is_empty() {
+            return Box::pin(future::pending());
+        }
+        Box::pin(async move { future::select_all(updates).await.0 })
+    }
+}
@@ -170,7 +170,7 @@ mod test {
     use crate::encoding::ValueType;
     use crate::encoding::WordOrder;
     use crate::presentation::Presentation;
-    use crate::tag_list::RegisterRange;
+    use crate::tag_list::{Access, RegisterRange};
 
     #[test]
     fn test_parse() {
@@ -192,6 +192,8 @@ mod test {
                 word_order: WordOrder::BigEndian,
             },
             enums: Vec::new(),
+            access: Access::ReadWrite,
+            overridden: false,
         };
         assert_eq!(&parse(&reg, "8933224").unwrap(), &[0x0088, 0x4f68]);
         reg.encoding.byte_order = ByteOrder::LittleEndian;
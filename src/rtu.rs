@@ -0,0 +1,393 @@
+// Modbus RTU framing. Frames are delimited by silent intervals on the
+// line, but since serial adapters often buffer data the expected
+// length of a frame is also derived from its content when possible.
+
+use crate::line_client::{FrameSource, LineClient};
+use async_trait::async_trait;
+use futures::FutureExt;
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+use tokio::time::{self, Duration};
+use tokio_modbus::client::{Client, Context};
+use tokio_modbus::slave::Slave;
+
+/// Maximum size of an RTU ADU, including address and CRC
+pub const MAX_ADU_LEN: usize = 256;
+
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xffffu16;
+    for b in data {
+        crc ^= u16::from(*b);
+        for _ in 0..8 {
+            if crc & 1 != 0 {
+                crc = (crc >> 1) ^ 0xa001;
+            } else {
+                crc >>= 1;
+            }
+        }
+    }
+    crc
+}
+
+fn crc_ok(adu: &[u8]) -> bool {
+    let Some(split) = adu.len().checked_sub(2) else {
+        return false;
+    };
+    let (data, crc) = adu.split_at(split);
+    crc16(data).to_le_bytes() == crc
+}
+
+/// Build a frame with address and CRC from a PDU
+pub fn encode_frame(unit: u8, pdu: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(pdu.len() + 3);
+    frame.push(unit);
+    frame.extend_from_slice(pdu);
+    let crc = crc16(&frame);
+    frame.extend_from_slice(&crc.to_le_bytes());
+    frame
+}
+
+/// Silent interval of 3.5 characters marking the end of a frame
+pub fn frame_gap(baud_rate: u32) -> Duration {
+    if baud_rate > 19200 {
+        Duration::from_micros(1750)
+    } else {
+        // 11 bits per character
+        Duration::from_micros(38_500_000 / u64::from(baud_rate.max(1)))
+    }
+}
+
+/// Silent intervals delimiting frames on a serial line
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FrameTiming {
+    /// Interval ending a frame, 3.5 characters
+    pub gap: Duration,
+    /// Longest interval between characters of a frame, 1.5 characters.
+    /// Not checked if None.
+    pub char_timeout: Option<Duration>,
+}
+
+impl FrameTiming {
+    /// Standard frame gap for the baud rate
+    pub fn new(baud_rate: u32) -> FrameTiming {
+        FrameTiming {
+            gap: frame_gap(baud_rate),
+            char_timeout: None,
+        }
+    }
+}
+
+/// Length of a request ADU including CRC, if it can be determined from
+/// the bytes received so far
+pub fn request_len(adu: &[u8]) -> Option<usize> {
+    let byte_at = |i: usize| adu.get(i).map(|b| usize::from(*b));
+    let pdu_len = match *adu.get(1)? {
+        0x01..=0x06 | 0x08 => 5,
+        0x07 | 0x0b | 0x0c | 0x11 => 1,
+        0x0f | 0x10 => 6 + byte_at(6)?,
+        0x14 | 0x15 => 2 + byte_at(2)?,
+        0x16 => 7,
+        0x17 => 10 + byte_at(10)?,
+        0x18 => 3,
+        0x2b => 4,
+        _ => return None,
+    };
+    Some(pdu_len + 3)
+}
+
+/// Length of a response ADU including CRC, if it can be determined from
+/// the bytes received so far
+pub fn response_len(adu: &[u8]) -> Option<usize> {
+    let byte_at = |i: usize| adu.get(i).map(|b| usize::from(*b));
+    let function = *adu.get(1)?;
+    if function & 0x80 != 0 {
+        return Some(5);
+    }
+    let pdu_len = match function {
+        0x01..=0x04 | 0x0c | 0x11 | 0x14 | 0x15 | 0x17 => 2 + byte_at(2)?,
+        0x05 | 0x06 | 0x0b | 0x0f | 0x10 => 5,
+        0x07 => 2,
+        0x16 => 7,
+        0x18 => 3 + (byte_at(2)? << 8 | byte_at(3)?),
+        _ => return None,
+    };
+    Some(pdu_len + 3)
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Frame {
+    /// Address and PDU, CRC removed
+    Adu(Vec<u8>),
+    CrcError,
+    Overrun,
+}
+
+pub struct FrameReader<R> {
+    reader: R,
+    gap: Duration,
+    char_timeout: Option<Duration>,
+    frame_len: fn(&[u8]) -> Option<usize>,
+    buf: Vec<u8>,
+    overrun: bool,
+    // A silent interval longer than the character timeout was seen
+    broken: bool,
+}
+
+impl<R> FrameReader<R>
+where
+    R: AsyncRead + Unpin,
+{
+    pub fn new(reader: R, timing: FrameTiming, frame_len: fn(&[u8]) -> Option<usize>) -> Self {
+        FrameReader {
+            reader,
+            gap: timing.gap,
+            char_timeout: timing.char_timeout,
+            frame_len,
+            buf: Vec::new(),
+            overrun: false,
+            broken: false,
+        }
+    }
+
+    // Returns a frame if the start of the buffer holds one with the
+    // expected length and a valid CRC
+    fn complete_frame(&mut self) -> Option<Frame> {
+        let len = (self.frame_len)(&self.buf)?;
+        if self.overrun || self.broken || self.buf.len() < len || !crc_ok(&self.buf[..len]) {
+            return None;
+        }
+        let mut adu: Vec<u8> = self.buf.drain(..len).collect();
+        adu.truncate(len - 2);
+        Some(Frame::Adu(adu))
+    }
+
+    // Called when the line has been silent long enough to end a frame
+    fn end_of_frame(&mut self) -> Frame {
+        let adu = std::mem::take(&mut self.buf);
+        let broken = std::mem::take(&mut self.broken);
+        if std::mem::take(&mut self.overrun) {
+            Frame::Overrun
+        } else if !broken && adu.len() >= 4 && crc_ok(&adu) {
+            Frame::Adu(adu[..adu.len() - 2].to_vec())
+        } else {
+            Frame::CrcError
+        }
+    }
+
+    // Read more data of the current frame. Returns None if the line is
+    // silent long enough to end the frame.
+    async fn read_within_gap(&mut self, chunk: &mut [u8]) -> io::Result<Option<usize>> {
+        let mut gap = self.gap;
+        if let Some(timeout) = self.char_timeout.filter(|t| *t < self.gap) {
+            if let Ok(res) = time::timeout(timeout, self.reader.read(chunk)).await {
+                return res.map(Some);
+            }
+            gap -= timeout;
+            // Data arriving now continues a frame with a too long silent
+            // interval, so it's discarded at the end of the frame
+            return match time::timeout(gap, self.reader.read(chunk)).await {
+                Ok(res) => {
+                    self.broken = true;
+                    res.map(Some)
+                }
+                Err(_) => Ok(None),
+            };
+        }
+        match time::timeout(gap, self.reader.read(chunk)).await {
+            Ok(res) => res.map(Some),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Drop data received so far, including data that can be read
+    /// without waiting
+    pub fn discard_input(&mut self) {
+        let mut chunk = [0u8; MAX_ADU_LEN];
+        while let Some(Ok(n)) = self.reader.read(&mut chunk).now_or_never()
+            && n > 0
+        {}
+        self.buf.clear();
+        self.overrun = false;
+        self.broken = false;
+    }
+
+    /// Wait for the next frame. Returns None at end of stream.
+    pub async fn next(&mut self) -> io::Result<Option<Frame>> {
+        let mut chunk = [0u8; MAX_ADU_LEN];
+        loop {
+            if let Some(frame) = self.complete_frame() {
+                return Ok(Some(frame));
+            }
+            let n = if self.buf.is_empty() && !self.overrun {
+                self.reader.read(&mut chunk).await?
+            } else {
+                match self.read_within_gap(&mut chunk).await? {
+                    Some(n) => n,
+                    None => return Ok(Some(self.end_of_frame())),
+                }
+            };
+            if n == 0 {
+                return Ok(None);
+            }
+            if self.buf.len() + n > MAX_ADU_LEN {
+                self.overrun = true;
+                self.buf.clear();
+            } else if !self.overrun {
+                self.buf.extend_from_slice(&chunk[..n]);
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl<R> FrameSource for FrameReader<R>
+where
+    R: AsyncRead + Unpin + Send,
+{
+    async fn next(&mut self) -> io::Result<Option<Frame>> {
+        FrameReader::next(self).await
+    }
+
+    fn discard_input(&mut self) {
+        FrameReader::discard_input(self)
+    }
+}
+
+/// Create a client context using RTU framing with the given timing
+pub fn attach_slave<T>(transport: T, slave: Slave, timing: FrameTiming) -> Context
+where
+    T: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (reader, writer) = tokio::io::split(transport);
+    let frames = FrameReader::new(reader, timing, response_len);
+    let client = LineClient::new(frames, writer, encode_frame, slave);
+    Context::from(Box::new(client) as Box<dyn Client>)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+    use tokio_modbus::client::{Reader, Writer};
+
+    #[test]
+    fn crc_test() {
+        assert_eq!(crc16(&[0x01, 0x03, 0x08, 0x2b, 0x00, 0x02]), 0x63b6);
+        assert_eq!(
+            encode_frame(0x01, &[0x03, 0x08, 0x2b, 0x00, 0x02]),
+            vec![0x01, 0x03, 0x08, 0x2b, 0x00, 0x02, 0xb6, 0x63]
+        );
+    }
+
+    #[tokio::test]
+    async fn frame_reader_test() {
+        let (mut tx, rx) = tokio::io::duplex(1024);
+        let timing = FrameTiming {
+            gap: Duration::from_millis(20),
+            char_timeout: None,
+        };
+        let mut frames = FrameReader::new(rx, timing, request_len);
+        // Two frames back to back are split by length
+        let mut data = encode_frame(1, &[0x03, 0x00, 0x00, 0x00, 0x02]);
+        data.extend(encode_frame(2, &[0x06, 0x00, 0x01, 0x12, 0x34]));
+        tx.write_all(&data).await.unwrap();
+        assert_eq!(
+            frames.next().await.unwrap(),
+            Some(Frame::Adu(vec![1, 0x03, 0x00, 0x00, 0x00, 0x02]))
+        );
+        assert_eq!(
+            frames.next().await.unwrap(),
+            Some(Frame::Adu(vec![2, 0x06, 0x00, 0x01, 0x12, 0x34]))
+        );
+        // Variable length diagnostics request is ended by the gap
+        tx.write_all(&encode_frame(1, &[0x08, 0x00, 0x00, 1, 2, 3, 4]))
+            .await
+            .unwrap();
+        assert_eq!(
+            frames.next().await.unwrap(),
+            Some(Frame::Adu(vec![1, 0x08, 0x00, 0x00, 1, 2, 3, 4]))
+        );
+        tx.write_all(&[1, 0x03, 0x00, 0x00, 0x00, 0x02, 0, 0])
+            .await
+            .unwrap();
+        assert_eq!(frames.next().await.unwrap(), Some(Frame::CrcError));
+        tx.write_all(&[0u8; MAX_ADU_LEN + 1]).await.unwrap();
+        assert_eq!(frames.next().await.unwrap(), Some(Frame::Overrun));
+        drop(tx);
+        assert_eq!(frames.next().await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn char_timeout_test() {
+        let (mut tx, rx) = tokio::io::duplex(1024);
+        let timing = FrameTiming {
+            gap: Duration::from_millis(200),
+            char_timeout: Some(Duration::from_millis(50)),
+        };
+        let mut frames = FrameReader::new(rx, timing, request_len);
+        let adu = encode_frame(1, &[0x03, 0x00, 0x00, 0x00, 0x02]);
+        let expected = Frame::Adu(adu[..adu.len() - 2].to_vec());
+        tokio::spawn(async move {
+            // Silent interval between character timeout and frame gap
+            tx.write_all(&adu[..3]).await.unwrap();
+            time::sleep(Duration::from_millis(100)).await;
+            tx.write_all(&adu[3..]).await.unwrap();
+            time::sleep(Duration::from_millis(300)).await;
+            tx.write_all(&adu[..3]).await.unwrap();
+            time::sleep(Duration::from_millis(10)).await;
+            tx.write_all(&adu[3..]).await.unwrap();
+        });
+        assert_eq!(frames.next().await.unwrap(), Some(Frame::CrcError));
+        assert_eq!(frames.next().await.unwrap(), Some(expected));
+    }
+
+    #[tokio::test]
+    async fn client_test() {
+        let (client, mut server) = tokio::io::duplex(1024);
+        let timing = FrameTiming {
+            gap: Duration::from_millis(5),
+            char_timeout: None,
+        };
+        let mut ctxt = attach_slave(client, Slave(1), timing);
+        // Late response to an earlier request
+        server
+            .write_all(&encode_frame(1, &[0x03, 2, 0, 9]))
+            .await
+            .unwrap();
+        let respond = async {
+            let mut buf = [0u8; MAX_ADU_LEN];
+            let exchanges: [(&[u8], &[u8]); 3] = [
+                (&[0x03, 0, 0, 0, 2], &[0x03, 4, 0, 1, 0, 2]),
+                // Wrong byte count
+                (&[0x03, 0, 0, 0, 2], &[0x03, 2, 0, 1]),
+                // Wrong address
+                (&[0x06, 0, 4, 0, 1], &[0x06, 0, 5, 0, 1]),
+            ];
+            for (request, reply) in exchanges {
+                let n = server.read(&mut buf).await.unwrap();
+                assert_eq!(buf[..n], encode_frame(1, request));
+                server.write_all(&encode_frame(1, reply)).await.unwrap();
+            }
+        };
+        let requests = async {
+            assert_eq!(
+                ctxt.read_holding_registers(0, 2).await.unwrap(),
+                Ok(vec![1, 2])
+            );
+            assert!(ctxt.read_holding_registers(0, 2).await.is_err());
+            assert!(ctxt.write_single_register(4, 1).await.is_err());
+        };
+        tokio::join!(respond, requests);
+    }
+
+    #[test]
+    fn response_len_test() {
+        assert_eq!(response_len(&[1]), None);
+        assert_eq!(response_len(&[1, 0x03]), None);
+        assert_eq!(response_len(&[1, 0x03, 4]), Some(9));
+        assert_eq!(response_len(&[1, 0x10]), Some(8));
+        assert_eq!(response_len(&[1, 0x83]), Some(5));
+        assert_eq!(response_len(&[1, 0x18, 0, 6]), Some(12));
+        assert_eq!(response_len(&[1, 0x2b, 0x0e]), None);
+    }
+}
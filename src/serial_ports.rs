@@ -0,0 +1,226 @@
+// Discovery of serial ports, used by the list-ports command and the
+// web server, and the settings used when opening them.
+
+use crate::error::DynResult;
+use serde_derive::Serialize;
+use tokio_serial::{
+    DataBits, FlowControl, Parity, SerialPortBuilder, SerialPortInfo, SerialPortType, StopBits,
+};
+
+/// Line settings of a serial port
+#[derive(Clone, Debug, PartialEq)]
+pub struct SerialSettings {
+    pub baud_rate: u32,
+    pub parity: Parity,
+    pub data_bits: DataBits,
+    pub stop_bits: StopBits,
+    pub flow_control: FlowControl,
+}
+
+impl Default for SerialSettings {
+    fn default() -> Self {
+        SerialSettings {
+            baud_rate: 9600,
+            parity: Parity::Even,
+            data_bits: DataBits::Eight,
+            stop_bits: StopBits::One,
+            flow_control: FlowControl::None,
+        }
+    }
+}
+
+impl SerialSettings {
+    pub fn builder(&self, path: &str) -> SerialPortBuilder {
+        tokio_serial::new(path, self.baud_rate)
+            .parity(self.parity)
+            .data_bits(self.data_bits)
+            .stop_bits(self.stop_bits)
+            .flow_control(self.flow_control)
+    }
+}
+
+/// A setting that isn't one of the allowed values
+#[derive(Debug)]
+pub struct InvalidValue(&'static str);
+
+impl std::error::Error for InvalidValue {}
+
+impl std::fmt::Display for InvalidValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "expected {}", self.0)
+    }
+}
+
+pub fn parse_baud_rate(s: &str) -> Result<u32, InvalidValue> {
+    match s.parse() {
+        Ok(rate) if rate > 0 => Ok(rate),
+        _ => Err(InvalidValue("a positive integer")),
+    }
+}
+
+pub fn parse_parity(s: &str) -> Result<Parity, InvalidValue> {
+    match s.to_lowercase().as_str() {
+        "none" | "n" => Ok(Parity::None),
+        "even" | "e" => Ok(Parity::Even),
+        "odd" | "o" => Ok(Parity::Odd),
+        _ => Err(InvalidValue("none, even or odd")),
+    }
+}
+
+pub fn parse_data_bits(s: &str) -> Result<DataBits, InvalidValue> {
+    match s {
+        "5" => Ok(DataBits::Five),
+        "6" => Ok(DataBits::Six),
+        "7" => Ok(DataBits::Seven),
+        "8" => Ok(DataBits::Eight),
+        _ => Err(InvalidValue("5, 6, 7 or 8")),
+    }
+}
+
+pub fn parse_stop_bits(s: &str) -> Result<StopBits, InvalidValue> {
+    match s {
+        "1" => Ok(StopBits::One),
+        "2" => Ok(StopBits::Two),
+        _ => Err(InvalidValue("1 or 2")),
+    }
+}
+
+pub fn parse_flow_control(s: &str) -> Result<FlowControl, InvalidValue> {
+    match s.to_lowercase().as_str() {
+        "none" => Ok(FlowControl::None),
+        "software" => Ok(FlowControl::Software),
+        "hardware" => Ok(FlowControl::Hardware),
+        _ => Err(InvalidValue("none, software or hardware")),
+    }
+}
+
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct PortInfo {
+    pub name: String,
+    /// usb, pci, bluetooth or unknown
+    #[serde(rename = "type")]
+    pub port_type: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vid: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pid: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub serial_number: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub manufacturer: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub product: Option<String>,
+}
+
+impl From<SerialPortInfo> for PortInfo {
+    fn from(info: SerialPortInfo) -> Self {
+        let name = info.port_name;
+        match info.port_type {
+            SerialPortType::UsbPort(usb) => PortInfo {
+                name,
+                port_type: "usb",
+                vid: Some(usb.vid),
+                pid: Some(usb.pid),
+                serial_number: usb.serial_number,
+                manufacturer: usb.manufacturer,
+                product: usb.product,
+            },
+            SerialPortType::PciPort => PortInfo {
+                name,
+                port_type: "pci",
+                ..PortInfo::default()
+            },
+            SerialPortType::BluetoothPort => PortInfo {
+                name,
+                port_type: "bluetooth",
+                ..PortInfo::default()
+            },
+            SerialPortType::Unknown => PortInfo {
+                name,
+                port_type: "unknown",
+                ..PortInfo::default()
+            },
+        }
+    }
+}
+
+/// Serial ports present on the system
+pub fn available() -> DynResult<Vec<PortInfo>> {
+    let mut ports: Vec<PortInfo> = tokio_serial::available_ports()?
+        .into_iter()
+        .map(PortInfo::from)
+        .collect();
+    ports.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(ports)
+}
+
+/// One line per port
+pub fn to_text(ports: &[PortInfo]) -> String {
+    let mut text = String::new();
+    for port in ports {
+        text += &port.name;
+        text += &format!(" {}", port.port_type);
+        if let (Some(vid), Some(pid)) = (port.vid, port.pid) {
+            text += &format!(" {vid:04x}:{pid:04x}");
+        }
+        if let Some(serial) = &port.serial_number {
+            text += &format!(" serial {serial}");
+        }
+        for s in [&port.manufacturer, &port.product].into_iter().flatten() {
+            text += &format!(" \"{s}\"");
+        }
+        text.push('\n');
+    }
+    text
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn port_info_test() {
+        let ports = [
+            PortInfo {
+                name: "/dev/ttyUSB0".to_string(),
+                port_type: "usb",
+                vid: Some(0x0403),
+                pid: Some(0x6001),
+                serial_number: Some("A50285BI".to_string()),
+                manufacturer: Some("FTDI".to_string()),
+                product: None,
+            },
+            PortInfo::from(SerialPortInfo {
+                port_name: "/dev/ttyS0".to_string(),
+                port_type: SerialPortType::Unknown,
+            }),
+        ];
+        assert_eq!(
+            to_text(&ports),
+            "/dev/ttyUSB0 usb 0403:6001 serial A50285BI \"FTDI\"\n/dev/ttyS0 unknown\n"
+        );
+        assert_eq!(
+            serde_json::to_string(&ports[1]).unwrap(),
+            r#"{"name":"/dev/ttyS0","type":"unknown"}"#
+        );
+    }
+
+    #[test]
+    fn parse_settings_test() {
+        assert_eq!(parse_parity("Even").unwrap(), Parity::Even);
+        assert_eq!(parse_parity("n").unwrap(), Parity::None);
+        assert!(parse_parity("evn").is_err());
+        assert_eq!(parse_data_bits("7").unwrap(), DataBits::Seven);
+        assert!(parse_data_bits("9").is_err());
+        assert_eq!(parse_stop_bits("2").unwrap(), StopBits::Two);
+        assert_eq!(
+            parse_flow_control("Hardware").unwrap(),
+            FlowControl::Hardware
+        );
+        assert!(parse_baud_rate("0").is_err());
+        assert_eq!(
+            parse_flow_control("xon").unwrap_err().to_string(),
+            "expected none, software or hardware"
+        );
+    }
+}
@@ -1,5 +1,6 @@
 use super::encoding::Encoding;
 use super::presentation::Presentation;
+use std::time::Duration;
 
 #[derive(Debug)]
 pub struct IntegerEnum {
@@ -7,6 +8,15 @@ pub struct IntegerEnum {
     pub label: String,
 }
 
+/// Access allowed to a tag over Modbus
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Access {
+    #[default]
+    ReadWrite,
+    ReadOnly,
+    WriteOnly,
+}
+
 #[derive(Debug)]
 pub struct RegisterRange {
     pub address_low: u16,           // Lowest address for this range
@@ -17,6 +27,8 @@ pub struct RegisterRange {
     pub presentation: Presentation, // How the value should be displayed
     pub encoding: Encoding,         // How the value is envoded in the range
     pub enums: Vec<IntegerEnum>,    // Enumerated values for this register
+    pub access: Access,             // Restricts reads or writes by a client
+    pub overridden: bool,           // Value replaced in forwarded traffic
 }
 
 #[derive(Debug)]
@@ -28,9 +40,34 @@ pub struct RegisterField {
     pub enums: Vec<IntegerEnum>, // Enumerated values for this register
 }
 
+/// When a client reads tags from the remote device
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum Poll {
+    /// Repeatedly at this interval, or at the default interval if None
+    Cyclic(Option<Duration>),
+    /// Once at start and whenever the values are requested by the web
+    /// interface
+    OnDemand,
+    Never,
+    #[default]
+    Inherit,
+}
+
+impl Poll {
+    /// The setting of an element inside an element with the setting `self`
+    pub fn inner(self, inner: Poll) -> Poll {
+        match (self, inner) {
+            (outer, Poll::Inherit) => outer,
+            (Poll::Cyclic(interval), Poll::Cyclic(None)) => Poll::Cyclic(interval),
+            (_, inner) => inner,
+        }
+    }
+}
+
 pub struct Group<T> {
     pub base_address: u16, // Register addresses in this group are offset by this amount
     pub label: Option<String>,
+    pub poll: Poll,
     pub tags: Vec<TagOrGroup<T>>,
 }
 
@@ -84,6 +121,8 @@ pub struct Bit {
     pub address: u16,
     pub label: Option<String>,
     pub initial_value: Option<bool>,
+    pub access: Access,
+    pub overridden: bool,
 }
 
 pub enum TagOrGroup<T> {
@@ -94,9 +133,30 @@ pub enum TagOrGroup<T> {
 pub type RegisterOrGroup = TagOrGroup<RegisterRange>;
 pub type BitOrGroup = TagOrGroup<Bit>;
 
+/// Maximum number of values in a FIFO queue
+pub const FIFO_MAX_LEN: usize = 31;
+
+/// Queue read with Read FIFO Queue (FC 24)
+pub struct FifoQueue {
+    pub address: u16, // Address of the FIFO pointer register
+    pub label: Option<String>,
+    pub initial_value: Vec<u16>,
+}
+
+/// File accessed with Read/Write File Record (FC 20/21).
+/// Records are described like registers, with the record number as address.
+pub struct FileDef {
+    pub number: u16,
+    pub label: Option<String>,
+    pub records: Vec<RegisterOrGroup>,
+}
+
+#[derive(Default)]
 pub struct TagDefList {
     pub input_registers: Vec<RegisterOrGroup>,
     pub holding_registers: Vec<RegisterOrGroup>,
     pub discrete_inputs: Vec<BitOrGroup>,
     pub coils: Vec<BitOrGroup>,
+    pub fifo_queues: Vec<FifoQueue>,
+    pub files: Vec<FileDef>,
 }
@@ -130,6 +130,9 @@ impl BuildTag for RegisterRange {
         if let Some(label) = &self.label {
             map_insert_str(&mut map, "label", label);
         }
+        if self.overridden {
+            map.insert("override".to_string(), Value::Bool(true));
+        }
         presentation_attributes(&mut map, &self.presentation);
         encoding_attributes(&mut map, &self.encoding);
 
@@ -159,6 +162,9 @@ impl BuildTag for Bit {
         if let Some(label) = &self.label {
             map_insert_str(&mut map, "label", label);
         }
+        if self.overridden {
+            map.insert("override".to_string(), Value::Bool(true));
+        }
         Value::Object(map)
     }
 }
@@ -1,7 +1,9 @@
 use crate::encoding::{ByteOrder, Encoding, ValueType, WordOrder};
 use crate::presentation::Presentation;
+use crate::records::FILE_RECORDS;
 use crate::tag_list::{
-    Bit, BitOrGroup, Group, IntegerEnum, RegisterField, RegisterOrGroup, RegisterRange, TagDefList,
+    Access, Bit, BitOrGroup, FifoQueue, FileDef, Group, IntegerEnum, Poll, RegisterField,
+    RegisterOrGroup, RegisterRange, TagDefList, TagOrGroup, FIFO_MAX_LEN,
 };
 use crate::xml_common::ParseErrorKind::UnexpectedElement;
 use crate::xml_common::{self, check_element_ns, optional_attribute, required_attribute};
@@ -9,6 +11,7 @@ use roxmltree::Node;
 
 use std::num::ParseIntError;
 use std::str::{FromStr, ParseBoolError};
+use std::time::Duration;
 
 pub type ParseError = xml_common::ParseErrorBase<ParseErrorKind>;
 
@@ -30,6 +33,11 @@ pub enum ParseErrorKind {
     InvalidWordOrder,
     InvalidSign,
     InvalidValueType,
+    FifoTooLong,
+    InvalidFileNumber,
+    RecordOutOfRange,
+    InvalidAccess,
+    InvalidPoll,
 }
 
 impl std::fmt::Display for ParseErrorKind {
@@ -47,6 +55,18 @@ impl std::fmt::Display for ParseErrorKind {
                 f,
                 "Attribute 'value-type' must be one of 'integer', 'float', or 'string'"
             ),
+            FifoTooLong => write!(f, "A FIFO queue can't hold more than 31 values"),
+            InvalidFileNumber => write!(f, "File number must be in the range 1 to 65535"),
+            RecordOutOfRange => write!(f, "Record numbers must be in the range 0 to 9999"),
+            InvalidAccess => write!(
+                f,
+                "Attribute 'access' must be one of 'read-write', 'read-only', or 'write-only'"
+            ),
+            InvalidPoll => write!(
+                f,
+                "Attribute 'poll' must be one of 'cyclic', 'on-demand', or 'never', \
+                 'poll-interval' must be positive and can only be used with 'cyclic'"
+            ),
         }
     }
 }
@@ -122,6 +142,31 @@ pub fn parse_encoding(node: &Node) -> Result<Encoding, ParseError> {
     })
 }
 
+pub fn parse_access(node: &Node) -> Result<Access, ParseError> {
+    match optional_attribute::<String>(node, "access")?.as_deref() {
+        Some("read-write") | None => Ok(Access::ReadWrite),
+        Some("read-only") => Ok(Access::ReadOnly),
+        Some("write-only") => Ok(Access::WriteOnly),
+        Some(_) => Err(ParseError::new(node, ParseErrorKind::InvalidAccess)),
+    }
+}
+
+/// Attributes 'poll' and 'poll-interval'
+pub fn parse_poll(node: &Node) -> Result<Poll, ParseError> {
+    let interval = optional_attribute::<u64>(node, "poll-interval")?;
+    if interval == Some(0) {
+        return Err(ParseError::new(node, ParseErrorKind::InvalidPoll));
+    }
+    let interval = interval.map(Duration::from_millis);
+    match optional_attribute::<String>(node, "poll")?.as_deref() {
+        None if interval.is_none() => Ok(Poll::Inherit),
+        None | Some("cyclic") => Ok(Poll::Cyclic(interval)),
+        Some("on-demand") if interval.is_none() => Ok(Poll::OnDemand),
+        Some("never") if interval.is_none() => Ok(Poll::Never),
+        Some(_) => Err(ParseError::new(node, ParseErrorKind::InvalidPoll)),
+    }
+}
+
 pub fn parse_enum(node: &Node) -> Result<IntegerEnum, ParseError> {
     let label: String = required_attribute(node, "label")?;
     let value = required_attribute::<ParsedU16>(node, "value")?.into();
@@ -174,6 +219,8 @@ pub fn parse_register(node: &Node) -> Result<RegisterRange, ParseError> {
     let initial_value: Option<String> = optional_attribute(node, "initial-value")?;
     let presentation = parse_presentation(node)?;
     let encoding = parse_encoding(node)?;
+    let access = parse_access(node)?;
+    let overridden = optional_attribute(node, "override")?.unwrap_or(false);
 
     let mut fields = Vec::new();
     let mut enums = Vec::new();
@@ -201,6 +248,8 @@ pub fn parse_register(node: &Node) -> Result<RegisterRange, ParseError> {
         presentation,
         encoding,
         enums,
+        access,
+        overridden,
     })
 }
 
@@ -209,10 +258,12 @@ pub fn parse_reg_group(node: &Node) -> Result<Group<RegisterRange>, ParseError>
         .map(|v| u16::from(v))
         .unwrap_or(0u16);
     let label: Option<String> = optional_attribute(node, "label")?;
+    let poll = parse_poll(node)?;
     let tags = parse_registers_or_groups(node)?;
     Ok(Group::<RegisterRange> {
         base_address,
         label,
+        poll,
         tags,
     })
 }
@@ -260,7 +311,8 @@ impl From<ParsedBit> for bool {
     }
 }
 
-struct ParsedU16(u16);
+/// Integer attribute in decimal, hex (0x) or binary (0b)
+pub struct ParsedU16(u16);
 impl FromStr for ParsedU16 {
     type Err = ParseIntError;
     fn from_str(s: &str) -> Result<Self, ParseIntError> {
@@ -292,11 +344,15 @@ pub fn parse_bit(node: &Node) -> Result<Bit, ParseError> {
     let label: Option<String> = optional_attribute(node, "label")?;
     let initial_value: Option<bool> =
         optional_attribute::<ParsedBit>(node, "initial-value")?.map(|b| b.into());
+    let access = parse_access(node)?;
+    let overridden = optional_attribute(node, "override")?.unwrap_or(false);
 
     Ok(Bit {
         address,
         label,
         initial_value,
+        access,
+        overridden,
     })
 }
 pub fn parse_bit_group(node: &Node) -> Result<Group<Bit>, ParseError> {
@@ -304,10 +360,12 @@ pub fn parse_bit_group(node: &Node) -> Result<Group<Bit>, ParseError> {
         .map(|v| u16::from(v))
         .unwrap_or(0u16);
     let label: Option<String> = optional_attribute(node, "label")?;
+    let poll = parse_poll(node)?;
     let tags = parse_bits_or_groups(node)?;
     Ok(Group::<Bit> {
         base_address,
         label,
+        poll,
         tags,
     })
 }
@@ -333,42 +391,141 @@ pub fn parse_bits_or_groups(parent: &Node) -> Result<Vec<BitOrGroup>, ParseError
     Ok(bits)
 }
 
-pub fn parse_tag_list(node: &Node) -> Result<TagDefList, ParseError> {
-    let mut holding_registers = None;
-    let mut input_registers = None;
-    let mut discrete_inputs = None;
-    let mut coils = None;
-    for child in node.children() {
+pub fn parse_fifo(node: &Node) -> Result<FifoQueue, ParseError> {
+    let address: u16 = required_attribute::<ParsedU16>(node, "addr")?.into();
+    let label: Option<String> = optional_attribute(node, "label")?;
+    let initial_value = match node.attribute("initial-value") {
+        Some(values) => values
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|v| !v.is_empty())
+            .map(|v| v.parse::<ParsedU16>().map(u16::from))
+            .collect::<Result<Vec<u16>, _>>()
+            .map_err(|e| {
+                ParseError::new(
+                    node,
+                    Base(xml_common::ParseErrorKind::ParseAttribute(
+                        "initial-value".to_string(),
+                        e.into(),
+                    )),
+                )
+            })?,
+        None => Vec::new(),
+    };
+    if initial_value.len() > FIFO_MAX_LEN {
+        return Err(ParseError::new(node, FifoTooLong));
+    }
+    Ok(FifoQueue {
+        address,
+        label,
+        initial_value,
+    })
+}
+
+pub fn parse_fifo_queues(parent: &Node) -> Result<Vec<FifoQueue>, ParseError> {
+    let mut fifos = Vec::new();
+    for child in parent.children() {
         if check_element_ns(&child)? {
             match child.tag_name().name() {
-                "holding-registers" => {
-                    let regs = parse_registers_or_groups(&child)?;
-                    holding_registers = Some(regs);
-                }
-                "input-registers" => {
-                    let regs = parse_registers_or_groups(&child)?;
-                    input_registers = Some(regs);
-                }
-                "discrete-inputs" => {
-                    let bits = parse_bits_or_groups(&child)?;
-                    discrete_inputs = Some(bits);
-                }
-                "coils" => {
-                    let bits = parse_bits_or_groups(&child)?;
-                    coils = Some(bits);
-                }
+                "fifo" => fifos.push(parse_fifo(&child)?),
                 _ => return Err(ParseError::new(&child, Base(UnexpectedElement))),
             }
         }
     }
-    Ok(TagDefList {
-        holding_registers: holding_registers.unwrap_or_default(),
-        input_registers: input_registers.unwrap_or_default(),
-        discrete_inputs: discrete_inputs.unwrap_or_default(),
-        coils: coils.unwrap_or_default(),
+    Ok(fifos)
+}
+
+// Check that every record, including all words of its value, is within
+// the file when the base addresses of the groups are added
+fn check_records(
+    node: &Node,
+    records: &[RegisterOrGroup],
+    base_address: u16,
+) -> Result<(), ParseError> {
+    for record in records {
+        let fits = match record {
+            TagOrGroup::Tag(reg) => base_address
+                .checked_add(reg.address_high)
+                .is_some_and(|high| usize::from(high) < FILE_RECORDS),
+            TagOrGroup::Group(group) => match base_address.checked_add(group.base_address) {
+                Some(base_address) => {
+                    check_records(node, &group.tags, base_address)?;
+                    true
+                }
+                None => false,
+            },
+        };
+        if !fits {
+            return Err(ParseError::new(node, RecordOutOfRange));
+        }
+    }
+    Ok(())
+}
+
+pub fn parse_file(node: &Node) -> Result<FileDef, ParseError> {
+    let number: u16 = required_attribute::<ParsedU16>(node, "number")?.into();
+    if number == 0 {
+        return Err(ParseError::new(node, InvalidFileNumber));
+    }
+    let label: Option<String> = optional_attribute(node, "label")?;
+    let records = parse_registers_or_groups(node)?;
+    check_records(node, &records, 0)?;
+    Ok(FileDef {
+        number,
+        label,
+        records,
     })
 }
 
+pub fn parse_files(parent: &Node) -> Result<Vec<FileDef>, ParseError> {
+    let mut files = Vec::new();
+    for child in parent.children() {
+        if check_element_ns(&child)? {
+            match child.tag_name().name() {
+                "file" => files.push(parse_file(&child)?),
+                _ => return Err(ParseError::new(&child, Base(UnexpectedElement))),
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// Parse an element that is part of a tag list and add it to `tag_list`.
+/// Returns false if the element doesn't belong to a tag list.
+pub fn parse_tag_list_child(tag_list: &mut TagDefList, child: &Node) -> Result<bool, ParseError> {
+    match child.tag_name().name() {
+        "holding-registers" => {
+            tag_list.holding_registers = parse_registers_or_groups(child)?;
+        }
+        "input-registers" => {
+            tag_list.input_registers = parse_registers_or_groups(child)?;
+        }
+        "discrete-inputs" => {
+            tag_list.discrete_inputs = parse_bits_or_groups(child)?;
+        }
+        "coils" => {
+            tag_list.coils = parse_bits_or_groups(child)?;
+        }
+        "fifo-queues" => {
+            tag_list.fifo_queues = parse_fifo_queues(child)?;
+        }
+        "files" => {
+            tag_list.files = parse_files(child)?;
+        }
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
+pub fn parse_tag_list(node: &Node) -> Result<TagDefList, ParseError> {
+    let mut tag_list = TagDefList::default();
+    for child in node.children() {
+        if check_element_ns(&child)? && !parse_tag_list_child(&mut tag_list, &child)? {
+            return Err(ParseError::new(&child, Base(UnexpectedElement)));
+        }
+    }
+    Ok(tag_list)
+}
+
 #[cfg(test)]
 use roxmltree::Document;
 
@@ -407,3 +564,69 @@ fn parse_register_test() -> Result<(), ParseError> {
     assert!(reg.fields.is_empty());
     Ok(())
 }
+
+#[test]
+fn parse_records_test() -> Result<(), ParseError> {
+    let doc = Document::parse(
+        r#"
+<tag-list xmlns="http://www.elektro-kapsel.se/xml/modbus_config/v2">
+  <fifo-queues>
+    <fifo addr="0x100" label="Events" initial-value="1, 2 0x10"/>
+    <fifo addr="0x200"/>
+  </fifo-queues>
+  <files>
+    <file number="4" label="Log">
+      <register-range addr-low="0" addr-high="9" label="Entries"/>
+    </file>
+  </files>
+</tag-list>
+"#,
+    )
+    .unwrap();
+
+    let tag_list = parse_tag_list(&doc.root_element())?;
+    assert_eq!(tag_list.fifo_queues.len(), 2);
+    assert_eq!(tag_list.fifo_queues[0].address, 0x100);
+    assert_eq!(tag_list.fifo_queues[0].label, Some("Events".to_string()));
+    assert_eq!(tag_list.fifo_queues[0].initial_value, vec![1, 2, 0x10]);
+    assert!(tag_list.fifo_queues[1].initial_value.is_empty());
+    assert_eq!(tag_list.files.len(), 1);
+    assert_eq!(tag_list.files[0].number, 4);
+    let RegisterOrGroup::Tag(reg) = &tag_list.files[0].records[0] else {
+        panic!("Not a tag");
+    };
+    assert_eq!(reg.address_high, 9);
+    Ok(())
+}
+
+#[test]
+fn parse_records_range_test() {
+    let parse = |records: &str| {
+        let xml = format!(
+            r#"
+<tag-list xmlns="http://www.elektro-kapsel.se/xml/modbus_config/v2">
+  <files>
+    <file number="1">
+      {records}
+    </file>
+  </files>
+</tag-list>
+"#
+        );
+        let doc = Document::parse(&xml).unwrap();
+        parse_tag_list(&doc.root_element()).map(|_| ())
+    };
+    assert!(parse(r#"<register addr="9999"/>"#).is_ok());
+    assert!(parse(r#"<group base-addr="9990"><register addr="9"/></group>"#).is_ok());
+    let err = parse(r#"<register addr="10000"/>"#).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "4:5: Record numbers must be in the range 0 to 9999"
+    );
+    // The second word of the value is past the last record
+    assert!(parse(r#"<register-range addr-low="9999" addr-high="10000"/>"#).is_err());
+    assert!(parse(r#"<group base-addr="9990"><register addr="10"/></group>"#).is_err());
+    // The address would wrap around
+    let nested = r#"<group base-addr="2"><register addr="0"/></group>"#;
+    assert!(parse(&format!(r#"<group base-addr="65535">{nested}</group>"#)).is_err());
+}
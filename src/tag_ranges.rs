@@ -1,5 +1,32 @@
 use crate::range_array::RangeArray;
-use crate::tag_list::{BitOrGroup, RegisterOrGroup, TagDefList};
+use crate::tag_list::{Access, BitOrGroup, Poll, RegisterOrGroup, TagDefList};
+use std::collections::BTreeMap;
+
+/// Addresses with restricted access
+#[derive(Debug, Default)]
+pub struct AccessRanges {
+    pub read_only: RangeArray<u16>,
+    pub write_only: RangeArray<u16>,
+}
+
+impl AccessRanges {
+    fn add(&mut self, access: Access, range: &std::ops::Range<u16>) {
+        match access {
+            Access::ReadWrite => {}
+            Access::ReadOnly => self.read_only.union(range),
+            Access::WriteOnly => self.write_only.union(range),
+        }
+    }
+}
+
+/// Addresses read with the same poll setting in client mode
+#[derive(Debug, Default)]
+pub struct PollRanges {
+    pub holding_registers: RangeArray<u16>,
+    pub input_registers: RangeArray<u16>,
+    pub discrete_inputs: RangeArray<u16>,
+    pub coils: RangeArray<u16>,
+}
 
 #[derive(Debug)]
 pub struct TagRanges {
@@ -7,6 +34,17 @@ pub struct TagRanges {
     pub input_registers: RangeArray<u16>,
     pub discrete_inputs: RangeArray<u16>,
     pub coils: RangeArray<u16>,
+    pub holding_register_access: AccessRanges,
+    pub input_register_access: AccessRanges,
+    pub discrete_input_access: AccessRanges,
+    pub coil_access: AccessRanges,
+    // Replaced in forwarded traffic
+    pub holding_register_overrides: RangeArray<u16>,
+    pub input_register_overrides: RangeArray<u16>,
+    pub discrete_input_overrides: RangeArray<u16>,
+    pub coil_overrides: RangeArray<u16>,
+    // Inherit isn't used as a key
+    pub polls: BTreeMap<Poll, PollRanges>,
 }
 
 impl TagRanges {
@@ -16,8 +54,53 @@ impl TagRanges {
             input_registers: RangeArray::new(),
             discrete_inputs: RangeArray::new(),
             coils: RangeArray::new(),
+            holding_register_access: AccessRanges::default(),
+            input_register_access: AccessRanges::default(),
+            discrete_input_access: AccessRanges::default(),
+            coil_access: AccessRanges::default(),
+            holding_register_overrides: RangeArray::new(),
+            input_register_overrides: RangeArray::new(),
+            discrete_input_overrides: RangeArray::new(),
+            coil_overrides: RangeArray::new(),
+            polls: BTreeMap::new(),
         }
     }
+
+    /// Tags outside groups with their own setting are polled according
+    /// to `poll`
+    pub fn with_poll(tag_list: &TagDefList, poll: Poll) -> Self {
+        let mut ranges = Self::from(tag_list);
+        let poll = Poll::Cyclic(None).inner(poll);
+        get_register_polls(
+            &mut ranges.polls,
+            |p| &mut p.holding_registers,
+            poll,
+            0,
+            &tag_list.holding_registers,
+        );
+        get_register_polls(
+            &mut ranges.polls,
+            |p| &mut p.input_registers,
+            poll,
+            0,
+            &tag_list.input_registers,
+        );
+        get_bit_polls(
+            &mut ranges.polls,
+            |p| &mut p.discrete_inputs,
+            poll,
+            0,
+            &tag_list.discrete_inputs,
+        );
+        get_bit_polls(
+            &mut ranges.polls,
+            |p| &mut p.coils,
+            poll,
+            0,
+            &tag_list.coils,
+        );
+        ranges
+    }
 }
 
 impl Default for TagRanges {
@@ -28,47 +111,149 @@ impl Default for TagRanges {
 
 fn get_register_ranges(
     ranges: &mut RangeArray<u16>,
+    access: &mut AccessRanges,
+    overrides: &mut RangeArray<u16>,
     base_address: u16,
     registers: &[RegisterOrGroup],
 ) {
     for reg in registers {
         match reg {
             RegisterOrGroup::Tag(r) => {
-                ranges.union(&(r.address_low + base_address..r.address_high + base_address + 1));
+                let range = r.address_low + base_address..r.address_high + base_address + 1;
+                ranges.union(&range);
+                access.add(r.access, &range);
+                if r.overridden {
+                    overrides.union(&range);
+                }
             }
             RegisterOrGroup::Group(g) => {
-                get_register_ranges(ranges, base_address + g.base_address, &g.tags);
+                get_register_ranges(
+                    ranges,
+                    access,
+                    overrides,
+                    base_address + g.base_address,
+                    &g.tags,
+                );
             }
         }
     }
 }
 
-fn get_bit_ranges(ranges: &mut RangeArray<u16>, base_address: u16, bits: &[BitOrGroup]) {
+fn get_bit_ranges(
+    ranges: &mut RangeArray<u16>,
+    access: &mut AccessRanges,
+    overrides: &mut RangeArray<u16>,
+    base_address: u16,
+    bits: &[BitOrGroup],
+) {
     for reg in bits {
         match reg {
             BitOrGroup::Tag(b) => {
                 let addr = b.address + base_address;
                 ranges.union(&(addr..addr + 1));
+                access.add(b.access, &(addr..addr + 1));
+                if b.overridden {
+                    overrides.union(&(addr..addr + 1));
+                }
             }
             BitOrGroup::Group(g) => {
-                get_bit_ranges(ranges, base_address + g.base_address, &g.tags);
+                get_bit_ranges(
+                    ranges,
+                    access,
+                    overrides,
+                    base_address + g.base_address,
+                    &g.tags,
+                );
             }
         }
     }
 }
+
+fn get_register_polls(
+    polls: &mut BTreeMap<Poll, PollRanges>,
+    table: fn(&mut PollRanges) -> &mut RangeArray<u16>,
+    poll: Poll,
+    base_address: u16,
+    registers: &[RegisterOrGroup],
+) {
+    for reg in registers {
+        match reg {
+            RegisterOrGroup::Tag(r) => {
+                let range = r.address_low + base_address..r.address_high + base_address + 1;
+                table(polls.entry(poll).or_default()).union(&range);
+            }
+            RegisterOrGroup::Group(g) => {
+                get_register_polls(
+                    polls,
+                    table,
+                    poll.inner(g.poll),
+                    base_address + g.base_address,
+                    &g.tags,
+                );
+            }
+        }
+    }
+}
+
+fn get_bit_polls(
+    polls: &mut BTreeMap<Poll, PollRanges>,
+    table: fn(&mut PollRanges) -> &mut RangeArray<u16>,
+    poll: Poll,
+    base_address: u16,
+    bits: &[BitOrGroup],
+) {
+    for bit in bits {
+        match bit {
+            BitOrGroup::Tag(b) => {
+                let addr = b.address + base_address;
+                table(polls.entry(poll).or_default()).union(&(addr..addr + 1));
+            }
+            BitOrGroup::Group(g) => {
+                get_bit_polls(
+                    polls,
+                    table,
+                    poll.inner(g.poll),
+                    base_address + g.base_address,
+                    &g.tags,
+                );
+            }
+        }
+    }
+}
+
 impl From<&TagDefList> for TagRanges {
     fn from(tag_list: &TagDefList) -> Self {
         let mut ranges = Self::new();
-        get_register_ranges(&mut ranges.input_registers, 0, &tag_list.input_registers);
+        get_register_ranges(
+            &mut ranges.input_registers,
+            &mut ranges.input_register_access,
+            &mut ranges.input_register_overrides,
+            0,
+            &tag_list.input_registers,
+        );
 
         get_register_ranges(
             &mut ranges.holding_registers,
+            &mut ranges.holding_register_access,
+            &mut ranges.holding_register_overrides,
             0,
             &tag_list.holding_registers,
         );
 
-        get_bit_ranges(&mut ranges.discrete_inputs, 0, &tag_list.discrete_inputs);
-        get_bit_ranges(&mut ranges.coils, 0, &tag_list.coils);
+        get_bit_ranges(
+            &mut ranges.discrete_inputs,
+            &mut ranges.discrete_input_access,
+            &mut ranges.discrete_input_overrides,
+            0,
+            &tag_list.discrete_inputs,
+        );
+        get_bit_ranges(
+            &mut ranges.coils,
+            &mut ranges.coil_access,
+            &mut ranges.coil_overrides,
+            0,
+            &tag_list.coils,
+        );
 
         ranges
     }
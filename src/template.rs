@@ -82,6 +82,44 @@ where
                 "discrete_inputs".to_string(),
                 tag_list_json::build_bit_list(device.addr, &tags.discrete_inputs),
             );
+            let mut fifo_list = Vec::new();
+            for fifo in &tags.fifo_queues {
+                let mut fifo_map = Map::new();
+                fifo_map.insert(
+                    "unit_addr".to_string(),
+                    Value::Number(Number::from(device.addr)),
+                );
+                fifo_map.insert(
+                    "addr".to_string(),
+                    Value::Number(Number::from(fifo.address)),
+                );
+                if let Some(label) = &fifo.label {
+                    fifo_map.insert("label".to_string(), Value::String(label.clone()));
+                }
+                fifo_list.push(Value::Object(fifo_map));
+            }
+            tag_map.insert("fifo_queues".to_string(), Value::Array(fifo_list));
+            let mut file_list = Vec::new();
+            for file in &tags.files {
+                let mut file_map = Map::new();
+                file_map.insert(
+                    "unit_addr".to_string(),
+                    Value::Number(Number::from(device.addr)),
+                );
+                file_map.insert(
+                    "number".to_string(),
+                    Value::Number(Number::from(file.number)),
+                );
+                if let Some(label) = &file.label {
+                    file_map.insert("label".to_string(), Value::String(label.clone()));
+                }
+                file_map.insert(
+                    "records".to_string(),
+                    tag_list_json::build_register_list(device.addr, &file.records),
+                );
+                file_list.push(Value::Object(file_map));
+            }
+            tag_map.insert("files".to_string(), Value::Array(file_list));
             device_list.push(Value::Object(tag_map));
         }
         let xml = match templates
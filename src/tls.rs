@@ -0,0 +1,295 @@
+// Modbus/TCP Security. TLS with mutual authentication using openssl.
+
+use openssl::error::ErrorStack;
+use openssl::ssl::{Ssl, SslAcceptor, SslConnector, SslMethod, SslRef, SslVerifyMode, SslVersion};
+use std::io;
+use std::path::PathBuf;
+use std::pin::Pin;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_openssl::SslStream;
+use x509_parser::asn1_rs::{Oid, oid};
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+/// Default port for Modbus/TCP Security
+pub const PORT: u16 = 802;
+
+// Certificate extension holding the role of the client
+const ROLE_OID: Oid<'static> = oid!(1.3.6.1.4.1.50316.802.1);
+
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    /// Own certificate chain in PEM format
+    pub cert: PathBuf,
+    /// Private key in PEM format
+    pub key: PathBuf,
+    /// CA certificates used to verify the peer
+    pub ca: PathBuf,
+    /// Name expected in the server certificate, the address is used if
+    /// not set. Only used by clients.
+    pub server_name: Option<String>,
+    /// Roles allowed to write. Everyone may write if empty. Only used by
+    /// servers.
+    pub write_roles: Vec<String>,
+}
+
+impl TlsConfig {
+    /// True if a client with this role may write
+    pub fn may_write(&self, role: Option<&str>) -> bool {
+        self.write_roles.is_empty() || role.is_some_and(|r| self.write_roles.iter().any(|w| w == r))
+    }
+}
+
+/// Server side configuration requiring client certificates
+pub fn acceptor(conf: &TlsConfig) -> Result<SslAcceptor, ErrorStack> {
+    let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls_server())?;
+    builder.set_min_proto_version(Some(SslVersion::TLS1_2))?;
+    builder.set_certificate_chain_file(&conf.cert)?;
+    builder.set_private_key_file(&conf.key, openssl::ssl::SslFiletype::PEM)?;
+    builder.check_private_key()?;
+    builder.set_ca_file(&conf.ca)?;
+    builder.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+    Ok(builder.build())
+}
+
+/// Client side configuration presenting a certificate
+pub fn connector(conf: &TlsConfig) -> Result<SslConnector, ErrorStack> {
+    let mut builder = SslConnector::builder(SslMethod::tls_client())?;
+    builder.set_min_proto_version(Some(SslVersion::TLS1_2))?;
+    builder.set_certificate_chain_file(&conf.cert)?;
+    builder.set_private_key_file(&conf.key, openssl::ssl::SslFiletype::PEM)?;
+    builder.check_private_key()?;
+    builder.set_ca_file(&conf.ca)?;
+    Ok(builder.build())
+}
+
+// Find the role extension in a DER encoded certificate. The value is an
+// UTF8String.
+fn role_from_der(der: &[u8]) -> Option<String> {
+    let (_, cert) = X509Certificate::from_der(der).ok()?;
+    let ext = cert.extensions().iter().find(|ext| ext.oid == ROLE_OID)?;
+    let (_, role) = <&str>::from_der(ext.value).ok()?;
+    Some(role.to_string())
+}
+
+/// Role of the authenticated peer, from its certificate
+pub fn peer_role(ssl: &SslRef) -> Option<String> {
+    let cert = ssl.peer_certificate()?;
+    role_from_der(&cert.to_der().ok()?)
+}
+
+/// Perform the server side handshake
+pub async fn accept<S>(acceptor: &SslAcceptor, stream: S) -> io::Result<SslStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let ssl = Ssl::new(acceptor.context()).map_err(io::Error::other)?;
+    let mut tls = SslStream::new(ssl, stream).map_err(io::Error::other)?;
+    Pin::new(&mut tls)
+        .accept()
+        .await
+        .map_err(io::Error::other)?;
+    Ok(tls)
+}
+
+/// Perform the client side handshake, verifying that the server
+/// certificate matches `server_name`
+pub async fn connect<S>(
+    connector: &SslConnector,
+    server_name: &str,
+    stream: S,
+) -> io::Result<SslStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let ssl = connector
+        .configure()
+        .and_then(|c| c.into_ssl(server_name))
+        .map_err(io::Error::other)?;
+    let mut tls = SslStream::new(ssl, stream).map_err(io::Error::other)?;
+    Pin::new(&mut tls)
+        .connect()
+        .await
+        .map_err(io::Error::other)?;
+    Ok(tls)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use openssl::asn1::{Asn1Object, Asn1OctetString, Asn1Time};
+    use openssl::bn::BigNum;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::hash::MessageDigest;
+    use openssl::nid::Nid;
+    use openssl::pkey::{PKey, Private};
+    use openssl::x509::extension::{BasicConstraints, SubjectAlternativeName};
+    use openssl::x509::{X509, X509Extension, X509Name};
+    use std::path::Path;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    // DER encoding of the role extension OID
+    const ROLE_OID_DER: &[u8] = &[
+        0x06, 0x0b, 0x2b, 0x06, 0x01, 0x04, 0x01, 0x83, 0x89, 0x0c, 0x86, 0x22, 0x01,
+    ];
+
+    fn key() -> PKey<Private> {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap()
+    }
+
+    // Certificate signed by `issuer`, or self-signed CA if None
+    fn cert(
+        name: &str,
+        key: &PKey<Private>,
+        issuer: Option<(&X509, &PKey<Private>)>,
+        role: Option<&str>,
+    ) -> X509 {
+        let mut subject = X509Name::builder().unwrap();
+        subject.append_entry_by_text("CN", name).unwrap();
+        let subject = subject.build();
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        let serial = BigNum::from_u32(name.len() as u32).unwrap();
+        builder
+            .set_serial_number(&serial.to_asn1_integer().unwrap())
+            .unwrap();
+        builder.set_subject_name(&subject).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        builder.set_pubkey(key).unwrap();
+        let (issuer_name, issuer_key) = match issuer {
+            Some((cert, key)) => {
+                let san = SubjectAlternativeName::new()
+                    .dns(name)
+                    .build(&builder.x509v3_context(Some(cert), None))
+                    .unwrap();
+                builder.append_extension(san).unwrap();
+                (cert.subject_name().to_owned().unwrap(), key)
+            }
+            None => {
+                let ca = BasicConstraints::new().critical().ca().build().unwrap();
+                builder.append_extension(ca).unwrap();
+                (subject, key)
+            }
+        };
+        builder.set_issuer_name(&issuer_name).unwrap();
+        if let Some(role) = role {
+            let mut value = vec![0x0c, role.len() as u8];
+            value.extend_from_slice(role.as_bytes());
+            let ext = X509Extension::new_from_der(
+                &Asn1Object::from_str("1.3.6.1.4.1.50316.802.1").unwrap(),
+                false,
+                &Asn1OctetString::new_from_bytes(&value).unwrap(),
+            )
+            .unwrap();
+            builder.append_extension(ext).unwrap();
+        }
+        builder.sign(issuer_key, MessageDigest::sha256()).unwrap();
+        builder.build()
+    }
+
+    fn write_conf(dir: &Path, name: &str, cert: &X509, key: &PKey<Private>) -> TlsConfig {
+        let conf = TlsConfig {
+            cert: dir.join(format!("{name}.crt")),
+            key: dir.join(format!("{name}.key")),
+            ca: dir.join("ca.crt"),
+            server_name: None,
+            write_roles: vec!["operator".to_string()],
+        };
+        std::fs::write(&conf.cert, cert.to_pem().unwrap()).unwrap();
+        std::fs::write(&conf.key, key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        conf
+    }
+
+    #[test]
+    fn role_test() {
+        let key = key();
+        let cert = cert("client", &key, None, Some("operator"));
+        assert_eq!(
+            role_from_der(&cert.to_der().unwrap()).as_deref(),
+            Some("operator")
+        );
+
+        // The role must come from the extension, not from bytes that look
+        // like it elsewhere, here in the serial number
+        let spoofed = [ROLE_OID_DER, &[0x04, 0x0a, 0x0c, 0x08], b"operator"].concat();
+        let mut subject = X509Name::builder().unwrap();
+        subject.append_entry_by_text("CN", "client").unwrap();
+        let subject = subject.build();
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        let serial = BigNum::from_slice(&spoofed).unwrap();
+        builder
+            .set_serial_number(&serial.to_asn1_integer().unwrap())
+            .unwrap();
+        builder.set_subject_name(&subject).unwrap();
+        builder.set_issuer_name(&subject).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+        let der = builder.build().to_der().unwrap();
+        assert!(der.windows(spoofed.len()).any(|w| w == spoofed));
+        assert_eq!(role_from_der(&der), None);
+    }
+
+    #[tokio::test]
+    async fn handshake_test() {
+        let dir = std::env::temp_dir().join(format!("mb-tool-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let ca_key = key();
+        let ca = cert("ca", &ca_key, None, None);
+        std::fs::write(dir.join("ca.crt"), ca.to_pem().unwrap()).unwrap();
+        let server_key = key();
+        let server_cert = cert("localhost", &server_key, Some((&ca, &ca_key)), None);
+        let server_conf = write_conf(&dir, "server", &server_cert, &server_key);
+        let client_key = key();
+        let client_cert = cert(
+            "client",
+            &client_key,
+            Some((&ca, &ca_key)),
+            Some("operator"),
+        );
+        let client_conf = write_conf(&dir, "client", &client_cert, &client_key);
+
+        let acceptor = acceptor(&server_conf).unwrap();
+        let connector = connector(&client_conf).unwrap();
+        let (client, server) = tokio::io::duplex(256);
+        let server = tokio::spawn(async move {
+            let mut tls = accept(&acceptor, server).await.unwrap();
+            let role = peer_role(tls.ssl());
+            let mut buf = [0u8; 4];
+            tls.read_exact(&mut buf).await.unwrap();
+            tls.write_all(&buf).await.unwrap();
+            tls.flush().await.unwrap();
+            role
+        });
+        let mut tls = connect(&connector, "localhost", client).await.unwrap();
+        tls.write_all(b"ping").await.unwrap();
+        tls.flush().await.unwrap();
+        let mut buf = [0u8; 4];
+        tls.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+        let role = server.await.unwrap();
+        assert_eq!(role.as_deref(), Some("operator"));
+        assert!(server_conf.may_write(role.as_deref()));
+        assert!(!server_conf.may_write(Some("viewer")));
+        assert!(!server_conf.may_write(None));
+
+        // The server name must match the certificate
+        let connector = super::connector(&client_conf).unwrap();
+        let acceptor = super::acceptor(&server_conf).unwrap();
+        let (client, server) = tokio::io::duplex(256);
+        tokio::spawn(async move { accept(&acceptor, server).await });
+        assert!(connect(&connector, "other", client).await.is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
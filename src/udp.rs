@@ -0,0 +1,159 @@
+// Modbus over UDP. Every datagram holds one ADU with the same MBAP
+// header as Modbus TCP. Datagrams may be lost, so requests that aren't
+// answered in time are retried like on other transports.
+
+use crate::pdu;
+use async_trait::async_trait;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::net::UdpSocket;
+use tokio_modbus::client::{Client, Context};
+use tokio_modbus::slave::{Slave, SlaveContext};
+use tokio_modbus::{Request, Response};
+
+/// Maximum size of an ADU, MBAP header included
+pub const MAX_ADU_LEN: usize = 260;
+
+/// Size of the MBAP header, unit included
+pub const MBAP_LEN: usize = 7;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Header {
+    pub transaction: u16,
+    pub unit: u8,
+}
+
+pub fn encode_adu(header: &Header, pdu: &[u8]) -> Vec<u8> {
+    let mut adu = Vec::with_capacity(MBAP_LEN + pdu.len());
+    adu.extend_from_slice(&header.transaction.to_be_bytes());
+    adu.extend_from_slice(&0u16.to_be_bytes());
+    adu.extend_from_slice(&(pdu.len() as u16 + 1).to_be_bytes());
+    adu.push(header.unit);
+    adu.extend_from_slice(pdu);
+    adu
+}
+
+/// Split a datagram into header and PDU. Returns None if the header is
+/// invalid.
+pub fn decode_adu(datagram: &[u8]) -> Option<(Header, &[u8])> {
+    if datagram.len() <= MBAP_LEN {
+        return None;
+    }
+    let word = |i: usize| u16::from_be_bytes([datagram[i], datagram[i + 1]]);
+    if word(2) != 0 || usize::from(word(4)) != datagram.len() - 6 {
+        return None;
+    }
+    let header = Header {
+        transaction: word(0),
+        unit: datagram[6],
+    };
+    Some((header, &datagram[MBAP_LEN..]))
+}
+
+struct UdpClient {
+    socket: UdpSocket,
+    slave: Slave,
+    transaction: u16,
+}
+
+impl SlaveContext for UdpClient {
+    fn set_slave(&mut self, slave: Slave) {
+        self.slave = slave;
+    }
+}
+
+#[async_trait]
+impl Client for UdpClient {
+    async fn call(&mut self, request: Request<'_>) -> tokio_modbus::Result<Response> {
+        self.transaction = self.transaction.wrapping_add(1);
+        let header = Header {
+            transaction: self.transaction,
+            unit: self.slave.into(),
+        };
+        let adu = encode_adu(&header, &pdu::encode_request(&request));
+        let mut buf = [0u8; MAX_ADU_LEN];
+        self.socket.send(&adu).await?;
+        loop {
+            let n = self.socket.recv(&mut buf).await?;
+            // Late responses to earlier requests are ignored
+            if let Some((resp_header, pdu)) = decode_adu(&buf[..n])
+                && resp_header == header
+            {
+                return pdu::decode_response(&request, pdu);
+            }
+        }
+    }
+
+    async fn disconnect(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Create a client context sending requests to `server`
+pub async fn connect_slave(server: SocketAddr, slave: Slave) -> io::Result<Context> {
+    let local = match server {
+        SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(server).await?;
+    let client = UdpClient {
+        socket,
+        slave,
+        transaction: 0,
+    };
+    Ok(Context::from(Box::new(client) as Box<dyn Client>))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::time::{self, Duration};
+    use tokio_modbus::ExceptionCode;
+    use tokio_modbus::client::Reader;
+
+    #[test]
+    fn adu_test() {
+        let header = Header {
+            transaction: 0x1234,
+            unit: 5,
+        };
+        let adu = encode_adu(&header, &[0x03, 0, 1, 0, 2]);
+        assert_eq!(adu, [0x12, 0x34, 0, 0, 0, 6, 5, 0x03, 0, 1, 0, 2]);
+        assert_eq!(decode_adu(&adu), Some((header, &adu[7..])));
+        assert_eq!(decode_adu(&adu[..11]), None);
+    }
+
+    #[tokio::test]
+    async fn client_retry_test() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut ctxt = connect_slave(server.local_addr().unwrap(), Slave(1))
+            .await
+            .unwrap();
+        let responder = tokio::spawn(async move {
+            let mut buf = [0u8; MAX_ADU_LEN];
+            // Answer the first request only after the second one
+            let (n, _) = server.recv_from(&mut buf).await.unwrap();
+            let (stale, _) = decode_adu(&buf[..n]).unwrap();
+            let (n, peer) = server.recv_from(&mut buf).await.unwrap();
+            let (header, pdu) = decode_adu(&buf[..n]).unwrap();
+            assert_eq!(pdu, [0x03, 0, 0, 0, 1]);
+            assert_ne!(header, stale);
+            let reply = encode_adu(&stale, &[0x03, 2, 0, 0]);
+            server.send_to(&reply, peer).await.unwrap();
+            let reply = encode_adu(&header, &[0x83, 2]);
+            server.send_to(&reply, peer).await.unwrap();
+        });
+        let timeout = Duration::from_millis(50);
+        assert!(
+            time::timeout(timeout, ctxt.read_holding_registers(0, 1))
+                .await
+                .is_err()
+        );
+        assert_eq!(
+            ctxt.read_holding_registers(0, 1).await.unwrap(),
+            Err(ExceptionCode::IllegalDataAddress)
+        );
+        responder.await.unwrap();
+    }
+}
@@ -38,6 +38,10 @@ pub type BuildPage = Box<dyn Fn(Request<Incoming>) -> DynResult<DynResponse> + S
 /// Takes a path and returns (mime_type, resource_data)
 pub type GetResurce = Box<dyn Fn(&str) -> DynResult<(&str, Bytes)> + Send + Sync>;
 
+/// Takes a path and returns (mime_type, response_data), or None if
+/// there's no such API
+pub type GetApi = Box<dyn Fn(&str) -> DynResult<Option<(&str, Bytes)>> + Send + Sync>;
+
 fn into_dyn_response<T>(resp: Response<T>) -> DynResponse
 where
     T: Body<Data = Bytes, Error = Infallible> + Send + Sync + Unpin + 'static,
@@ -56,10 +60,11 @@ pub trait WebsocketReceive {
 }
 
 pub struct ServerConfig {
-    bind_addr: Option<IpAddr>,
+    bind_addrs: Vec<IpAddr>,
     port: Option<u16>,
     build_page: BuildPage,
     web_resource: GetResurce,
+    api: GetApi,
     ws_connect: Box<dyn WebsocketConnect + Sync + Send>,
 }
 
@@ -69,10 +74,11 @@ fn no_resource(_path: &str) -> DynResult<(&str, Bytes)> {
 impl ServerConfig {
     pub fn new(ws_connect: Box<dyn WebsocketConnect + Sync + Send>) -> Self {
         Self {
-            bind_addr: None,
+            bind_addrs: Vec::new(),
             port: None,
             build_page: Box::new(default_page),
             web_resource: Box::new(no_resource),
+            api: Box::new(|_| Ok(None)),
             ws_connect,
         }
     }
@@ -81,8 +87,10 @@ impl ServerConfig {
         self.port = Some(p);
         self
     }
+    /// Add an address to listen on. Listens on 127.0.0.1 if no address
+    /// is added.
     pub fn bind_addr(mut self, a: IpAddr) -> Self {
-        self.bind_addr = Some(a);
+        self.bind_addrs.push(a);
         self
     }
 
@@ -95,6 +103,13 @@ impl ServerConfig {
         self.web_resource = resource;
         self
     }
+
+    /// Handler for paths starting with /api/. The path is passed without
+    /// the prefix.
+    pub fn api(mut self, api: GetApi) -> Self {
+        self.api = api;
+        self
+    }
 }
 
 pub fn default_page(_req: Request<Incoming>) -> DynResult<DynResponse> {
@@ -103,9 +118,13 @@ pub fn default_page(_req: Request<Incoming>) -> DynResult<DynResponse> {
 
 pub type WsSender = mpsc::Sender<String>;
 
+// Messages queued for a WebSocket client. A browser that falls this far
+// behind misses messages instead of making the queue grow without limit.
+pub const WS_SEND_QUEUE_LEN: usize = 1024;
+
 pub async fn ws_client(ws: HyperWebsocket, conf: Arc<ServerConfig>) {
     info!("Connecting WS");
-    let (ws_send_in, mut ws_send_out) = mpsc::channel::<String>(4);
+    let (ws_send_in, mut ws_send_out) = mpsc::channel::<String>(WS_SEND_QUEUE_LEN);
     let mut stream = match ws.await {
         Ok(s) => s,
         Err(e) => {
@@ -201,6 +220,27 @@ async fn handle(conf: Arc<ServerConfig>, mut req: Request<Incoming>) -> DynResul
             if path.starts_with("/dyn/") {
                 debug!("Requested dyn");
                 (conf.build_page)(req)
+            } else if let Some(api_path) = path.strip_prefix("/api/") {
+                debug!("Requested API {api_path}");
+                let (status, mime_type, data) = match (conf.api)(api_path) {
+                    Ok(Some((mime_type, data))) => (StatusCode::OK, mime_type, data),
+                    Ok(None) => (
+                        StatusCode::NOT_FOUND,
+                        "text/plain",
+                        Bytes::from(format!("No API {api_path}")),
+                    ),
+                    Err(e) => (
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        "text/plain",
+                        Bytes::from(format!("API error: {e}")),
+                    ),
+                };
+                Ok(into_dyn_response(
+                    Response::builder()
+                        .status(status)
+                        .header(header::CONTENT_TYPE, mime_type)
+                        .body(Full::from(data))?,
+                ))
             } else if path.starts_with("/socket/") {
                 debug!("Requested socket");
 
@@ -248,24 +288,35 @@ async fn handle(conf: Arc<ServerConfig>, mut req: Request<Incoming>) -> DynResul
         )),
     }
 }
-pub fn setup_server(
-    conf: ServerConfig,
-) -> DynResult<(
-    Pin<Box<dyn Future<Output = DynResult<()>> + Send + Sync>>,
-    IpAddr,
-    u16,
-)> {
-    let port = conf.port.unwrap_or(0);
-    let bind_addr = conf
-        .bind_addr
-        .unwrap_or_else(|| IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)));
-    let socket_addr = SocketAddr::new(bind_addr, port);
+pub type ServerFuture = Pin<Box<dyn Future<Output = DynResult<()>> + Send + Sync>>;
+
+/// Bind to all configured addresses. Returns the server and the
+/// addresses it listens on.
+pub fn setup_server(conf: ServerConfig) -> DynResult<(ServerFuture, Vec<SocketAddr>)> {
+    let mut port = conf.port.unwrap_or(0);
+    let mut bind_addrs = conf.bind_addrs.clone();
+    if bind_addrs.is_empty() {
+        bind_addrs.push(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)));
+    }
+    let mut listeners = Vec::new();
+    for addr in bind_addrs {
+        let listener = std::net::TcpListener::bind(SocketAddr::new(addr, port))?;
+        // Listen on the same port on all addresses if the first one was
+        // picked by the system
+        port = listener.local_addr()?.port();
+        listener.set_nonblocking(true)?;
+        listeners.push(TcpListener::from_std(listener)?);
+    }
+    let local_addrs = listeners
+        .iter()
+        .map(|l| l.local_addr())
+        .collect::<Result<Vec<_>, _>>()?;
     let conf = Arc::new(conf);
     let service = service_fn(move |req| handle(conf.clone(), req));
-    let server = Box::pin(async move {
-        let listener = TcpListener::bind(socket_addr).await?;
-        loop {
-            {
+    let servers = listeners.into_iter().map(move |listener| -> ServerFuture {
+        let service = service.clone();
+        Box::pin(async move {
+            loop {
                 let (stream, _) = listener.accept().await?;
                 let io = TokioIo::new(stream);
                 let service = service.clone();
@@ -279,7 +330,8 @@ pub fn setup_server(
                     }
                 });
             }
-        }
+        })
     });
-    Ok((server, bind_addr, port))
+    let server = Box::pin(future::try_join_all(servers).map(|res| res.map(|_| ())));
+    Ok((server, local_addrs))
 }
@@ -0,0 +1,333 @@
+// Writes issued by a client on a timer or when a value crosses a
+// threshold, used to drive test sequences against real devices. Writes
+// are defined per device in the configuration and can be enabled,
+// disabled or run at once while running.
+
+use crate::device_list::{ScheduledWriteDef, WriteTrigger};
+use crate::devices::{Devices, Event, Table};
+use crate::error::DynResult;
+use crate::tags::Tags;
+use futures::future;
+use log::{error, info};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::{self, Duration, Instant};
+
+pub struct WriteSchedule {
+    defs: Vec<ScheduledWriteDef>,
+    enabled: Vec<AtomicBool>,
+}
+
+fn value_name(table: Table, addr: u16) -> String {
+    let table = match table {
+        Table::HoldingRegisters => "holding register",
+        Table::InputRegisters => "input register",
+        Table::Coils => "coil",
+        Table::DiscreteInputs => "discrete input",
+    };
+    format!("{table} {addr}")
+}
+
+/// Short text describing what is written and when
+pub fn description(def: &ScheduledWriteDef) -> String {
+    let mut desc = format!("Write {} to {}", def.value, value_name(def.table, def.addr));
+    match &def.trigger {
+        WriteTrigger::Interval(interval) => {
+            desc += &format!(" every {} ms", interval.as_millis());
+        }
+        WriteTrigger::Above {
+            table,
+            addr,
+            threshold,
+        } => {
+            desc += &format!(
+                " when {} rises above {threshold}",
+                value_name(*table, *addr)
+            )
+        }
+        WriteTrigger::Below {
+            table,
+            addr,
+            threshold,
+        } => {
+            desc += &format!(
+                " when {} falls below {threshold}",
+                value_name(*table, *addr)
+            )
+        }
+    }
+    desc
+}
+
+impl WriteSchedule {
+    pub fn new(defs: &[ScheduledWriteDef]) -> WriteSchedule {
+        WriteSchedule {
+            defs: defs.to_vec(),
+            enabled: defs.iter().map(|d| AtomicBool::new(d.enabled)).collect(),
+        }
+    }
+
+    /// All writes and whether they are currently enabled
+    pub fn list(&self) -> impl Iterator<Item = (&ScheduledWriteDef, bool)> {
+        self.defs
+            .iter()
+            .zip(&self.enabled)
+            .map(|(def, enabled)| (def, enabled.load(Ordering::Relaxed)))
+    }
+
+    pub fn get(&self, index: usize) -> Option<(&ScheduledWriteDef, bool)> {
+        self.list().nth(index)
+    }
+
+    /// Returns false if there's no write with this index
+    pub fn set_enabled(&self, index: usize, enabled: bool) -> bool {
+        match self.enabled.get(index) {
+            Some(e) => {
+                e.store(enabled, Ordering::Relaxed);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+fn read_value(tags: &Tags, table: Table, addr: u16) -> Option<u16> {
+    let addr = addr as usize;
+    match table {
+        Table::HoldingRegisters => tags.holding_registers.get_array(|r| r.get(addr).copied()),
+        Table::InputRegisters => tags.input_registers.get_array(|r| r.get(addr).copied()),
+        Table::Coils => tags.coils.get_array(|b| b.get(addr).map(|&b| b as u16)),
+        Table::DiscreteInputs => tags
+            .discrete_inputs
+            .get_array(|b| b.get(addr).map(|&b| b as u16)),
+    }
+}
+
+// Whether the value watched by a triggered write is past its threshold.
+// None for writes on a timer.
+fn triggered(devices: &Devices, unit: u8, trigger: &WriteTrigger) -> Option<bool> {
+    let (table, addr, threshold, above) = match *trigger {
+        WriteTrigger::Interval(_) => return None,
+        WriteTrigger::Above {
+            table,
+            addr,
+            threshold,
+        } => (table, addr, threshold, true),
+        WriteTrigger::Below {
+            table,
+            addr,
+            threshold,
+        } => (table, addr, threshold, false),
+    };
+    let value = devices
+        .tags_read(unit, |tags| read_value(tags, table, addr))
+        .ok()
+        .flatten()
+        .unwrap_or_default();
+    Some(if above {
+        value > threshold
+    } else {
+        value < threshold
+    })
+}
+
+/// Put the value of a scheduled write in the tags of the unit and have
+/// the client write it to the device
+pub fn execute(devices: &Devices, unit: u8, def: &ScheduledWriteDef) -> DynResult<()> {
+    let value = match def.table {
+        Table::Coils => u16::from(def.value != 0),
+        Table::HoldingRegisters => def.value,
+        Table::InputRegisters | Table::DiscreteInputs => {
+            return Err(format!("No {} in the tag list", value_name(def.table, def.addr)).into());
+        }
+    };
+    devices.write_values(None, unit, def.table, def.addr, &[value])?;
+    info!("Unit {unit}: {}", description(def));
+    Ok(())
+}
+
+fn execute_enabled(devices: &Devices, unit: u8, index: usize) {
+    let Ok(schedule) = devices.write_schedule(unit) else {
+        return;
+    };
+    if let Some((def, true)) = schedule.get(index)
+        && let Err(e) = execute(devices, unit, def)
+    {
+        error!("Unit {unit}: Scheduled write failed: {e}");
+    }
+}
+
+struct Timer {
+    unit: u8,
+    index: usize,
+    interval: Duration,
+    due: Instant,
+}
+
+/// Issue the scheduled writes of all units and log the results. A write
+/// triggered by a value is issued when the value crosses the threshold,
+/// compared to the value when the schedule started or was last checked.
+pub async fn run(devices: Devices) {
+    let now = Instant::now();
+    let mut timers = Vec::new();
+    // Whether the value watched by a triggered write was past the
+    // threshold, indexed by unit and write
+    let mut past = BTreeMap::new();
+    for unit in devices.units() {
+        let Ok(schedule) = devices.write_schedule(unit) else {
+            continue;
+        };
+        for (index, (def, _)) in schedule.list().enumerate() {
+            match (&def.trigger, triggered(&devices, unit, &def.trigger)) {
+                (WriteTrigger::Interval(interval), _) => timers.push(Timer {
+                    unit,
+                    index,
+                    interval: *interval,
+                    due: now + *interval,
+                }),
+                (_, Some(state)) => {
+                    past.insert((unit, index), state);
+                }
+                (_, None) => {}
+            }
+        }
+    }
+    if timers.is_empty() && past.is_empty() {
+        return;
+    }
+    let mut events = devices.subscribe();
+    loop {
+        let due = timers.iter().map(|timer| timer.due).min();
+        let wait = async {
+            match due {
+                Some(due) => time::sleep_until(due).await,
+                None => future::pending().await,
+            }
+        };
+        tokio::select! {
+            _ = wait => {
+                let now = Instant::now();
+                for timer in timers.iter_mut().filter(|timer| timer.due <= now) {
+                    // Don't try to catch up after falling behind
+                    timer.due = (timer.due + timer.interval).max(now);
+                    execute_enabled(&devices, timer.unit, timer.index);
+                }
+            }
+            (unit, _) = devices.updated() => {
+                let Ok(schedule) = devices.write_schedule(unit) else {
+                    continue;
+                };
+                for (&(_, index), state) in past.range_mut((unit, 0)..=(unit, usize::MAX)) {
+                    let Some((def, _)) = schedule.get(index) else {
+                        continue;
+                    };
+                    let now = triggered(&devices, unit, &def.trigger).unwrap_or(false);
+                    if now && !*state {
+                        execute_enabled(&devices, unit, index);
+                    }
+                    *state = now;
+                }
+            }
+            event = events.recv() => match event {
+                Ok(Event::WriteDone(result)) if result.request.origin.is_none() => {
+                    let request = &result.request;
+                    let name = value_name(request.table, request.start);
+                    match &result.error {
+                        None => info!("Unit {}: Wrote {name}", request.unit),
+                        Some(e) => error!("Unit {}: Failed to write {name}: {e}", request.unit),
+                    }
+                }
+                Ok(_) | Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => break,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::device_list_xml::parse_device_list;
+    use roxmltree::Document;
+
+    const DEVICES: &str = r#"
+<tag-list xmlns="http://www.elektro-kapsel.se/xml/modbus_config/v2">
+  <device addr="1">
+    <write-schedule>
+      <write addr="1" value="42" interval="100"/>
+      <write table="coils" addr="0" value="1" when-table="holding-registers" when-addr="0"
+             above="100"/>
+      <write addr="2" value="7" interval="500" enabled="false"/>
+    </write-schedule>
+    <holding-registers>
+      <register-range addr-low="0" addr-high="2"/>
+    </holding-registers>
+    <coils>
+      <bit addr="0"/>
+    </coils>
+  </device>
+</tag-list>
+"#;
+
+    fn devices() -> Devices {
+        let doc = Document::parse(DEVICES).unwrap();
+        Devices::new(&parse_device_list(&doc.root_element()).unwrap())
+    }
+
+    #[test]
+    fn description_test() {
+        let devices = devices();
+        let schedule = devices.write_schedule(1).unwrap();
+        let descriptions: Vec<_> = schedule.list().map(|(def, _)| description(def)).collect();
+        assert_eq!(
+            descriptions,
+            [
+                "Write 42 to holding register 1 every 100 ms",
+                "Write 1 to coil 0 when holding register 0 rises above 100",
+                "Write 7 to holding register 2 every 500 ms",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn run_test() {
+        let devices = devices();
+        let client = devices.select(&[1]);
+        tokio::spawn(run(devices.clone()));
+        let requested = async || {
+            let request = client.next_write().await;
+            (request.table, request.start, request.values)
+        };
+
+        time::sleep(Duration::from_millis(150)).await;
+        assert_eq!(requested().await, (Table::HoldingRegisters, 1, vec![42]));
+        assert!(devices.set_scheduled_write_enabled(1, 0, false).unwrap());
+        let holding = devices
+            .tags_read(1, |tags| {
+                tags.holding_registers.get_array(|r| r[..3].to_vec())
+            })
+            .unwrap();
+        assert_eq!(holding, [0, 42, 0]);
+
+        // Only crossing the threshold triggers the write
+        for value in [200, 150, 50, 101] {
+            devices
+                .tags_write(1, |tags| tags.holding_registers.update(0, &[value]))
+                .unwrap();
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(requested().await, (Table::Coils, 0, vec![1]));
+        assert_eq!(requested().await, (Table::Coils, 0, vec![1]));
+        assert!(
+            time::timeout(Duration::from_millis(10), requested())
+                .await
+                .is_err()
+        );
+        assert!(
+            devices
+                .tags_read(1, |tags| tags.coils.get_array(|b| b[0]))
+                .unwrap()
+        );
+    }
+}
@@ -406,6 +406,43 @@ class BitAreaUpdater {
     }
 }
 
+class FifoUpdater {
+    focusedElement = null;
+    fifos = {};
+
+    constructor(parent, send) {
+        this.send = send;
+
+        for (let v of parent.getElementsByClassName("mb_fifo")) {
+            let addr = parseInt(v.getAttributeNS(MB_NS, "addr"));
+	    let unit_addr = parseInt(v.getAttributeNS(MB_NS, "unit-addr"));
+	    this.fifos[unit_addr + ":" + addr] = v;
+            let updater = this;
+            v.addEventListener("change", function (e) {
+		let values = v.value.split(/[\s,]+/).filter((s) => s != "").map(Number);
+                updater.send({
+		    unit_addr: unit_addr,
+                    addr: addr,
+                    values: values
+                });
+            });
+            v.addEventListener("focus", function (e) {
+                updater.focusedElement = v;
+            });
+            v.addEventListener("blur", function (e) {
+                updater.focusedElement = null;
+            });
+        }
+    }
+
+    update_values(unit_addr, addr, values) {
+	let inp = this.fifos[unit_addr + ":" + addr];
+	if (inp && !(inp === this.focusedElement)) {
+	    inp.value = values.join(" ");
+	}
+    }
+}
+
 
 function socket_uri() {
     var loc = window.location,
@@ -420,6 +457,184 @@ function socket_uri() {
     return new_uri;
 }
 const MB_NS = "http://www.elektro-kapsel.se/xml/mb-tool";
+const XHTML_NS = "http://www.w3.org/1999/xhtml";
+
+const DEVICE_ID_OBJECTS = ["Vendor name", "Product code", "Revision", "Vendor URL",
+			   "Product name", "Model name", "User application name"];
+
+function show_device_identification(unit_addr, objects) {
+    for (let dl of document.getElementsByClassName("device_identification")) {
+	if (parseInt(dl.getAttributeNS(MB_NS, "unit-addr")) != unit_addr) continue;
+	dl.replaceChildren();
+	for (let [id, value] of objects) {
+	    let dt = document.createElementNS(XHTML_NS, "dt");
+	    dt.textContent = DEVICE_ID_OBJECTS[id] || "Object 0x" + id.toString(16);
+	    let dd = document.createElementNS(XHTML_NS, "dd");
+	    dd.textContent = value;
+	    dl.append(dt, dd);
+	}
+    }
+}
+
+// List the faults of a device with a checkbox for enabling each
+function show_faults(unit_addr, faults, send) {
+    for (let div of document.getElementsByClassName("faults")) {
+	if (parseInt(div.getAttributeNS(MB_NS, "unit-addr")) != unit_addr) continue;
+	div.replaceChildren();
+	if (faults.length == 0) continue;
+	let header = document.createElementNS(XHTML_NS, "h2");
+	header.textContent = "Faults";
+	let list = document.createElementNS(XHTML_NS, "ul");
+	list.classList.add("fault_list");
+	faults.forEach((fault, index) => {
+	    let item = document.createElementNS(XHTML_NS, "li");
+	    let label = document.createElementNS(XHTML_NS, "label");
+	    let check = document.createElementNS(XHTML_NS, "input");
+	    check.type = "checkbox";
+	    check.checked = fault.enabled;
+	    check.addEventListener("change", function (e) {
+		send({unit_addr: unit_addr, index: index, enabled: e.target.checked});
+	    });
+	    let text = fault.description;
+	    if (fault.label) text = fault.label + ": " + text;
+	    label.append(check, text);
+	    item.append(label);
+	    list.append(item);
+	});
+	div.append(header, list);
+    }
+}
+
+// Writes issued on a timer or by a value in client mode, with a button
+// to issue one at once
+function show_write_schedule(unit_addr, writes, send_enabled, send_run) {
+    for (let div of document.getElementsByClassName("write_schedule")) {
+	if (parseInt(div.getAttributeNS(MB_NS, "unit-addr")) != unit_addr) continue;
+	div.replaceChildren();
+	if (writes.length == 0) continue;
+	let header = document.createElementNS(XHTML_NS, "h2");
+	header.textContent = "Scheduled writes";
+	let list = document.createElementNS(XHTML_NS, "ul");
+	list.classList.add("write_list");
+	writes.forEach((write, index) => {
+	    let item = document.createElementNS(XHTML_NS, "li");
+	    let label = document.createElementNS(XHTML_NS, "label");
+	    let check = document.createElementNS(XHTML_NS, "input");
+	    check.type = "checkbox";
+	    check.checked = write.enabled;
+	    check.addEventListener("change", function (e) {
+		send_enabled({unit_addr: unit_addr, index: index, enabled: e.target.checked});
+	    });
+	    let text = write.description;
+	    if (write.label) text = write.label + ": " + text;
+	    label.append(check, text);
+	    let run = document.createElementNS(XHTML_NS, "button");
+	    run.type = "button";
+	    run.textContent = "Run";
+	    run.addEventListener("click", function () {
+		send_run({unit_addr: unit_addr, index: index});
+	    });
+	    item.append(label, " ", run);
+	    list.append(item);
+	});
+	div.append(header, list);
+    }
+}
+
+// Only shown in client mode, after the first request to the unit
+function show_unit_status(status) {
+    for (let div of document.getElementsByClassName("unit_status")) {
+	if (parseInt(div.getAttributeNS(MB_NS, "unit-addr")) != status.unit_addr) continue;
+	div.classList.toggle("responding", status.responding === true);
+	div.classList.toggle("not_responding", status.responding === false);
+	div.classList.toggle("offline", status.offline);
+	if (status.responding == null) {
+	    div.textContent = "";
+	    continue;
+	}
+	let text = status.responding ? "Responding" : "Not responding";
+	if (status.offline) {
+	    text = "Offline, " + status.consecutive_failures + " requests without response";
+	}
+	if (status.last_error) text += ", last error: " + status.last_error;
+	text += " (" + status.failures + " of " + status.requests + " requests failed)";
+	div.textContent = text;
+    }
+}
+
+// Flag values whose ranges can't be read in client mode. Updaters are
+// indexed by table.
+function show_range_health(updaters, health) {
+    let flagged = [];
+    for (let range of health.ranges) {
+	let updater = updaters[range.table];
+	if (!updater) continue;
+	let dev = updater.get_device(health.unit_addr);
+	let values = dev.value_map.overlapping(range.start, range.start + range.length);
+	for (let entry of values) {
+	    entry.value.classList.remove("stale", "bad");
+	    entry.value.removeAttribute("title");
+	    if (range.quality == "Stale" || range.quality == "Bad") {
+		flagged.push({ value: entry.value, range: range });
+	    }
+	}
+    }
+    for (let { value, range } of flagged) {
+	value.classList.add(range.quality == "Stale" ? "stale" : "bad");
+	let title = "Read failed: " + range.last_error;
+	if (range.last_success != null) {
+	    title += ", last read " + new Date(range.last_success).toLocaleTimeString();
+	}
+	value.title = title;
+    }
+}
+
+// Flag values that a client failed to write to the device, or clear the
+// flag once written
+function show_write_result(updaters, result) {
+    let updater = updaters[result.table];
+    if (!updater) return;
+    let dev = updater.get_device(result.unit_addr);
+    let values = dev.value_map.overlapping(result.start, result.start + result.length);
+    for (let entry of values) {
+	if (result.error != null) {
+	    entry.value.classList.add("write_failed");
+	    entry.value.title = "Write failed: " + result.error;
+	} else if (entry.value.classList.contains("write_failed")) {
+	    entry.value.classList.remove("write_failed");
+	    entry.value.removeAttribute("title");
+	}
+    }
+}
+
+const TRAFFIC_LOG_LEN = 100;
+
+// Requests forwarded in gateway or tap mode, newest first
+function show_traffic(traffic) {
+    let time = new Date().toLocaleTimeString();
+    let text = time + " unit " + traffic.unit_addr + ": "
+	+ traffic.request + " -> " + traffic.response;
+    for (let div of document.getElementsByClassName("traffic")) {
+	div.classList.add("active");
+	for (let log of div.getElementsByClassName("traffic_log")) {
+	    let item = document.createElement("li");
+	    item.textContent = text;
+	    log.prepend(item);
+	    while (log.children.length > TRAFFIC_LOG_LEN) {
+		log.lastElementChild.remove();
+	    }
+	}
+    }
+}
+
+// Delay is either a fixed time or a range like "50-200"
+function show_response_delay(unit_addr, min, max) {
+    for (let inp of document.getElementsByClassName("mb_response_delay")) {
+	if (parseInt(inp.getAttributeNS(MB_NS, "unit-addr")) != unit_addr) continue;
+	if (inp === document.activeElement) continue;
+	inp.value = (min == max) ? min : min + "-" + max;
+    }
+}
 
 function setup() {
     ws = new WebSocket(socket_uri());
@@ -463,6 +678,37 @@ function setup() {
 		ws.send(JSON.stringify({ UpdateDiscreteInputs: data }))
 	    });
     }
+
+    let fifos = [];
+    for (let f of document.getElementsByClassName("fifo_queues")) {
+	fifos.push(new FifoUpdater(
+	    f,
+	    function (data) {
+		ws.send(JSON.stringify({ UpdateFifo: data }))
+	    }));
+    }
+
+    // One updater per file, keyed by unit address and file number
+    let files = {};
+    for (let f of document.getElementsByClassName("file_records")) {
+	let unit_addr = parseInt(f.getAttributeNS(MB_NS, "unit-addr"));
+	let file = parseInt(f.getAttributeNS(MB_NS, "file"));
+	files[unit_addr + ":" + file] = new RegisterAreaUpdater(
+	    f,
+	    function (data) {
+		data.file = file;
+		ws.send(JSON.stringify({ UpdateFileRecords: data }))
+	    });
+    }
+    for (let inp of document.getElementsByClassName("mb_response_delay")) {
+	let unit_addr = parseInt(inp.getAttributeNS(MB_NS, "unit-addr"));
+	inp.addEventListener("change", function (e) {
+	    let [min, max] = inp.value.split("-").map(Number);
+	    if (max == null) max = min;
+	    if (isNaN(min) || isNaN(max)) return;
+	    ws.send(JSON.stringify({ ResponseDelay: {unit_addr: unit_addr, min: min, max: max} }))
+	});
+    }
     let echo_count = 0;
     setInterval(function() {
 	ws.send(JSON.stringify({Echo: echo_count}));
@@ -512,6 +758,20 @@ function setup() {
 					  update_discrete_inputs.start, update_discrete_inputs.regs);
         }
 
+	let update_fifo = cmd.UpdateFifo;
+	if (update_fifo) {
+	    for (let f of fifos) {
+		f.update_values(update_fifo.unit_addr, update_fifo.addr, update_fifo.values);
+	    }
+	}
+	let update_file = cmd.UpdateFileRecords;
+	if (update_file) {
+	    let f = files[update_file.unit_addr + ":" + update_file.file];
+	    if (f) {
+		f.update_values(update_file.unit_addr, update_file.start, update_file.regs);
+	    }
+	}
+
 	let echo_reply = cmd.Echo;
 	if (echo_reply != null) {
 	    if (heart_beat) {
@@ -522,10 +782,66 @@ function setup() {
 	    }
 	}
 
+	let device_id = cmd.DeviceIdentification;
+	if (device_id) {
+	    show_device_identification(device_id.unit_addr, device_id.objects);
+	}
+
+	let response_delay = cmd.ResponseDelay;
+	if (response_delay) {
+	    show_response_delay(response_delay.unit_addr, response_delay.min, response_delay.max);
+	}
+
+	let unit_status = cmd.UnitStatus;
+	if (unit_status) {
+	    show_unit_status(unit_status);
+	}
+
+	let updaters = { HoldingRegisters: holding_regs,
+			 InputRegisters: input_regs,
+			 Coils: coils,
+			 DiscreteInputs: discrete_inputs };
+	let range_health = cmd.RangeHealth;
+	if (range_health) {
+	    show_range_health(updaters, range_health);
+	}
+
+	let write_result = cmd.WriteResult;
+	if (write_result) {
+	    show_write_result(updaters, write_result);
+	}
+
+	let traffic = cmd.Traffic;
+	if (traffic) {
+	    show_traffic(traffic);
+	}
+
+	let faults = cmd.Faults;
+	if (faults) {
+	    show_faults(faults.unit_addr, faults.faults, function (data) {
+		ws.send(JSON.stringify({ SetFault: data }))
+	    });
+	}
+
+	let write_schedule = cmd.WriteSchedule;
+	if (write_schedule) {
+	    show_write_schedule(write_schedule.unit_addr, write_schedule.writes, function (data) {
+		ws.send(JSON.stringify({ SetScheduledWrite: data }))
+	    }, function (data) {
+		ws.send(JSON.stringify({ RunScheduledWrite: data }))
+	    });
+	}
+
 	let unit_addresses = cmd.ListUnitAddresses;
         if (unit_addresses) {
 	    console.log("Units: "+unit_addresses);
 	    for (u of unit_addresses) {
+		ws.send(JSON.stringify({ RequestDeviceIdentification: {unit_addr: u} }))
+		ws.send(JSON.stringify({ RequestFaults: {unit_addr: u} }))
+		ws.send(JSON.stringify({ RequestWriteSchedule: {unit_addr: u} }))
+		ws.send(JSON.stringify({ RequestResponseDelay: {unit_addr: u} }))
+		ws.send(JSON.stringify({ RequestUnitStatus: {unit_addr: u} }))
+		ws.send(JSON.stringify({ RequestRangeHealth: {unit_addr: u} }))
 		ws.send(JSON.stringify({ RequestHoldingRegs: {unit_addr: u,
 							      start: 0, length: 32768 } }))
 		ws.send(JSON.stringify({ RequestHoldingRegs: {unit_addr: u,
@@ -544,6 +860,17 @@ function setup() {
 								 start: 0, length: 32768 } }))
 		ws.send(JSON.stringify({ RequestDiscreteInputs: {unit_addr: u,
 								 start: 32768, length: 32768 } }))
+		for (let f of document.getElementsByClassName("mb_fifo")) {
+		    if (parseInt(f.getAttributeNS(MB_NS, "unit-addr")) != u) continue;
+		    let addr = parseInt(f.getAttributeNS(MB_NS, "addr"));
+		    ws.send(JSON.stringify({ RequestFifo: {unit_addr: u, addr: addr} }))
+		}
+		for (let f of document.getElementsByClassName("file_records")) {
+		    if (parseInt(f.getAttributeNS(MB_NS, "unit-addr")) != u) continue;
+		    let file = parseInt(f.getAttributeNS(MB_NS, "file"));
+		    ws.send(JSON.stringify({ RequestFileRecords: {unit_addr: u, file: file,
+								  start: 0, length: 10000 } }))
+		}
 	    }
 	}
     };